@@ -0,0 +1,180 @@
+extern crate criterion;
+extern crate socks;
+
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use socks::{Socks5Builder, Socks5Datagram};
+
+#[allow(dead_code)]
+#[path = "../src/test_util.rs"]
+mod test_util;
+
+use test_util::{fake_proxy_replying, Mock};
+
+// Counts allocations so that the number made by each operation can be
+// measured alongside its timing.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Measures the number of allocations made rather than the time taken.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    // Criterion rejects samples which measure zero, so one is added to each;
+    // it's negligible once divided between the sample's iterations.
+    fn end(&self, start: usize) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed) - start + 1
+    }
+
+    fn add(&self, a: &usize, b: &usize) -> usize {
+        a + b
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+
+impl ValueFormatter for Allocations {
+    fn scale_values(&self, _: f64, _: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(&self,
+                         _: f64,
+                         throughput: &Throughput,
+                         values: &mut [f64])
+                         -> &'static str {
+        let (len, unit) = match *throughput {
+            Throughput::Bytes(len) | Throughput::BytesDecimal(len) => (len, "allocs/B"),
+            Throughput::Elements(len) => (len, "allocs/elem"),
+        };
+        for value in values {
+            *value /= len as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+const NO_AUTH_RESPONSE: &[u8] = &[5, 0, 5, 0, 0, 1, 10, 0, 0, 2, 0, 80];
+
+const PASSWORD_RESPONSE: &[u8] = &[5, 2, 1, 0, 5, 0, 0, 1, 10, 0, 0, 2, 0, 80];
+
+// A proxy connection which has already sent its responses, created outside
+// the measured routine.
+fn mock(response: &[u8]) -> Mock {
+    let mut mock = Mock::new(response.to_vec());
+    mock.output.reserve(1024);
+    mock
+}
+
+fn handshake<M: Measurement>(c: &mut Criterion<M>, name: &str) {
+    let mut group = c.benchmark_group(name);
+    let builder = Socks5Builder::new();
+    group.bench_function("ip", |b| {
+        b.iter_batched(|| mock(NO_AUTH_RESPONSE),
+                       |mock| builder.connect_via(mock, "10.0.0.1:80").unwrap(),
+                       BatchSize::SmallInput)
+    });
+    group.bench_function("domain", |b| {
+        b.iter_batched(|| mock(NO_AUTH_RESPONSE),
+                       |mock| builder.connect_via(mock, "example.com:80").unwrap(),
+                       BatchSize::SmallInput)
+    });
+
+    let mut builder = Socks5Builder::new();
+    builder.password("user", "pass");
+    group.bench_function("password", |b| {
+        b.iter_batched(|| mock(PASSWORD_RESPONSE),
+                       |mock| builder.connect_via(mock, "10.0.0.1:80").unwrap(),
+                       BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+fn datagram<M: Measurement>(c: &mut Criterion<M>, name: &str) {
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = relay.local_addr().unwrap().port();
+    let (proxy, server) = fake_proxy_replying([127, 0, 0, 1, (port >> 8) as u8, port as u8]);
+    let socket = Socks5Datagram::bind(proxy, "127.0.0.1:0").unwrap();
+    // hold the association open until the benchmarks are done
+    let _control = server.join().unwrap();
+    let local = socket.get_ref().local_addr().unwrap();
+
+    let payload = [0; 1024];
+    let mut buf = [0; 2048];
+    let mut message = vec![0, 0, 0, 1, 10, 0, 0, 1, 0, 53];
+    message.extend_from_slice(&payload);
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(payload.len() as u64));
+    group.bench_function("send_to", |b| {
+        b.iter(|| {
+            socket.send_to(&payload, "10.0.0.1:53").unwrap();
+            relay.recv(&mut buf).unwrap();
+        })
+    });
+    group.bench_function("recv_from", |b| {
+        b.iter(|| {
+            relay.send_to(&message, local).unwrap();
+            socket.recv_from(&mut buf).unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn timing(c: &mut Criterion) {
+    handshake(c, "handshake");
+    datagram(c, "datagram");
+}
+
+fn allocations(c: &mut Criterion<Allocations>) {
+    handshake(c, "handshake-allocations");
+    datagram(c, "datagram-allocations");
+}
+
+criterion_group!(benches, timing);
+criterion_group! {
+    name = allocation_benches;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = allocations
+}
+criterion_main!(benches, allocation_benches);
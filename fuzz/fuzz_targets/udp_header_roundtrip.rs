@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use socks::TargetAddr;
+
+fuzz_target!(|addr: TargetAddr| {
+    socks::fuzz::udp_header_roundtrip(&addr);
+});
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// The write half of a `BufferedSocksStream`. Reads go straight to the inner
+// stream, after flushing any buffered writes.
+struct WriteHalf<S: Write>(BufWriter<S>);
+
+impl<S: Read + Write> Read for WriteHalf<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // a peer is unlikely to respond to a request it hasn't seen yet
+        self.0.flush()?;
+        self.0.get_mut().read(buf)
+    }
+}
+
+/// A proxied stream with both its read and write sides buffered.
+///
+/// Wrapping a stream in a `BufReader` alone makes it awkward to write to, and
+/// a `BufWriter` alone can't be used as a `BufRead`. This type owns both
+/// buffers around a single stream such as a `Socks5Stream`.
+///
+/// Buffered writes are flushed before the read buffer is refilled from the
+/// stream, so request/response protocols don't deadlock waiting on an
+/// unflushed request.
+pub struct BufferedSocksStream<S: Write> {
+    inner: BufReader<WriteHalf<S>>,
+}
+
+impl<S: Read + Write> BufferedSocksStream<S> {
+    /// Wraps a stream with default buffer capacities.
+    pub fn new(stream: S) -> BufferedSocksStream<S> {
+        BufferedSocksStream { inner: BufReader::new(WriteHalf(BufWriter::new(stream))) }
+    }
+
+    /// Wraps a stream with the specified read and write buffer capacities.
+    pub fn with_capacities(read_capacity: usize,
+                           write_capacity: usize,
+                           stream: S)
+                           -> BufferedSocksStream<S> {
+        let writer = BufWriter::with_capacity(write_capacity, stream);
+        BufferedSocksStream { inner: BufReader::with_capacity(read_capacity, WriteHalf(writer)) }
+    }
+
+    /// Returns a shared reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref().0.get_ref()
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    ///
+    /// Reading from or writing to the inner stream directly bypasses the
+    /// buffers.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut().0.get_mut()
+    }
+
+    /// Flushes buffered writes and returns the inner stream.
+    ///
+    /// Any data in the read buffer is lost; use `into_parts` to keep it.
+    pub fn into_inner(self) -> io::Result<S> {
+        self.inner.into_inner().0.into_inner().map_err(Into::into)
+    }
+
+    /// Flushes buffered writes and returns the inner stream along with any
+    /// data which has been read from it but not yet consumed.
+    pub fn into_parts(self) -> io::Result<(S, Vec<u8>)> {
+        let buffered = self.inner.buffer().to_vec();
+        let stream = self.inner.into_inner().0.into_inner().map_err(io::Error::from)?;
+        Ok((stream, buffered))
+    }
+}
+
+impl<S: Write + fmt::Debug> fmt::Debug for BufferedSocksStream<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BufferedSocksStream")
+            .field("stream", self.inner.get_ref().0.get_ref())
+            .field("read_buffered", &self.inner.buffer().len())
+            .field("write_buffered", &self.inner.get_ref().0.buffer().len())
+            .finish()
+    }
+}
+
+impl<S: Read + Write> Read for BufferedSocksStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Read + Write> BufRead for BufferedSocksStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<S: Read + Write> Write for BufferedSocksStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.get_mut().0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.get_mut().0.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::Mock;
+
+    #[test]
+    fn request_response() {
+        // the request must arrive before the response is sent
+        let mock = Mock::new(b"220 ready\r\n".to_vec()).write_first();
+        let mut stream = BufferedSocksStream::new(mock);
+
+        stream.write_all(b"NOOP\r\n").unwrap();
+        assert!(stream.get_ref().output.is_empty());
+
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "220 ready\r\n");
+
+        stream.write_all(b"QUIT\r\n").unwrap();
+        let mock = stream.into_inner().unwrap();
+        assert_eq!(mock.output, b"NOOP\r\nQUIT\r\n");
+    }
+    #[test]
+    fn into_parts() {
+        let mut stream = BufferedSocksStream::new(Mock::new(b"220 ready\r\n221 bye\r\n".to_vec()));
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        stream.write_all(b"QUIT\r\n").unwrap();
+
+        let (mock, buffered) = stream.into_parts().unwrap();
+        assert_eq!(mock.output, b"QUIT\r\n");
+        assert_eq!(buffered, b"221 bye\r\n");
+    }
+}
@@ -0,0 +1,124 @@
+//! The SOCKS5 challenge-handshake authentication method
+//! (draft-ietf-aft-socks-chap).
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use std::io::{self, Read, Write};
+
+const VERSION: u8 = 1;
+
+const ATTR_STATUS: u8 = 0x00;
+const ATTR_USER_IDENTITY: u8 = 0x02;
+const ATTR_CHALLENGE: u8 = 0x03;
+const ATTR_RESPONSE: u8 = 0x04;
+const ATTR_ALGORITHMS: u8 = 0x11;
+
+const HMAC_MD5: u8 = 0x85;
+
+fn write_message<W: Write>(socket: &mut W, attrs: &[(u8, &[u8])]) -> io::Result<()> {
+    let mut packet = vec![];
+    let _ = packet.write_u8(VERSION);
+    let _ = packet.write_u8(attrs.len() as u8);
+    for &(ty, value) in attrs {
+        let _ = packet.write_u8(ty);
+        let _ = packet.write_u8(value.len() as u8);
+        packet.extend_from_slice(value);
+    }
+    socket.write_all(&packet)
+}
+
+fn read_message<R: Read>(socket: &mut R) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    if socket.read_u8()? != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
+    }
+
+    let count = socket.read_u8()?;
+    let mut attrs = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let ty = socket.read_u8()?;
+        let len = socket.read_u8()?;
+        let mut value = vec![0; len as usize];
+        socket.read_exact(&mut value)?;
+        attrs.push((ty, value));
+    }
+
+    Ok(attrs)
+}
+
+fn respond(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Md5>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(challenge);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn authenticate<S>(socket: &mut S, username: &str, secret: &[u8]) -> io::Result<()>
+    where S: Read + Write
+{
+    if username.is_empty() || username.len() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid username"));
+    }
+
+    write_message(socket,
+                  &[(ATTR_ALGORITHMS, &[HMAC_MD5]), (ATTR_USER_IDENTITY, username.as_bytes())])?;
+
+    loop {
+        let mut response = None;
+        for (ty, value) in read_message(socket)? {
+            match ty {
+                ATTR_STATUS if value == [0] => return Ok(()),
+                ATTR_STATUS => {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                              "CHAP authentication failed"))
+                }
+                ATTR_ALGORITHMS if value != [HMAC_MD5] => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "unsupported CHAP algorithm"))
+                }
+                ATTR_CHALLENGE => response = Some(respond(secret, &value)),
+                _ => {}
+            }
+        }
+
+        if let Some(response) = response {
+            write_message(socket, &[(ATTR_RESPONSE, &response)])?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::Mock;
+
+    #[test]
+    fn hmac_md5() {
+        // RFC 2202 test case 2
+        let response = respond(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(response,
+                   [0x75, 0x0c, 0x78, 0x3e, 0x6a, 0xb0, 0xb5, 0x03, 0xea, 0xa8, 0x6e, 0x31,
+                    0x0a, 0x5d, 0xb7, 0x38]);
+    }
+
+    #[test]
+    fn handshake() {
+        let mut input = vec![1, 2, ATTR_ALGORITHMS, 1, HMAC_MD5, ATTR_CHALLENGE, 4, 1, 2, 3, 4];
+        input.extend_from_slice(&[1, 1, ATTR_STATUS, 1, 0]);
+        let mut mock = Mock::new(input);
+
+        authenticate(&mut mock, "user", b"secret").unwrap();
+
+        let mut expected = vec![1, 2, ATTR_ALGORITHMS, 1, HMAC_MD5, ATTR_USER_IDENTITY, 4];
+        expected.extend_from_slice(b"user");
+        expected.extend_from_slice(&[1, 1, ATTR_RESPONSE, 16]);
+        expected.extend_from_slice(&respond(b"secret", &[1, 2, 3, 4]));
+        assert_eq!(mock.output, expected);
+    }
+
+    #[test]
+    fn rejected() {
+        let mut mock = Mock::new(vec![1, 1, ATTR_STATUS, 1, 1]);
+
+        let err = authenticate(&mut mock, "user", b"secret").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use resolver::resolve;
+use {ConnectError, Credentials, Resolver, Socks5Builder, Socks5Stream, SystemResolver, TargetAddr,
+     ToTargetAddr, connect_with};
+
+struct Cached {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// A reusable SOCKS5 connector which caches the addresses of its proxy.
+///
+/// The proxy's name is resolved on the first connection and the result is
+/// reused until the TTL expires, or until none of the addresses can be
+/// connected to, rather than being resolved again for every connection.
+/// Authentication and validation settings are taken from a `Socks5Builder`.
+///
+/// A connector can be shared between threads. Its credentials can be replaced
+/// while it is in use, without affecting connections already being made.
+pub struct SocksConnector {
+    builder: RwLock<Arc<Socks5Builder>>,
+    proxy: TargetAddr,
+    resolver: Arc<dyn Resolver>,
+    ttl: Duration,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    cache: Mutex<Option<Cached>>,
+}
+
+impl fmt::Debug for SocksConnector {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SocksConnector")
+            .field("builder", &*self.builder.read().unwrap())
+            .field("proxy", &self.proxy)
+            .field("ttl", &self.ttl)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish()
+    }
+}
+
+impl SocksConnector {
+    /// Creates a connector which connects through `proxy` with the settings
+    /// of `builder`.
+    ///
+    /// The proxy is not resolved until the first connection is made.
+    pub fn new<T: ToTargetAddr>(builder: Socks5Builder, proxy: T) -> io::Result<SocksConnector> {
+        Ok(SocksConnector {
+            builder: RwLock::new(Arc::new(builder)),
+            proxy: proxy.to_target_addr()?,
+            resolver: Arc::new(SystemResolver),
+            ttl: Duration::from_secs(60),
+            connect_timeout: None,
+            handshake_timeout: None,
+            cache: Mutex::new(None),
+        })
+    }
+
+    /// Sets the resolver used to look up the proxy's address.
+    ///
+    /// Defaults to `SystemResolver`.
+    pub fn resolver<R>(&mut self, resolver: R) -> &mut SocksConnector
+        where R: Resolver + 'static
+    {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Sets how long the proxy's resolved addresses are reused for.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn ttl(&mut self, ttl: Duration) -> &mut SocksConnector {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets the timeout for opening each TCP connection to the proxy.
+    ///
+    /// Defaults to `None`, which waits for as long as the operating system
+    /// does.
+    pub fn connect_timeout(&mut self, timeout: Option<Duration>) -> &mut SocksConnector {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the timeout for each read and write during the SOCKS handshake.
+    ///
+    /// The timeouts are cleared once the connection is established. Defaults
+    /// to `None`.
+    pub fn handshake_timeout(&mut self, timeout: Option<Duration>) -> &mut SocksConnector {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Connects to a target server through the proxy.
+    pub fn connect<U: ToTargetAddr>(&self, target: U) -> io::Result<Socks5Stream> {
+        let target = target.to_target_addr()?;
+
+        let start = Instant::now();
+        let socket = self.connect_proxy()
+            .map_err(|(proxy, e)| ConnectError::unreachable(proxy, &target, e))?;
+        let tcp_connect = start.elapsed();
+
+        socket.set_read_timeout(self.handshake_timeout)?;
+        socket.set_write_timeout(self.handshake_timeout)?;
+        let builder = self.builder.read().unwrap().clone();
+        let stream = builder.establish(socket, target, tcp_connect)?;
+        if self.handshake_timeout.is_some() {
+            stream.get_ref().set_read_timeout(None)?;
+            stream.get_ref().set_write_timeout(None)?;
+        }
+        Ok(stream)
+    }
+
+    /// Replaces the credentials offered for a method, such as when a proxy
+    /// password is rotated.
+    ///
+    /// Subsequent connections use the new credentials.
+    pub fn set_credentials(&self, credentials: Credentials) {
+        let mut builder = self.builder.write().unwrap();
+        Arc::make_mut(&mut builder).credentials(credentials);
+    }
+
+    /// Replaces all of the settings taken from a `Socks5Builder`.
+    ///
+    /// Subsequent connections use the new settings.
+    pub fn set_builder(&self, builder: Socks5Builder) {
+        *self.builder.write().unwrap() = Arc::new(builder);
+    }
+
+    /// Discards the cached addresses of the proxy, so that it is resolved
+    /// again on the next connection.
+    pub fn clear_cache(&self) {
+        *self.cache.lock().unwrap() = None;
+    }
+
+    fn proxy_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(ref cached) = *cache {
+            if Instant::now() < cached.expires {
+                return Ok(cached.addrs.clone());
+            }
+        }
+
+        let addrs = resolve(&*self.resolver, &self.proxy)?;
+        *cache = Some(Cached {
+            addrs: addrs.clone(),
+            expires: Instant::now() + self.ttl,
+        });
+        Ok(addrs)
+    }
+
+    // Like `connect_proxy` in the crate root, but with the cached addresses
+    // and the connect timeout. The cache is cleared if no address works.
+    fn connect_proxy(&self) -> Result<TcpStream, (Option<SocketAddr>, io::Error)> {
+        let before_connect = self.builder.read().unwrap().socket_hook();
+        let mut last = None;
+        for addr in self.proxy_addrs().map_err(|e| (None, e))? {
+            let socket = match (&before_connect, self.connect_timeout) {
+                (Some(before_connect), timeout) => connect_with(addr, &**before_connect, timeout),
+                (None, Some(timeout)) => TcpStream::connect_timeout(&addr, timeout),
+                (None, None) => TcpStream::connect(addr),
+            };
+            match socket {
+                Ok(socket) => return Ok(socket),
+                Err(e) => last = Some((Some(addr), e)),
+            }
+        }
+
+        self.clear_cache();
+        Err(last.unwrap_or_else(|| {
+            let msg = "could not resolve to any addresses";
+            (None, io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use server::{read_password_request, write_password_response};
+    use super::*;
+    use test_util::{fake_proxy, fake_proxy_serving};
+
+    #[test]
+    fn reuses_connector() {
+        let (proxy, server) = fake_proxy_serving(2, [5, 0, 0, 1, 127, 0, 0, 1, 0, 80]);
+
+        let mut connector = SocksConnector::new(Socks5Builder::new(), proxy).unwrap();
+        connector.handshake_timeout(Some(Duration::from_secs(5)));
+        for target in &["10.0.0.1:80", "10.0.0.2:80"] {
+            let stream = connector.connect(*target).unwrap();
+            assert_eq!(*stream.target_addr(), target.to_target_addr().unwrap());
+            assert_eq!(stream.get_ref().read_timeout().unwrap(), None);
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rotate_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut passwords = vec![];
+            for _ in 0..2 {
+                let mut socket = listener.accept().unwrap().0;
+                let mut greeting = [0; 4];
+                socket.read_exact(&mut greeting).unwrap();
+                socket.write_all(&[5, 2]).unwrap();
+                let (_, password) = read_password_request(&mut socket).unwrap();
+                write_password_response(&mut socket, true).unwrap();
+                passwords.push(password);
+                let mut request = [0; 10];
+                socket.read_exact(&mut request).unwrap();
+                socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+            }
+            passwords
+        });
+
+        let mut builder = Socks5Builder::new();
+        builder.password("user", "old");
+        let connector = SocksConnector::new(builder, proxy).unwrap();
+        connector.connect("10.0.0.1:80").unwrap();
+        connector.set_credentials(Credentials::Password {
+            username: "user".to_owned(),
+            password: "new".to_owned(),
+        });
+        connector.connect("10.0.0.1:80").unwrap();
+        assert_eq!(server.join().unwrap(), ["old", "new"]);
+    }
+
+    #[test]
+    fn custom_resolver() {
+        let (proxy, server) = fake_proxy();
+
+        let mut connector = SocksConnector::new(Socks5Builder::new(), ("proxy.test", 1080))
+            .unwrap();
+        connector.resolver(move |host: &str, port| {
+            assert_eq!((host, port), ("proxy.test", 1080));
+            Ok(vec![proxy])
+        });
+        connector.connect("10.0.0.1:80").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+
+        let mut connector = SocksConnector::new(Socks5Builder::new(), proxy).unwrap();
+        connector.handshake_timeout(Some(Duration::from_millis(100)));
+        let err = connector.connect("10.0.0.1:80").unwrap_err();
+        assert!(err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut);
+        drop(listener);
+    }
+}
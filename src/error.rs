@@ -0,0 +1,465 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use TargetAddr;
+use server::ReplyCode;
+#[cfg(feature = "tokio-socks")]
+use tokio_socks;
+#[cfg(feature = "transcript")]
+use transcript::Transcript;
+use v5::AuthMethod;
+
+/// Errors specific to the SOCKS protocol.
+///
+/// These are returned wrapped in an `io::Error`, and can be inspected via
+/// `io::Error::get_ref` and `downcast_ref`. Errors from connecting through a
+/// proxy are additionally wrapped in a `ConnectError`.
+///
+/// New variants may be added in future releases.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// An internationalized domain name could not be converted to ASCII.
+    InvalidIdn {
+        /// The domain name.
+        domain: String,
+    },
+    /// A domain name target is malformed.
+    InvalidDomain {
+        /// The domain name.
+        domain: String,
+        /// A description of the problem.
+        reason: &'static str,
+    },
+    /// A domain name target is longer than allowed by the protocol or by the
+    /// configured limit.
+    DomainTooLong {
+        /// The domain name.
+        domain: String,
+        /// The length of the ASCII form of the domain name, in bytes.
+        len: usize,
+        /// The maximum length allowed.
+        max: usize,
+    },
+    /// A SOCKS4 user ID contains a NUL byte.
+    InvalidUserid,
+    /// A username is not between 1 and 255 bytes long, as required by
+    /// RFC 1929.
+    InvalidUsername {
+        /// The length of the username, in bytes.
+        len: usize,
+    },
+    /// A password is not between 1 and 255 bytes long, as required by
+    /// RFC 1929.
+    InvalidPassword {
+        /// The length of the password, in bytes.
+        len: usize,
+    },
+    /// The proxy rejected all of the offered authentication methods.
+    NoAcceptableAuthMethod {
+        /// The methods offered to the proxy, in order of preference.
+        advertised: Vec<AuthMethod>,
+    },
+    /// The proxy selected an authentication method which was not offered.
+    UnexpectedAuthMethod {
+        /// The method selected by the proxy.
+        selected: AuthMethod,
+        /// The methods offered to the proxy, in order of preference.
+        advertised: Vec<AuthMethod>,
+    },
+    /// The proxy selected "no authentication" although credentials were
+    /// offered, and the downgrade policy forbids it.
+    AuthDowngrade {
+        /// The methods offered to the proxy, in order of preference.
+        advertised: Vec<AuthMethod>,
+    },
+    /// A connection to a BIND listener came from a peer other than the
+    /// expected one.
+    UnexpectedBindPeer {
+        /// The target passed to the BIND request.
+        expected: TargetAddr,
+        /// The address of the peer reported by the proxy.
+        peer: TargetAddr,
+    },
+    /// The remote process did not connect to a BIND listener in time.
+    AcceptTimedOut {
+        /// The timeout which elapsed.
+        timeout: Duration,
+    },
+    /// A SOCKS4 proxy rejected a request.
+    Socks4Reply {
+        /// The reply code sent by the proxy.
+        code: u8,
+    },
+    /// A SOCKS5 proxy failed to carry out a request.
+    Socks5Reply {
+        /// The reply code sent by the proxy.
+        code: ReplyCode,
+    },
+}
+
+fn socks4_reply_reason(code: u8) -> &'static str {
+    match code {
+        92 => "request rejected because SOCKS server cannot connect to idnetd on the client",
+        93 => "request rejected because the client program and identd report different user-ids",
+        _ => "request rejected or failed",
+    }
+}
+
+fn socks5_reply_reason(code: ReplyCode) -> &'static str {
+    match code {
+        ReplyCode::GeneralFailure => "general SOCKS server failure",
+        ReplyCode::NotAllowed => "connection not allowed by ruleset",
+        ReplyCode::NetworkUnreachable => "network unreachable",
+        ReplyCode::HostUnreachable => "host unreachable",
+        ReplyCode::ConnectionRefused => "connection refused",
+        ReplyCode::TtlExpired => "TTL expired",
+        ReplyCode::CommandNotSupported => "command not supported",
+        ReplyCode::AddressTypeNotSupported => "address kind not supported",
+        ReplyCode::Succeeded | ReplyCode::Other(_) => "unknown error",
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidIdn { ref domain } => {
+                write!(fmt, "invalid internationalized domain name `{}`", domain)
+            }
+            Error::InvalidDomain { ref domain, reason } => {
+                write!(fmt, "invalid domain name `{}`: {}", domain.escape_debug(), reason)
+            }
+            Error::DomainTooLong { ref domain, len, max } => {
+                write!(fmt,
+                       "domain name `{}` is {} bytes long, but at most {} are allowed",
+                       domain.escape_debug(),
+                       len,
+                       max)
+            }
+            Error::InvalidUserid => fmt.write_str("SOCKS4 user ID contains a NUL byte"),
+            Error::InvalidUsername { len } => {
+                write!(fmt, "username must be 1 to 255 bytes long, but is {} bytes", len)
+            }
+            Error::InvalidPassword { len } => {
+                write!(fmt, "password must be 1 to 255 bytes long, but is {} bytes", len)
+            }
+            Error::NoAcceptableAuthMethod { ref advertised } => {
+                write!(fmt, "proxy accepted none of the offered auth methods {:?}", advertised)
+            }
+            Error::UnexpectedAuthMethod { selected, ref advertised } => {
+                write!(fmt,
+                       "proxy selected auth method {:?}, but only {:?} were offered",
+                       selected,
+                       advertised)
+            }
+            Error::AuthDowngrade { ref advertised } => {
+                write!(fmt,
+                       "proxy selected no authentication, but credentials were offered with {:?}",
+                       advertised)
+            }
+            Error::UnexpectedBindPeer { ref expected, ref peer } => {
+                write!(fmt, "expected a connection from {:?}, but got one from {:?}", expected, peer)
+            }
+            Error::AcceptTimedOut { timeout } => {
+                write!(fmt, "no connection to the BIND listener within {:?}", timeout)
+            }
+            Error::Socks4Reply { code } => fmt.write_str(socks4_reply_reason(code)),
+            Error::Socks5Reply { code } => fmt.write_str(socks5_reply_reason(code)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::InvalidIdn { .. } => "invalid internationalized domain name",
+            Error::InvalidDomain { .. } => "invalid domain name",
+            Error::DomainTooLong { .. } => "domain name too long",
+            Error::InvalidUserid => "invalid SOCKS4 user ID",
+            Error::InvalidUsername { .. } => "invalid username",
+            Error::InvalidPassword { .. } => "invalid password",
+            Error::NoAcceptableAuthMethod { .. } => "no acceptable auth methods",
+            Error::UnexpectedAuthMethod { .. } => "unknown auth method",
+            Error::AuthDowngrade { .. } => "authentication downgraded",
+            Error::UnexpectedBindPeer { .. } => "unexpected BIND peer",
+            Error::AcceptTimedOut { .. } => "BIND accept timed out",
+            Error::Socks4Reply { code } => socks4_reply_reason(code),
+            Error::Socks5Reply { code } => socks5_reply_reason(code),
+        }
+    }
+}
+
+impl Error {
+    /// Converts an error from the `tokio-socks` crate into the form this
+    /// crate's errors take.
+    ///
+    /// Rejections by the proxy are returned wrapped in an `io::Error` as
+    /// `Error::Socks5Reply`, `Error::Socks4Reply` or
+    /// `Error::NoAcceptableAuthMethod`, and I/O errors are unwrapped, so that
+    /// code which inspects errors works the same for both crates. Other errors
+    /// are wrapped as they are.
+    ///
+    /// Requires the `tokio-socks` Cargo feature.
+    #[cfg(feature = "tokio-socks")]
+    pub fn from_tokio_socks(err: tokio_socks::Error) -> io::Error {
+        use tokio_socks::Error as E;
+
+        let code = match err {
+            E::Io(e) => return e,
+            E::NoAcceptableAuthMethods => {
+                let err = Error::NoAcceptableAuthMethod { advertised: vec![] };
+                return io::Error::other(err);
+            }
+            E::IdentdAuthFailure => return io::Error::other(Error::Socks4Reply { code: 92 }),
+            E::InvalidUserIdAuthFailure => {
+                return io::Error::other(Error::Socks4Reply { code: 93 });
+            }
+            E::PasswordAuthFailure(_) | E::AuthorizationRequired => {
+                return io::Error::new(io::ErrorKind::PermissionDenied, err);
+            }
+            E::InvalidResponseVersion | E::InvalidReservedByte | E::UnknownAddressType => {
+                return io::Error::new(io::ErrorKind::InvalidData, err);
+            }
+            E::InvalidTargetAddress(_) | E::InvalidAuthValues(_) => {
+                return io::Error::new(io::ErrorKind::InvalidInput, err);
+            }
+            E::GeneralSocksServerFailure => ReplyCode::GeneralFailure,
+            E::ConnectionNotAllowedByRuleset => ReplyCode::NotAllowed,
+            E::NetworkUnreachable => ReplyCode::NetworkUnreachable,
+            E::HostUnreachable => ReplyCode::HostUnreachable,
+            E::ConnectionRefused => ReplyCode::ConnectionRefused,
+            E::TtlExpired => ReplyCode::TtlExpired,
+            E::CommandNotSupported => ReplyCode::CommandNotSupported,
+            E::AddressTypeNotSupported => ReplyCode::AddressTypeNotSupported,
+            _ => return io::Error::other(err),
+        };
+        io::Error::other(Error::Socks5Reply { code })
+    }
+}
+
+/// Requires the `tokio-socks` Cargo feature.
+///
+/// Errors with a counterpart in `tokio-socks`, such as rejections by the
+/// proxy, are converted to it; others are wrapped in `tokio_socks::Error::Io`.
+#[cfg(feature = "tokio-socks")]
+impl From<Error> for tokio_socks::Error {
+    fn from(err: Error) -> tokio_socks::Error {
+        use tokio_socks::Error as E;
+
+        match err {
+            Error::NoAcceptableAuthMethod { .. } => E::NoAcceptableAuthMethods,
+            Error::UnexpectedAuthMethod { .. } => E::UnknownAuthMethod,
+            Error::Socks4Reply { code: 92 } => E::IdentdAuthFailure,
+            Error::Socks4Reply { code: 93 } => E::InvalidUserIdAuthFailure,
+            Error::Socks5Reply { code } => {
+                match code {
+                    ReplyCode::GeneralFailure => E::GeneralSocksServerFailure,
+                    ReplyCode::NotAllowed => E::ConnectionNotAllowedByRuleset,
+                    ReplyCode::NetworkUnreachable => E::NetworkUnreachable,
+                    ReplyCode::HostUnreachable => E::HostUnreachable,
+                    ReplyCode::ConnectionRefused => E::ConnectionRefused,
+                    ReplyCode::TtlExpired => E::TtlExpired,
+                    ReplyCode::CommandNotSupported => E::CommandNotSupported,
+                    ReplyCode::AddressTypeNotSupported => E::AddressTypeNotSupported,
+                    ReplyCode::Succeeded | ReplyCode::Other(_) => E::UnknownError,
+                }
+            }
+            err => E::Io(io::Error::other(err)),
+        }
+    }
+}
+
+/// An error connecting to a target through a proxy.
+///
+/// This is returned wrapped in an `io::Error` of the same kind as the
+/// underlying error, and identifies the proxy and target involved so that
+/// applications using many proxies don't need to track them separately.
+#[derive(Debug)]
+pub struct ConnectError {
+    kind: ConnectErrorKind,
+    proxy: Option<SocketAddr>,
+    target: TargetAddr,
+    error: io::Error,
+    #[cfg(feature = "transcript")]
+    pub(crate) transcript: Option<Transcript>,
+}
+
+impl ConnectError {
+    pub(crate) fn wrap(proxy: Option<SocketAddr>, target: &TargetAddr, error: io::Error) -> io::Error {
+        ConnectError::with_kind(classify(&error), proxy, target, error)
+    }
+
+    pub(crate) fn unreachable(proxy: Option<SocketAddr>,
+                              target: &TargetAddr,
+                              error: io::Error)
+                              -> io::Error {
+        ConnectError::with_kind(ConnectErrorKind::ProxyUnreachable, proxy, target, error)
+    }
+
+    fn with_kind(kind: ConnectErrorKind,
+                 proxy: Option<SocketAddr>,
+                 target: &TargetAddr,
+                 error: io::Error)
+                 -> io::Error {
+        let err = ConnectError {
+            kind,
+            proxy,
+            target: target.clone(),
+            error,
+            #[cfg(feature = "transcript")]
+            transcript: None,
+        };
+        io::Error::new(err.error.kind(), err)
+    }
+
+    /// Returns the classification of the error.
+    pub fn kind(&self) -> ConnectErrorKind {
+        self.kind
+    }
+
+    /// Determines if the error is likely to be transient, such that retrying
+    /// the connection may succeed.
+    pub fn is_retryable(&self) -> bool {
+        self.kind.is_retryable()
+    }
+
+    /// Returns the address of the proxy, if the connection got as far as
+    /// resolving it.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy
+    }
+
+    /// Returns the target the proxy was asked to connect to.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Returns the bytes exchanged with the proxy before the error occurred,
+    /// if the connection got as far as negotiating with it.
+    ///
+    /// Requires the `transcript` Cargo feature.
+    #[cfg(feature = "transcript")]
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Returns a shared reference to the underlying error.
+    pub fn get_ref(&self) -> &io::Error {
+        &self.error
+    }
+
+    /// Consumes the `ConnectError`, returning the underlying error.
+    pub fn into_inner(self) -> io::Error {
+        self.error
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("error connecting to ")?;
+        match self.target {
+            TargetAddr::Ip(addr) => write!(fmt, "{}", addr)?,
+            TargetAddr::Domain(ref domain, port) => write!(fmt, "{}:{}", domain, port)?,
+        }
+        if let Some(proxy) = self.proxy {
+            write!(fmt, " through proxy {}", proxy)?;
+        }
+        write!(fmt, ": {}", self.error)
+    }
+}
+
+impl error::Error for ConnectError {
+    fn description(&self) -> &str {
+        #[allow(deprecated)]
+        self.error.description()
+    }
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A classification of `ConnectError`s.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ConnectErrorKind {
+    /// The proxy could not be resolved or connected to.
+    ProxyUnreachable,
+    /// The connection to the proxy failed partway through, for example by
+    /// timing out or being reset.
+    Io,
+    /// The proxy reported a general failure.
+    ProxyFailure,
+    /// The proxy could not reach the target, because the network or host was
+    /// unreachable, the connection was refused, or the TTL expired.
+    TargetUnreachable,
+    /// Authentication to the proxy failed, or no acceptable authentication
+    /// method could be agreed on.
+    Auth,
+    /// The request was not allowed by the proxy's ruleset.
+    NotAllowed,
+    /// The proxy does not support the requested command or address type.
+    Unsupported,
+    /// The proxy sent a malformed response.
+    Protocol,
+    /// The request itself was invalid, for example due to a malformed domain
+    /// name.
+    InvalidInput,
+    /// Any other error.
+    Other,
+}
+
+impl ConnectErrorKind {
+    /// Determines if errors of this kind are likely to be transient.
+    ///
+    /// Network failures are retryable, while errors due to configuration or
+    /// the proxy's policy, such as rejected credentials or unsupported
+    /// commands, are permanent.
+    pub fn is_retryable(&self) -> bool {
+        matches!(*self,
+                 ConnectErrorKind::ProxyUnreachable |
+                 ConnectErrorKind::Io |
+                 ConnectErrorKind::ProxyFailure |
+                 ConnectErrorKind::TargetUnreachable)
+    }
+}
+
+fn classify(error: &io::Error) -> ConnectErrorKind {
+    match error.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(&Error::Socks4Reply { code: 91 }) => return ConnectErrorKind::ProxyFailure,
+        Some(&Error::Socks4Reply { .. }) => return ConnectErrorKind::Auth,
+        Some(&Error::Socks5Reply { code }) => {
+            return match code {
+                ReplyCode::GeneralFailure => ConnectErrorKind::ProxyFailure,
+                ReplyCode::NotAllowed => ConnectErrorKind::NotAllowed,
+                ReplyCode::NetworkUnreachable |
+                ReplyCode::HostUnreachable |
+                ReplyCode::ConnectionRefused |
+                ReplyCode::TtlExpired => ConnectErrorKind::TargetUnreachable,
+                ReplyCode::CommandNotSupported |
+                ReplyCode::AddressTypeNotSupported => ConnectErrorKind::Unsupported,
+                ReplyCode::Succeeded | ReplyCode::Other(_) => ConnectErrorKind::Other,
+            }
+        }
+        Some(&Error::NoAcceptableAuthMethod { .. }) => return ConnectErrorKind::Auth,
+        Some(&Error::UnexpectedAuthMethod { .. }) => return ConnectErrorKind::Protocol,
+        Some(&Error::AuthDowngrade { .. }) => return ConnectErrorKind::Auth,
+        _ => {}
+    }
+
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => ConnectErrorKind::Auth,
+        io::ErrorKind::InvalidData => ConnectErrorKind::Protocol,
+        io::ErrorKind::InvalidInput => ConnectErrorKind::InvalidInput,
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::UnexpectedEof |
+        io::ErrorKind::Interrupted => ConnectErrorKind::Io,
+        _ => ConnectErrorKind::Other,
+    }
+}
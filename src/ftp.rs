@@ -0,0 +1,180 @@
+//! FTP active-mode data connections through a SOCKS5 proxy.
+//!
+//! In active mode, the FTP server opens the data connection to an address the
+//! client announces with a `PORT` or `EPRT` command. When the client sits
+//! behind a SOCKS proxy, that address must be the proxy-side end of a BIND
+//! request rather than a local socket.
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use {Socks5Listener, Socks5Stream, TargetAddr, ToTargetAddr};
+
+/// Formats an address as the argument of an FTP `PORT` command, as described
+/// in RFC 959.
+pub fn port_argument(addr: &SocketAddrV4) -> String {
+    let ip = addr.ip().octets();
+    format!("{},{},{},{},{},{}",
+            ip[0],
+            ip[1],
+            ip[2],
+            ip[3],
+            addr.port() >> 8,
+            addr.port() & 0xff)
+}
+
+/// Formats an address as the argument of an FTP `EPRT` command, as described
+/// in RFC 2428.
+pub fn eprt_argument(addr: &SocketAddr) -> String {
+    match *addr {
+        SocketAddr::V4(ref addr) => format!("|1|{}|{}|", addr.ip(), addr.port()),
+        SocketAddr::V6(ref addr) => format!("|2|{}|{}|", addr.ip(), addr.port()),
+    }
+}
+
+// Like `port_argument`, but fails for IPv6 addresses.
+pub(crate) fn checked_port_argument(addr: &SocketAddr) -> io::Result<String> {
+    match *addr {
+        SocketAddr::V4(ref addr) => Ok(port_argument(addr)),
+        SocketAddr::V6(_) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "PORT does not support IPv6 addresses"))
+        }
+    }
+}
+
+/// Returns the address a remote process should connect to in order to reach
+/// a proxy-side listener bound to `bound`.
+///
+/// Proxies commonly report an unspecified bound address, meaning that the
+/// listener accepts connections on the same interface the control connection
+/// was made to.
+pub(crate) fn connectable_addr(bound: &TargetAddr, proxy: SocketAddr) -> io::Result<SocketAddr> {
+    let addr = match *bound {
+        TargetAddr::Ip(addr) => addr,
+        TargetAddr::Domain(..) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "proxy bound to a domain name rather than an IP address"))
+        }
+    };
+
+    if !addr.ip().is_unspecified() {
+        return Ok(addr);
+    }
+
+    let addr = match proxy {
+        SocketAddr::V4(proxy) => SocketAddr::V4(SocketAddrV4::new(*proxy.ip(), addr.port())),
+        SocketAddr::V6(proxy) => SocketAddr::V6(SocketAddrV6::new(*proxy.ip(), addr.port(), 0, 0)),
+    };
+    Ok(addr)
+}
+
+/// A pending FTP data connection.
+///
+/// The data listener is bound through the same proxy the control connection
+/// uses.
+#[derive(Debug)]
+pub struct DataListener {
+    listener: Socks5Listener,
+    addr: SocketAddr,
+}
+
+impl DataListener {
+    /// Initiates a BIND request for a data connection from `server` through
+    /// the proxy that `control` is connected to.
+    ///
+    /// `server` should be the address of the FTP server; the proxy will only
+    /// accept data connections from it.
+    pub fn bind<U>(control: &Socks5Stream, server: U) -> io::Result<DataListener>
+        where U: ToTargetAddr
+    {
+        let proxy = control.get_ref().peer_addr()?;
+        let listener = Socks5Listener::bind(proxy, server)?;
+        DataListener::new(listener, proxy)
+    }
+
+    /// Like `bind`, but authenticates to the proxy with the given username
+    /// and password.
+    pub fn bind_with_password<U>(control: &Socks5Stream,
+                                 server: U,
+                                 username: &str,
+                                 password: &str)
+                                 -> io::Result<DataListener>
+        where U: ToTargetAddr
+    {
+        let proxy = control.get_ref().peer_addr()?;
+        let listener = Socks5Listener::bind_with_password(proxy, server, username, password)?;
+        DataListener::new(listener, proxy)
+    }
+
+    fn new(listener: Socks5Listener, proxy: SocketAddr) -> io::Result<DataListener> {
+        let addr = connectable_addr(listener.proxy_addr(), proxy)?;
+        Ok(DataListener {
+            listener,
+            addr,
+        })
+    }
+
+    /// Returns the address the FTP server should connect to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the argument of the `PORT` command announcing this listener.
+    ///
+    /// `PORT` only supports IPv4 addresses; use `eprt_argument` if the proxy
+    /// may bind to an IPv6 address.
+    pub fn port_argument(&self) -> io::Result<String> {
+        checked_port_argument(&self.addr)
+    }
+
+    /// Returns the argument of the `EPRT` command announcing this listener.
+    pub fn eprt_argument(&self) -> String {
+        eprt_argument(&self.addr)
+    }
+
+    /// Waits for the FTP server to open the data connection.
+    ///
+    /// The `PORT` or `EPRT` command, followed by the transfer command, should
+    /// be sent over the control connection before this method is called.
+    pub fn accept(self) -> io::Result<Socks5Stream> {
+        self.listener.accept()
+    }
+
+    /// Consumes the `DataListener`, returning the inner `Socks5Listener`.
+    pub fn into_inner(self) -> Socks5Listener {
+        self.listener
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+    use super::*;
+
+    #[test]
+    fn port() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(132, 235, 1, 2), 6275);
+        assert_eq!(port_argument(&addr), "132,235,1,2,24,131");
+    }
+
+    #[test]
+    fn eprt() {
+        let addr = "132.235.1.2:6275".parse().unwrap();
+        assert_eq!(eprt_argument(&addr), "|1|132.235.1.2|6275|");
+
+        let addr = "[1080::8:800:200c:417a]:5282".parse().unwrap();
+        assert_eq!(eprt_argument(&addr), "|2|1080::8:800:200c:417a|5282|");
+    }
+
+    #[test]
+    fn unspecified_bound_addr() {
+        let bound = TargetAddr::Ip("0.0.0.0:4000".parse().unwrap());
+        let proxy = "10.0.0.1:1080".parse().unwrap();
+        assert_eq!(connectable_addr(&bound, proxy).unwrap(),
+                   "10.0.0.1:4000".parse::<SocketAddr>().unwrap());
+
+        let bound = TargetAddr::Ip("10.0.0.2:4000".parse().unwrap());
+        assert_eq!(connectable_addr(&bound, proxy).unwrap(),
+                   "10.0.0.2:4000".parse::<SocketAddr>().unwrap());
+    }
+}
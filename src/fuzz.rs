@@ -0,0 +1,45 @@
+//! Entry points for fuzzing the protocol parsers.
+//!
+//! Requires the `fuzzing` Cargo feature, which also implements `Arbitrary`
+//! for the crate's message types. This module is not part of the crate's
+//! stable API; it exists for the targets in the `fuzz` directory.
+use {encode_domain, TargetAddr};
+use v4;
+use v5::{self, Validation};
+
+/// Parses a SOCKS4 reply.
+pub fn socks4_reply(data: &[u8]) {
+    let _ = v4::read_response(&mut &data[..]);
+}
+
+/// Parses a SOCKS5 reply in each validation mode.
+pub fn socks5_reply(data: &[u8]) {
+    for &validation in &[Validation::Standard, Validation::Strict, Validation::Lenient] {
+        let _ = v5::read_reply(&mut &data[..], validation);
+    }
+}
+
+/// Parses the header of a SOCKS5 UDP message.
+pub fn udp_header(data: &[u8]) {
+    if let Ok((_, len)) = v5::parse_udp_header(data) {
+        assert!(len <= data.len());
+    }
+}
+
+/// Checks that a UDP header parses back to the address it was encoded from.
+pub fn udp_header_roundtrip(addr: &TargetAddr) {
+    let header = match v5::encode_udp_header(addr) {
+        Ok(header) => header,
+        Err(_) => return,
+    };
+    let (parsed, len) = v5::parse_udp_header(&header).unwrap();
+    assert_eq!(len, header.len());
+    match (addr, parsed.addr()) {
+        // domains are sent in their ASCII form
+        (&TargetAddr::Domain(ref domain, port), &TargetAddr::Domain(ref parsed, parsed_port)) => {
+            assert_eq!(*parsed, encode_domain(domain).unwrap());
+            assert_eq!(parsed_port, port);
+        }
+        (addr, parsed) => assert_eq!(parsed, addr),
+    }
+}
@@ -0,0 +1,224 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct State {
+    last_active: Instant,
+    closed: bool,
+}
+
+struct Entry {
+    state: Arc<Mutex<State>>,
+    socket: TcpStream,
+    idle: Duration,
+}
+
+// The streams watched by the timer thread, which runs while there are any.
+#[derive(Default)]
+struct Timer {
+    entries: HashMap<u64, Entry>,
+    next_id: u64,
+    running: bool,
+}
+
+fn timer() -> &'static (Mutex<Timer>, Condvar) {
+    static TIMER: OnceLock<(Mutex<Timer>, Condvar)> = OnceLock::new();
+    TIMER.get_or_init(Default::default)
+}
+
+// Shuts down each stream once it has been idle for its timeout, sleeping
+// until the earliest deadline in between.
+fn run_timer() {
+    let (ref lock, ref cond) = *timer();
+    let mut timer = lock.lock().unwrap();
+    loop {
+        if timer.entries.is_empty() {
+            timer.running = false;
+            return;
+        }
+
+        let now = Instant::now();
+        let mut next = None::<Instant>;
+        timer.entries.retain(|_, entry| {
+            let mut state = entry.state.lock().unwrap();
+            let deadline = state.last_active + entry.idle;
+            if deadline <= now {
+                let _ = entry.socket.shutdown(Shutdown::Both);
+                state.closed = true;
+                return false;
+            }
+            next = Some(next.map_or(deadline, |next| cmp::min(next, deadline)));
+            true
+        });
+
+        if let Some(next) = next {
+            timer = cond.wait_timeout(timer, next - now).unwrap().0;
+        }
+    }
+}
+
+// Stops watching the stream when dropped.
+struct Registration {
+    id: u64,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // drop the timer's clone of the socket, which would otherwise keep
+        // the connection open
+        if let Ok(mut timer) = timer().0.lock() {
+            timer.entries.remove(&self.id);
+        }
+    }
+}
+
+/// A proxied stream which is shut down after a period of inactivity.
+///
+/// Useful when holding many proxied connections open against proxies which
+/// bill per open connection. A background thread, shared by every
+/// `IdleTimeout`, shuts the connection to the proxy down once no read or
+/// write has completed for the idle period, after which reads return EOF and
+/// writes fail. Time spent blocked in a read counts as idle.
+pub struct IdleTimeout<S> {
+    stream: S,
+    registration: Registration,
+}
+
+impl<S: AsRef<TcpStream>> IdleTimeout<S> {
+    /// Wraps a stream, shutting it down after it has been idle for `idle`.
+    pub fn new(stream: S, idle: Duration) -> io::Result<IdleTimeout<S>> {
+        let socket = stream.as_ref().try_clone()?;
+        let state = Arc::new(Mutex::new(State {
+            last_active: Instant::now(),
+            closed: false,
+        }));
+
+        let (ref lock, ref cond) = *timer();
+        let mut timer = lock.lock().unwrap();
+        if !timer.running {
+            thread::Builder::new()
+                .name("socks-idle-timeout".to_owned())
+                .spawn(run_timer)?;
+            timer.running = true;
+        }
+        let id = timer.next_id;
+        timer.next_id += 1;
+        timer.entries.insert(id, Entry {
+            state: state.clone(),
+            socket,
+            idle,
+        });
+        // the new deadline may be earlier than the one the timer waits for
+        cond.notify_one();
+
+        Ok(IdleTimeout {
+            stream,
+            registration: Registration { id, state },
+        })
+    }
+}
+
+impl<S> IdleTimeout<S> {
+    /// Determines if the stream has been shut down for being idle.
+    pub fn is_closed(&self) -> bool {
+        self.registration.state.lock().unwrap().closed
+    }
+
+    /// Returns a shared reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    ///
+    /// Reads and writes through the inner stream do not reset the idle
+    /// timer.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `IdleTimeout`, returning the inner stream.
+    ///
+    /// The stream is no longer shut down when idle.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn touch<T>(&self, r: io::Result<T>) -> io::Result<T> {
+        if r.is_ok() {
+            self.registration.state.lock().unwrap().last_active = Instant::now();
+        }
+        r
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for IdleTimeout<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("IdleTimeout")
+            .field("stream", &self.stream)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl<S: Read> Read for IdleTimeout<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let r = self.stream.read(buf);
+        self.touch(r)
+    }
+}
+
+impl<S: Write> Write for IdleTimeout<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let r = self.stream.write(buf);
+        self.touch(r)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::{pair, TcpMock};
+
+    #[test]
+    fn idle_close() {
+        let (client, mut server) = pair();
+
+        let mut stream = IdleTimeout::new(TcpMock(client), Duration::from_millis(200)).unwrap();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(100));
+            stream.write_all(b"x").unwrap();
+        }
+        assert!(!stream.is_closed());
+
+        thread::sleep(Duration::from_millis(400));
+        assert!(stream.is_closed());
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"xxx");
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn shared_timer() {
+        let (slow, _slow_server) = pair();
+        let (fast, _fast_server) = pair();
+
+        let slow = IdleTimeout::new(TcpMock(slow), Duration::from_secs(60)).unwrap();
+        // registered later, but expires first
+        let fast = IdleTimeout::new(TcpMock(fast), Duration::from_millis(100)).unwrap();
+        thread::sleep(Duration::from_millis(400));
+        assert!(fast.is_closed());
+        assert!(!slow.is_closed());
+    }
+}
@@ -3,23 +3,130 @@
 #![warn(missing_docs)]
 
 extern crate byteorder;
+extern crate socket2;
 
 #[cfg(unix)]
 extern crate libc;
 #[cfg(windows)]
 extern crate winapi;
+#[cfg(feature = "fuzzing")]
+extern crate arbitrary;
+#[cfg(feature = "chap")]
+extern crate hmac;
+#[cfg(feature = "http")]
+extern crate http;
+#[cfg(feature = "idna")]
+extern crate idna;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+extern crate io_uring;
+#[cfg(feature = "chap")]
+extern crate md5;
+#[cfg(feature = "rustls")]
+extern crate rustls;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio-socks")]
+extern crate tokio_socks;
+#[cfg(feature = "tungstenite")]
+extern crate tungstenite;
+#[cfg(feature = "url")]
+extern crate url;
 
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream,
+               ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 use std::vec;
 
-pub use v4::{Socks4Stream, Socks4Listener};
-pub use v5::{Socks5Stream, Socks5Listener, Socks5Datagram};
+use socket2::{Domain, SockRef, Socket, Type};
 
+pub use buffered::BufferedSocksStream;
+pub use connector::SocksConnector;
+pub use error::{ConnectError, ConnectErrorKind, Error};
+pub use idle::IdleTimeout;
+pub use proxied::MaybeProxiedStream;
+pub use relay::{copy_bidirectional, relay};
+pub use resolver::{CachingResolver, Resolver, SystemResolver};
+pub use writev::WritevExt;
+pub use v4::{Socks4Stream, Socks4Listener, Socks4Builder};
+pub use v5::{Socks5Stream, Socks5Listener, Socks5Datagram, Socks5Builder, AuthMethod,
+             Credentials, DatagramMessages, Socks5DatagramBuilder, Socks5Via, Validation,
+             DowngradePolicy, Fingerprint, SharedDatagram, DatagramHandle, UdpHeader, Command,
+             AddressType, InlineMessage};
+pub use server::ReplyCode;
+#[cfg(feature = "unstable-socks6")]
+pub use v6::{Socks6Stream, Socks6Builder, Socks6Option};
+
+pub mod ftp;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod server;
+
+mod buffered;
+#[cfg(feature = "chap")]
+mod chap;
+mod connector;
+mod error;
+mod idle;
+mod proxied;
+mod relay;
+mod resolver;
+#[cfg(test)]
+mod test_util;
 mod v4;
 mod v5;
+#[cfg(feature = "unstable-socks6")]
+mod v6;
 mod writev;
 
+#[cfg(feature = "rustls")]
+pub mod tls;
+#[cfg(feature = "transcript")]
+pub mod transcript;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+#[cfg(feature = "tungstenite")]
+pub mod websocket;
+
+/// A breakdown of the time spent establishing a proxied connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectTiming {
+    pub(crate) tcp_connect: Duration,
+    pub(crate) auth: Duration,
+    pub(crate) request: Duration,
+}
+
+impl ConnectTiming {
+    /// Returns the time taken to open the TCP connection to the proxy.
+    pub fn tcp_connect(&self) -> Duration {
+        self.tcp_connect
+    }
+
+    /// Returns the time taken to negotiate an authentication method and
+    /// authenticate to the proxy.
+    ///
+    /// This is always zero for SOCKS4, which has no authentication phase.
+    pub fn auth(&self) -> Duration {
+        self.auth
+    }
+
+    /// Returns the time between sending the request and receiving the
+    /// proxy's reply.
+    pub fn request(&self) -> Duration {
+        self.request
+    }
+
+    /// Returns the total time taken to establish the connection.
+    pub fn total(&self) -> Duration {
+        self.tcp_connect + self.auth + self.request
+    }
+}
+
 /// A description of a connection target.
 #[derive(Debug, Clone)]
 pub enum TargetAddr {
@@ -28,10 +135,344 @@ pub enum TargetAddr {
     /// Connect to a fully qualified domain name.
     ///
     /// The domain name will be passed along to the proxy server and DNS lookup
-    /// will happen there.
+    /// will happen there. If the `idna` Cargo feature is enabled,
+    /// internationalized domain names are converted to punycode first.
     Domain(String, u16),
 }
 
+impl TargetAddr {
+    /// Returns the host portion of the target.
+    ///
+    /// This is the domain name for `Domain` targets and the textual form of
+    /// the IP address for `Ip` targets.
+    pub fn host(&self) -> Cow<'_, str> {
+        match *self {
+            TargetAddr::Ip(addr) => Cow::Owned(addr.ip().to_string()),
+            TargetAddr::Domain(ref domain, _) => Cow::Borrowed(domain),
+        }
+    }
+
+    /// Returns the port of the target.
+    pub fn port(&self) -> u16 {
+        match *self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => port,
+        }
+    }
+
+    /// Changes the port of the target.
+    pub fn set_port(&mut self, new_port: u16) {
+        match *self {
+            TargetAddr::Ip(ref mut addr) => addr.set_port(new_port),
+            TargetAddr::Domain(_, ref mut port) => *port = new_port,
+        }
+    }
+
+    /// Returns `true` if the target is an IP address.
+    pub fn is_ip(&self) -> bool {
+        match *self {
+            TargetAddr::Ip(_) => true,
+            TargetAddr::Domain(..) => false,
+        }
+    }
+
+    /// Returns `true` if the target is a domain name.
+    pub fn is_domain(&self) -> bool {
+        !self.is_ip()
+    }
+
+    /// Returns the SOCKS5 address type used to encode the target.
+    ///
+    /// For an address reported by a proxy, this is the type the proxy sent in
+    /// its reply, so it can be used to tell whether the proxy bound an IPv4
+    /// or IPv6 socket.
+    pub fn address_type(&self) -> AddressType {
+        match *self {
+            TargetAddr::Ip(SocketAddr::V4(_)) => AddressType::Ipv4,
+            TargetAddr::Ip(SocketAddr::V6(_)) => AddressType::Ipv6,
+            TargetAddr::Domain(..) => AddressType::Domain,
+        }
+    }
+
+    /// Checks that a domain name target follows the letter-digit-hyphen
+    /// (LDH) syntax of RFC 1123 host names.
+    ///
+    /// Domain names are checked for basic well-formedness before they are
+    /// sent to the proxy, but many names accepted by DNS are not valid host
+    /// names. This method can be used to apply the stricter rules when the
+    /// target comes from untrusted input. IP address targets always pass.
+    pub fn validate_ldh(&self) -> io::Result<()> {
+        let domain = match *self {
+            TargetAddr::Ip(_) => return Ok(()),
+            TargetAddr::Domain(ref domain, _) => domain,
+        };
+
+        let ascii = encode_domain(domain)?;
+        for label in ascii.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(invalid_domain(domain, "labels must be 1 to 63 bytes long"));
+            }
+            if label.starts_with('-') || label.ends_with('-') {
+                return Err(invalid_domain(domain, "labels must not start or end with a hyphen"));
+            }
+            if !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                return Err(invalid_domain(domain,
+                                          "labels may only contain letters, digits and hyphens"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for TargetAddr {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<TargetAddr> {
+        if u.arbitrary()? {
+            Ok(TargetAddr::Ip(u.arbitrary()?))
+        } else {
+            Ok(TargetAddr::Domain(u.arbitrary()?, u.arbitrary()?))
+        }
+    }
+}
+
+/// Domain names are compared case-insensitively, as in DNS.
+impl PartialEq for TargetAddr {
+    fn eq(&self, other: &TargetAddr) -> bool {
+        match (self, other) {
+            (TargetAddr::Ip(a), TargetAddr::Ip(b)) => a == b,
+            (TargetAddr::Domain(a, a_port), TargetAddr::Domain(b, b_port)) => {
+                a.eq_ignore_ascii_case(b) && a_port == b_port
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for TargetAddr {}
+
+impl Hash for TargetAddr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            TargetAddr::Ip(ref addr) => {
+                state.write_u8(0);
+                addr.hash(state);
+            }
+            TargetAddr::Domain(ref domain, port) => {
+                state.write_u8(1);
+                for b in domain.bytes() {
+                    state.write_u8(b.to_ascii_lowercase());
+                }
+                // terminate the name so that it can't run into the port
+                state.write_u8(0xff);
+                port.hash(state);
+            }
+        }
+    }
+}
+
+fn invalid_domain(domain: &str, reason: &'static str) -> io::Error {
+    let err = Error::InvalidDomain {
+        domain: domain.to_owned(),
+        reason,
+    };
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+fn domain_too_long(domain: &str, len: usize, max: usize) -> io::Error {
+    let err = Error::DomainTooLong {
+        domain: domain.to_owned(),
+        len,
+        max,
+    };
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+// Checks that a domain name target is at most `max` bytes long once encoded.
+fn check_domain_len(target: &TargetAddr, max: usize) -> io::Result<()> {
+    if let TargetAddr::Domain(ref domain, _) = *target {
+        let len = encode_domain(domain)?.len();
+        if len > max {
+            return Err(domain_too_long(domain, len, max));
+        }
+    }
+    Ok(())
+}
+
+/// A borrowed handle to a socket: a `BorrowedFd` on Unix and a
+/// `BorrowedSocket` on Windows.
+///
+/// Passed to the `before_connect` hooks of the builders.
+#[cfg(unix)]
+pub type SocketHandle<'a> = std::os::unix::io::BorrowedFd<'a>;
+
+/// A borrowed handle to a socket: a `BorrowedFd` on Unix and a
+/// `BorrowedSocket` on Windows.
+///
+/// Passed to the `before_connect` hooks of the builders.
+#[cfg(windows)]
+pub type SocketHandle<'a> = std::os::windows::io::BorrowedSocket<'a>;
+
+type BeforeConnect = dyn Fn(SocketHandle<'_>, &SocketAddr) -> io::Result<()> + Sync + Send;
+
+// Combines a traffic class to set on sockets to the proxy with a user's
+// `before_connect` hook.
+fn socket_hook(traffic_class: Option<u32>,
+               before_connect: &Option<Arc<BeforeConnect>>)
+               -> Option<Arc<BeforeConnect>> {
+    let class = match traffic_class {
+        Some(class) => class,
+        None => return before_connect.clone(),
+    };
+    let before_connect = before_connect.clone();
+    Some(Arc::new(move |socket: SocketHandle<'_>, addr: &SocketAddr| {
+        set_traffic_class(SockRef::from(&socket), addr.is_ipv6(), class)?;
+        match before_connect {
+            Some(ref before_connect) => before_connect(socket, addr),
+            None => Ok(()),
+        }
+    }))
+}
+
+// Sets `IP_TOS` or `IPV6_TCLASS` on a socket.
+fn set_traffic_class(socket: SockRef<'_>, ipv6: bool, class: u32) -> io::Result<()> {
+    if !ipv6 {
+        return socket.set_tos_v4(class);
+    }
+
+    #[cfg(any(target_os = "android",
+              target_os = "freebsd",
+              target_os = "linux",
+              target_os = "macos",
+              target_os = "netbsd",
+              target_os = "openbsd"))]
+    return socket.set_tclass_v6(class);
+    #[cfg(not(any(target_os = "android",
+                  target_os = "freebsd",
+                  target_os = "linux",
+                  target_os = "macos",
+                  target_os = "netbsd",
+                  target_os = "openbsd")))]
+    return Err(io::Error::new(io::ErrorKind::Unsupported,
+                              "IPv6 traffic classes are not supported on this platform"));
+}
+
+// Connects to a proxy, trying each of its addresses in turn. On failure, the
+// last address tried is returned along with the error. `before_connect` is
+// called with each socket before it is connected.
+fn connect_proxy<T: ToSocketAddrs>(proxy: T,
+                                   before_connect: Option<&BeforeConnect>)
+                                   -> Result<TcpStream, (Option<SocketAddr>, io::Error)> {
+    let mut last = None;
+    for addr in proxy.to_socket_addrs().map_err(|e| (None, e))? {
+        let socket = match before_connect {
+            Some(before_connect) => connect_with(addr, before_connect, None),
+            None => TcpStream::connect(addr),
+        };
+        match socket {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last = Some((Some(addr), e)),
+        }
+    }
+    Err(last.unwrap_or_else(|| {
+        (None, io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses"))
+    }))
+}
+
+// Opens a connection with `socket2` so that `before_connect` can be called
+// between creating the socket and connecting it.
+fn connect_with(addr: SocketAddr,
+                before_connect: &BeforeConnect,
+                timeout: Option<Duration>)
+                -> io::Result<TcpStream> {
+    #[cfg(unix)]
+    use std::os::unix::io::AsFd;
+    #[cfg(windows)]
+    use std::os::windows::io::AsSocket;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    #[cfg(unix)]
+    before_connect(socket.as_fd(), &addr)?;
+    #[cfg(windows)]
+    before_connect(socket.as_socket(), &addr)?;
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+        None => socket.connect(&addr.into())?,
+    }
+    Ok(socket.into())
+}
+
+// Checks that the peer reported in the second reply to a BIND request matches
+// the target the request was made for. Ports are not compared.
+fn check_bind_peer(expected: &TargetAddr, peer: &TargetAddr) -> io::Result<()> {
+    let matches = match (expected, peer) {
+        (&TargetAddr::Ip(expected), &TargetAddr::Ip(peer)) => {
+            expected.ip().is_unspecified() || expected.ip() == peer.ip()
+        }
+        (&TargetAddr::Domain(..), &TargetAddr::Ip(peer)) => {
+            expected.to_socket_addrs()?.any(|addr| addr.ip() == peer.ip())
+        }
+        (&TargetAddr::Domain(..), &TargetAddr::Domain(..)) => {
+            expected.host().eq_ignore_ascii_case(&peer.host())
+        }
+        (&TargetAddr::Ip(_), &TargetAddr::Domain(..)) => false,
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        let err = Error::UnexpectedBindPeer {
+            expected: expected.clone(),
+            peer: peer.clone(),
+        };
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, err))
+    }
+}
+
+// Converts a domain name to the ASCII form sent to the proxy, checking that
+// it is well formed.
+//
+// Internationalized names are converted to punycode when the `idna` feature
+// is enabled, and otherwise passed along as UTF-8.
+fn encode_domain(domain: &str) -> io::Result<Cow<'_, str>> {
+    if domain.is_empty() {
+        return Err(invalid_domain(domain, "empty domain name"));
+    }
+    if domain.contains('\0') {
+        return Err(invalid_domain(domain, "domain name contains a NUL byte"));
+    }
+    if domain.starts_with('.') || domain.ends_with('.') {
+        return Err(invalid_domain(domain, "domain name starts or ends with a dot"));
+    }
+
+    if domain.is_ascii() {
+        Ok(Cow::Borrowed(domain))
+    } else {
+        idna_to_ascii(domain)
+    }
+}
+
+#[cfg(feature = "idna")]
+fn idna_to_ascii(domain: &str) -> io::Result<Cow<'_, str>> {
+    match idna::domain_to_ascii(domain) {
+        Ok(domain) => Ok(Cow::Owned(domain)),
+        Err(_) => {
+            let err = Error::InvalidIdn { domain: domain.to_owned() };
+            Err(io::Error::new(io::ErrorKind::InvalidInput, err))
+        }
+    }
+}
+
+#[cfg(not(feature = "idna"))]
+fn idna_to_ascii(domain: &str) -> io::Result<Cow<'_, str>> {
+    Ok(Cow::Borrowed(domain))
+}
+
+/// Domain names are resolved locally, allowing a `TargetAddr` to be used for
+/// direct connections when no proxy is configured.
+///
+/// Domain names are validated and converted to ASCII the same way as when
+/// they are sent to a proxy.
 impl ToSocketAddrs for TargetAddr {
     type Iter = Iter;
 
@@ -39,7 +480,7 @@ impl ToSocketAddrs for TargetAddr {
         let inner = match *self {
             TargetAddr::Ip(addr) => IterInner::Ip(Some(addr)),
             TargetAddr::Domain(ref domain, port) => {
-                let it = (&**domain, port).to_socket_addrs()?;
+                let it = (&*encode_domain(domain)?, port).to_socket_addrs()?;
                 IterInner::Domain(it)
             }
         };
@@ -66,6 +507,35 @@ impl Iterator for Iter {
     }
 }
 
+/// Returns the addresses of `addrs` as target candidates.
+///
+/// Any name is resolved locally, and each resulting address is yielded as a
+/// `TargetAddr::Ip` in the order returned by the resolver. This is used by
+/// `connect_each` to try each address of a target in turn, as
+/// `TcpStream::connect` does for direct connections.
+pub fn target_addrs<T: ToSocketAddrs>(addrs: T) -> io::Result<TargetAddrs<T::Iter>> {
+    addrs.to_socket_addrs().map(TargetAddrs)
+}
+
+/// An iterator over the addresses of a `ToSocketAddrs` value as
+/// `TargetAddr`s.
+///
+/// Returned by `target_addrs`.
+#[derive(Debug)]
+pub struct TargetAddrs<I>(I);
+
+impl<I: Iterator<Item = SocketAddr>> Iterator for TargetAddrs<I> {
+    type Item = TargetAddr;
+
+    fn next(&mut self) -> Option<TargetAddr> {
+        self.0.next().map(TargetAddr::Ip)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
 /// A trait for objects that can be converted to `TargetAddr`.
 pub trait ToTargetAddr {
     /// Converts the value of `self` to a `TargetAddr`.
@@ -158,3 +628,356 @@ impl<'a> ToTargetAddr for &'a str {
         (host, port).to_target_addr()
     }
 }
+
+// Returns the default port of URL schemes commonly used with proxies.
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        _ => None,
+    }
+}
+
+/// Default ports for URL schemes, used when converting URLs without an
+/// explicit port to targets.
+///
+/// The ports of `http`, `https`, `ws`, `wss` and `ftp` are known by default.
+/// Additional schemes, such as internal ones, can be registered, and
+/// registered ports take precedence over the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct SchemePorts {
+    ports: HashMap<String, u16>,
+}
+
+impl SchemePorts {
+    /// Creates a new mapping with only the default ports.
+    pub fn new() -> SchemePorts {
+        SchemePorts::default()
+    }
+
+    /// Registers the default port of a scheme.
+    ///
+    /// Schemes are case-insensitive.
+    pub fn insert(&mut self, scheme: &str, port: u16) -> &mut SchemePorts {
+        self.ports.insert(scheme.to_ascii_lowercase(), port);
+        self
+    }
+
+    /// Returns the default port of a scheme, if known.
+    pub fn port(&self, scheme: &str) -> Option<u16> {
+        let scheme = scheme.to_ascii_lowercase();
+        self.ports.get(&scheme).cloned().or_else(|| default_port(&scheme))
+    }
+
+    /// Converts a URL to a target.
+    ///
+    /// Requires the `url` Cargo feature.
+    #[cfg(feature = "url")]
+    pub fn url_target_addr(&self, url: &url::Url) -> io::Result<TargetAddr> {
+        let port = url.port()
+            .or_else(|| self.port(url.scheme()))
+            .or_else(|| url.port_or_known_default());
+        let port = match port {
+            Some(port) => port,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "URL has no port and an unknown scheme"))
+            }
+        };
+
+        match url.host() {
+            Some(url::Host::Domain(domain)) => Ok(TargetAddr::Domain(domain.to_owned(), port)),
+            Some(url::Host::Ipv4(ip)) => (ip, port).to_target_addr(),
+            Some(url::Host::Ipv6(ip)) => (ip, port).to_target_addr(),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "URL has no host")),
+        }
+    }
+
+    /// Converts a URI to a target.
+    ///
+    /// Requires the `http` Cargo feature.
+    #[cfg(feature = "http")]
+    pub fn uri_target_addr(&self, uri: &http::Uri) -> io::Result<TargetAddr> {
+        self.authority_target_addr(uri.scheme_str(), uri.host(), uri.port_u16())
+    }
+
+    // Converts the parts of a URI to a target, using the default port of
+    // `scheme` if there's no explicit port.
+    #[cfg(any(feature = "http", feature = "tungstenite"))]
+    pub(crate) fn authority_target_addr(&self,
+                                        scheme: Option<&str>,
+                                        host: Option<&str>,
+                                        port: Option<u16>)
+                                        -> io::Result<TargetAddr> {
+        let port = match port.or_else(|| scheme.and_then(|s| self.port(s))) {
+            Some(port) => port,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "URI has no port and an unknown scheme"))
+            }
+        };
+
+        let host = match host {
+            Some(host) => host,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "URI has no host")),
+        };
+
+        // IPv6 hosts are bracketed in URIs
+        if host.starts_with('[') && host.ends_with(']') {
+            if let Ok(ip) = host[1..host.len() - 1].parse::<Ipv6Addr>() {
+                return (ip, port).to_target_addr();
+            }
+        }
+
+        (host, port).to_target_addr()
+    }
+}
+
+/// Requires the `url` Cargo feature.
+///
+/// If the URL does not specify a port, the default port of its scheme is used.
+#[cfg(feature = "url")]
+impl ToTargetAddr for url::Url {
+    fn to_target_addr(&self) -> io::Result<TargetAddr> {
+        SchemePorts::new().url_target_addr(self)
+    }
+}
+
+/// Requires the `http` Cargo feature.
+///
+/// If the URI does not specify a port, the default port of its scheme is used.
+#[cfg(feature = "http")]
+impl ToTargetAddr for http::Uri {
+    fn to_target_addr(&self) -> io::Result<TargetAddr> {
+        SchemePorts::new().uri_target_addr(self)
+    }
+}
+
+/// Requires the `tokio-socks` Cargo feature.
+#[cfg(feature = "tokio-socks")]
+impl<'a> ToTargetAddr for tokio_socks::TargetAddr<'a> {
+    fn to_target_addr(&self) -> io::Result<TargetAddr> {
+        Ok(TargetAddr::from(self.clone()))
+    }
+}
+
+/// Requires the `tokio-socks` Cargo feature.
+#[cfg(feature = "tokio-socks")]
+impl<'a> From<tokio_socks::TargetAddr<'a>> for TargetAddr {
+    fn from(addr: tokio_socks::TargetAddr<'a>) -> TargetAddr {
+        match addr {
+            tokio_socks::TargetAddr::Ip(addr) => TargetAddr::Ip(addr),
+            tokio_socks::TargetAddr::Domain(domain, port) => {
+                TargetAddr::Domain(domain.into_owned(), port)
+            }
+        }
+    }
+}
+
+/// Requires the `tokio-socks` Cargo feature.
+#[cfg(feature = "tokio-socks")]
+impl From<TargetAddr> for tokio_socks::TargetAddr<'static> {
+    fn from(addr: TargetAddr) -> tokio_socks::TargetAddr<'static> {
+        match addr {
+            TargetAddr::Ip(addr) => tokio_socks::TargetAddr::Ip(addr),
+            TargetAddr::Domain(domain, port) => {
+                tokio_socks::TargetAddr::Domain(Cow::Owned(domain), port)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn accessors() {
+        let mut addr = TargetAddr::Domain("example.com".to_owned(), 80);
+        assert_eq!(addr.host(), "example.com");
+        assert_eq!(addr.port(), 80);
+        assert!(addr.is_domain());
+        assert_eq!(addr.address_type(), AddressType::Domain);
+        addr.set_port(443);
+        assert_eq!(addr.port(), 443);
+
+        let mut addr = TargetAddr::Ip("[::1]:80".parse().unwrap());
+        assert_eq!(addr.host(), "::1");
+        assert!(addr.is_ip());
+        assert_eq!(addr.address_type(), AddressType::Ipv6);
+        addr.set_port(443);
+        assert_eq!(addr, TargetAddr::Ip("[::1]:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn local_resolution() {
+        let addr = TargetAddr::Ip("127.0.0.1:80".parse().unwrap());
+        let addrs = addr.to_socket_addrs().unwrap().collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["127.0.0.1:80".parse().unwrap()]);
+
+        let addr = TargetAddr::Domain("localhost".to_owned(), 80);
+        assert!(addr.to_socket_addrs().unwrap().all(|a| a.ip().is_loopback() && a.port() == 80));
+
+        let addr = TargetAddr::Domain("local\0host".to_owned(), 80);
+        assert!(addr.to_socket_addrs().is_err());
+    }
+
+    #[test]
+    fn case_insensitive_domains() {
+        let a = TargetAddr::Domain("Example.COM".to_owned(), 80);
+        let b = TargetAddr::Domain("example.com".to_owned(), 80);
+        assert_eq!(a, b);
+        assert!(a != TargetAddr::Domain("example.com".to_owned(), 443));
+        assert!(a != TargetAddr::Domain("example.org".to_owned(), 80));
+
+        let mut map = HashMap::new();
+        map.insert(a, 1);
+        assert_eq!(map.get(&b), Some(&1));
+    }
+
+    #[cfg(feature = "url")]
+    #[test]
+    fn url_target_addr() {
+        let url = url::Url::parse("https://example.com/path").unwrap();
+        match url.to_target_addr().unwrap() {
+            TargetAddr::Domain(ref host, 443) if host == "example.com" => {}
+            addr => panic!("unexpected address {:?}", addr),
+        }
+
+        let url = url::Url::parse("http://[::1]:8080/").unwrap();
+        match url.to_target_addr().unwrap() {
+            TargetAddr::Ip(addr) => assert_eq!(addr, "[::1]:8080".parse().unwrap()),
+            addr => panic!("unexpected address {:?}", addr),
+        }
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn uri_target_addr() {
+        let uri = "http://example.com/path".parse::<http::Uri>().unwrap();
+        match uri.to_target_addr().unwrap() {
+            TargetAddr::Domain(ref host, 80) if host == "example.com" => {}
+            addr => panic!("unexpected address {:?}", addr),
+        }
+
+        let uri = "https://[::1]/".parse::<http::Uri>().unwrap();
+        match uri.to_target_addr().unwrap() {
+            TargetAddr::Ip(addr) => assert_eq!(addr, "[::1]:443".parse().unwrap()),
+            addr => panic!("unexpected address {:?}", addr),
+        }
+
+        let uri = "gopher://example.com/".parse::<http::Uri>().unwrap();
+        assert!(uri.to_target_addr().is_err());
+    }
+
+    #[test]
+    fn scheme_ports() {
+        let mut ports = SchemePorts::new();
+        assert_eq!(ports.port("https"), Some(443));
+        assert_eq!(ports.port("internal"), None);
+
+        ports.insert("Internal", 8080).insert("http", 8000);
+        assert_eq!(ports.port("internal"), Some(8080));
+        assert_eq!(ports.port("HTTP"), Some(8000));
+        assert_eq!(ports.port("wss"), Some(443));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn uri_scheme_ports() {
+        let mut ports = SchemePorts::new();
+        ports.insert("internal", 8080);
+
+        let uri = "internal://example.com/".parse::<http::Uri>().unwrap();
+        assert_eq!(ports.uri_target_addr(&uri).unwrap(),
+                   TargetAddr::Domain("example.com".to_owned(), 8080));
+        let uri = "internal://example.com:9000/".parse::<http::Uri>().unwrap();
+        assert_eq!(ports.uri_target_addr(&uri).unwrap(),
+                   TargetAddr::Domain("example.com".to_owned(), 9000));
+    }
+
+    fn invalid_domain_reason(err: io::Error) -> &'static str {
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::InvalidDomain { reason, .. }) => reason,
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn malformed_domains() {
+        assert_eq!(encode_domain("").map_err(invalid_domain_reason).unwrap_err(),
+                   "empty domain name");
+        assert_eq!(encode_domain("exa\0mple.com").map_err(invalid_domain_reason).unwrap_err(),
+                   "domain name contains a NUL byte");
+        assert_eq!(encode_domain(".example.com").map_err(invalid_domain_reason).unwrap_err(),
+                   "domain name starts or ends with a dot");
+        assert_eq!(encode_domain("example.com.").map_err(invalid_domain_reason).unwrap_err(),
+                   "domain name starts or ends with a dot");
+        assert!(encode_domain("under_score.example.com").is_ok());
+    }
+
+    #[test]
+    fn ldh() {
+        assert!(TargetAddr::Domain("foo-1.example.com".to_owned(), 80).validate_ldh().is_ok());
+        assert!(TargetAddr::Ip("127.0.0.1:80".parse().unwrap()).validate_ldh().is_ok());
+        assert!(TargetAddr::Domain("under_score.example.com".to_owned(), 80)
+                    .validate_ldh()
+                    .is_err());
+        assert!(TargetAddr::Domain("-foo.example.com".to_owned(), 80).validate_ldh().is_err());
+        assert!(TargetAddr::Domain("foo..com".to_owned(), 80).validate_ldh().is_err());
+    }
+
+    #[cfg(feature = "idna")]
+    #[test]
+    fn idn_to_punycode() {
+        assert_eq!(encode_domain("example.com").unwrap(), "example.com");
+        assert_eq!(encode_domain("bücher.example").unwrap(), "xn--bcher-kva.example");
+
+        let err = encode_domain("bücher.xn--a").unwrap_err();
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::InvalidIdn { .. }) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn bind_peer() {
+        let peer = TargetAddr::Ip("10.0.0.1:50000".parse().unwrap());
+        let expected = TargetAddr::Ip("10.0.0.1:0".parse().unwrap());
+        check_bind_peer(&expected, &peer).unwrap();
+        let expected = TargetAddr::Ip("0.0.0.0:0".parse().unwrap());
+        check_bind_peer(&expected, &peer).unwrap();
+
+        let expected = TargetAddr::Ip("10.0.0.2:0".parse().unwrap());
+        let err = check_bind_peer(&expected, &peer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        match *err.get_ref().unwrap().downcast_ref::<Error>().unwrap() {
+            Error::UnexpectedBindPeer { peer: ref actual, .. } => assert_eq!(*actual, peer),
+            ref e => panic!("unexpected error {:?}", e),
+        }
+
+        let expected = TargetAddr::Domain("localhost".to_owned(), 0);
+        let peer = TargetAddr::Domain("LOCALHOST".to_owned(), 1234);
+        check_bind_peer(&expected, &peer).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "tokio-socks")]
+    fn tokio_socks_conversions() {
+        let addr = TargetAddr::Domain("example.com".to_owned(), 80);
+        let converted = tokio_socks::TargetAddr::from(addr.clone());
+        assert_eq!(converted, tokio_socks::TargetAddr::Domain("example.com".into(), 80));
+        assert_eq!(converted.to_target_addr().unwrap(), addr);
+
+        let err = Error::from_tokio_socks(tokio_socks::Error::HostUnreachable);
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::Socks5Reply { code }) => assert_eq!(code, ReplyCode::HostUnreachable),
+            e => panic!("unexpected error {:?}", e),
+        }
+        let err = tokio_socks::Error::from(Error::Socks5Reply { code: ReplyCode::NotAllowed });
+        assert!(matches!(err, tokio_socks::Error::ConnectionNotAllowedByRuleset));
+    }
+}
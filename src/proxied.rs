@@ -0,0 +1,166 @@
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use {Socks4Stream, Socks5Stream};
+
+/// A connection which may or may not go through a SOCKS proxy.
+///
+/// This allows applications to hold a single stream type whether or not a
+/// proxy is configured. Reads and writes go to the target server in every
+/// case.
+#[derive(Debug)]
+pub enum MaybeProxiedStream {
+    /// A direct connection to the target server.
+    Direct(TcpStream),
+    /// A connection through a SOCKS4 proxy.
+    Socks4(Socks4Stream),
+    /// A connection through a SOCKS5 proxy.
+    Socks5(Socks5Stream),
+}
+
+impl MaybeProxiedStream {
+    /// Determines if the connection goes through a proxy.
+    pub fn is_proxied(&self) -> bool {
+        match *self {
+            MaybeProxiedStream::Direct(_) => false,
+            MaybeProxiedStream::Socks4(_) | MaybeProxiedStream::Socks5(_) => true,
+        }
+    }
+
+    /// Returns the address of the remote end of the connection: the target
+    /// server for a direct connection, and the proxy server otherwise.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    /// Returns the local address of the connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    /// Moves the inner `TcpStream` into or out of nonblocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.get_ref().set_nonblocking(nonblocking)
+    }
+
+    /// Returns a shared reference to the inner `TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self {
+            MaybeProxiedStream::Direct(ref socket) => socket,
+            MaybeProxiedStream::Socks4(ref stream) => stream.get_ref(),
+            MaybeProxiedStream::Socks5(ref stream) => stream.get_ref(),
+        }
+    }
+
+    /// Returns a mutable reference to the inner `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        match *self {
+            MaybeProxiedStream::Direct(ref mut socket) => socket,
+            MaybeProxiedStream::Socks4(ref mut stream) => stream.get_mut(),
+            MaybeProxiedStream::Socks5(ref mut stream) => stream.get_mut(),
+        }
+    }
+
+    /// Consumes the `MaybeProxiedStream`, returning the inner `TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        match self {
+            MaybeProxiedStream::Direct(socket) => socket,
+            MaybeProxiedStream::Socks4(stream) => stream.into_inner(),
+            MaybeProxiedStream::Socks5(stream) => stream.into_inner(),
+        }
+    }
+}
+
+impl From<TcpStream> for MaybeProxiedStream {
+    fn from(socket: TcpStream) -> MaybeProxiedStream {
+        MaybeProxiedStream::Direct(socket)
+    }
+}
+
+impl From<Socks4Stream> for MaybeProxiedStream {
+    fn from(stream: Socks4Stream) -> MaybeProxiedStream {
+        MaybeProxiedStream::Socks4(stream)
+    }
+}
+
+impl From<Socks5Stream> for MaybeProxiedStream {
+    fn from(stream: Socks5Stream) -> MaybeProxiedStream {
+        MaybeProxiedStream::Socks5(stream)
+    }
+}
+
+impl AsRef<TcpStream> for MaybeProxiedStream {
+    fn as_ref(&self) -> &TcpStream {
+        self.get_ref()
+    }
+}
+
+impl Read for MaybeProxiedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_mut().read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.get_mut().read_vectored(bufs)
+    }
+}
+
+impl Read for &MaybeProxiedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_ref().read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.get_ref().read_vectored(bufs)
+    }
+}
+
+impl Write for MaybeProxiedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.get_mut().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+impl Write for &MaybeProxiedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_ref().write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.get_ref().write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_ref().flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn direct() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut stream = MaybeProxiedStream::from(TcpStream::connect(addr).unwrap());
+        let mut socket = listener.accept().unwrap().0;
+
+        assert!(!stream.is_proxied());
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,391 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Copies data in both directions between a connection and a proxied
+/// stream, such as a `Socks5Stream`, until both sides have closed.
+///
+/// When one side reaches EOF, the write half of the other is shut down, so
+/// that half-closed connections are relayed faithfully. Returns the number of
+/// bytes copied from `a` to `b` and from `b` to `a`. To relay between two
+/// proxied streams, pass the first one's socket with `as_ref`.
+///
+/// On Linux, data is moved between the sockets with `splice(2)` through a
+/// pipe, avoiding copies through userspace; elsewhere it is copied through a
+/// 64 KiB buffer. One direction is handled on a separate thread. Both streams
+/// must be in blocking mode.
+///
+/// The sockets are read directly, so data already read into a wrapper's
+/// buffer is not relayed. Take it out first, for example with
+/// `BufferedSocksStream::into_parts`, and write it to the other side.
+pub fn relay<S>(a: &TcpStream, b: &S) -> io::Result<(u64, u64)>
+    where S: AsRef<TcpStream>
+{
+    let mut copied = (0, 0);
+    relay_sockets(a, b.as_ref(), None, None, &mut copied).map(|()| copied)
+}
+
+/// Copies data in both directions between two proxied streams until both
+/// sides have closed, returning the number of bytes copied from `a` to `b`
+/// and from `b` to `a`.
+///
+/// This is like `relay`, but both sides may be any stream over a
+/// `TcpStream`, such as a `Socks4Stream`, `Socks5Stream` or
+/// `MaybeProxiedStream`. As with `relay`, data already read into a wrapper's
+/// buffer is not relayed.
+pub fn copy_bidirectional<A, B>(a: &A, b: &B) -> io::Result<(u64, u64)>
+    where A: AsRef<TcpStream>,
+          B: AsRef<TcpStream>
+{
+    relay(a.as_ref(), b)
+}
+
+// Tracks when data was last read in either direction of a relay, so that it
+// can be torn down once neither side has sent anything for `timeout`.
+struct Activity {
+    start: Instant,
+    // milliseconds from `start` to the last read
+    last: AtomicU64,
+    timeout: Option<Duration>,
+}
+
+impl Activity {
+    fn new(timeout: Option<Duration>) -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            timeout,
+        }
+    }
+
+    fn touch(&self) {
+        if self.timeout.is_some() {
+            self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    // Handles an error reading from `src`, returning `Ok` if the read should
+    // be retried. A read which timed out is retried unless the relay has been
+    // idle for the whole timeout.
+    fn retry(&self, src: &TcpStream, err: io::Error) -> io::Result<()> {
+        let timeout = match (err.kind(), self.timeout) {
+            (io::ErrorKind::Interrupted, _) => return Ok(()),
+            (io::ErrorKind::WouldBlock, Some(timeout)) |
+            (io::ErrorKind::TimedOut, Some(timeout)) => timeout,
+            _ => return Err(err),
+        };
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        let idle = self.start.elapsed().saturating_sub(last);
+        if idle >= timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "relay idle timeout expired"));
+        }
+        src.set_read_timeout(Some(timeout - idle))?;
+        Ok(())
+    }
+}
+
+// Relays data in both directions between two sockets, limiting each
+// direction to `rate` bytes per second if set. The number of bytes copied
+// from `a` to `b` and from `b` to `a` is accumulated in `copied`, even if
+// the relay fails.
+//
+// If `idle_timeout` is set, the relay is torn down with a `TimedOut` error
+// once no data has been read from either socket for that long.
+pub(crate) fn relay_sockets(a: &TcpStream,
+                            b: &TcpStream,
+                            rate: Option<u64>,
+                            idle_timeout: Option<Duration>,
+                            copied: &mut (u64, u64))
+                            -> io::Result<()> {
+    if idle_timeout.is_some() {
+        a.set_read_timeout(idle_timeout)?;
+        b.set_read_timeout(idle_timeout)?;
+    }
+    let activity = Activity::new(idle_timeout);
+
+    let copy = |src: &TcpStream, dst: &TcpStream, total: &mut u64| {
+        let result = match rate {
+            Some(rate) => copy_limited(src, dst, rate, &activity, total),
+            None => copy(src, dst, &activity, total),
+        };
+        if let Err(ref e) = result {
+            if e.kind() == io::ErrorKind::TimedOut {
+                // wake up the other direction
+                let _ = src.shutdown(Shutdown::Both);
+                let _ = dst.shutdown(Shutdown::Both);
+            }
+        }
+        result
+    };
+
+    let a_write = a.try_clone()?;
+    let b_read = b.try_clone()?;
+    let (forward_total, reverse_total) = (&mut copied.0, &mut copied.1);
+    thread::scope(|scope| {
+        let reverse = thread::Builder::new()
+            .name("socks-relay".to_owned())
+            .spawn_scoped(scope, || {
+                let result = copy(&b_read, &a_write, reverse_total);
+                let _ = a_write.shutdown(Shutdown::Write);
+                result
+            })?;
+
+        let result = copy(a, b, forward_total);
+        let _ = b.shutdown(Shutdown::Write);
+        let reverse_result = reverse.join()
+            .map_err(|_| io::Error::other("relay thread panicked"))?;
+
+        result.and(reverse_result)
+    })
+}
+
+// Copies data through a buffer, sleeping as needed to keep the average
+// throughput at or below `rate` bytes per second.
+fn copy_limited(src: &TcpStream,
+                dst: &TcpStream,
+                rate: u64,
+                activity: &Activity,
+                total: &mut u64)
+                -> io::Result<()> {
+    let rate = cmp::max(rate, 1);
+    let mut buf = [0; 16 * 1024];
+    // don't read more than a second's worth of data at once
+    let chunk = cmp::min(buf.len() as u64, rate) as usize;
+
+    let start = Instant::now();
+    let mut limited = 0;
+    loop {
+        let len = match (&*src).read(&mut buf[..chunk]) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(e) => {
+                activity.retry(src, e)?;
+                continue;
+            }
+        };
+        activity.touch();
+        limited += len as u64;
+        let due = Duration::from_secs_f64(limited as f64 / rate as f64);
+        let elapsed = start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+        (&*dst).write_all(&buf[..len])?;
+        *total += len as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn copy(src: &TcpStream, dst: &TcpStream, activity: &Activity, total: &mut u64) -> io::Result<()> {
+    use libc;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::ptr;
+
+    // The amount of data moved by each call; the default capacity of a pipe.
+    const CHUNK_LEN: usize = 64 * 1024;
+
+    struct Pipe([RawFd; 2]);
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.0[0]);
+                libc::close(self.0[1]);
+            }
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        loop {
+            let r = unsafe {
+                libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, libc::SPLICE_F_MOVE)
+            };
+            if r >= 0 {
+                return Ok(r as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let pipe = Pipe(fds);
+
+    let mut moved = false;
+    loop {
+        let len = match splice(src.as_raw_fd(), pipe.0[1], CHUNK_LEN) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            // the socket doesn't support splicing; nothing has been moved yet
+            Err(ref e) if !moved && e.raw_os_error() == Some(libc::EINVAL) => {
+                return copy_buffered(src, dst, activity, total);
+            }
+            Err(e) => {
+                activity.retry(src, e)?;
+                continue;
+            }
+        };
+        activity.touch();
+        moved = true;
+
+        let mut remaining = len;
+        while remaining > 0 {
+            let written = splice(pipe.0[0], dst.as_raw_fd(), remaining)?;
+            remaining -= written;
+            *total += written as u64;
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy(src: &TcpStream, dst: &TcpStream, activity: &Activity, total: &mut u64) -> io::Result<()> {
+    copy_buffered(src, dst, activity, total)
+}
+
+// Like `io::copy`, but with a buffer large enough to keep up with a busy
+// relay.
+fn copy_buffered(src: &TcpStream,
+                 dst: &TcpStream,
+                 activity: &Activity,
+                 total: &mut u64)
+                 -> io::Result<()> {
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let len = match (&*src).read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(e) => {
+                activity.retry(src, e)?;
+                continue;
+            }
+        };
+        activity.touch();
+        (&*dst).write_all(&buf[..len])?;
+        *total += len as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::{pair, TcpMock};
+
+    #[test]
+    fn rate_limited() {
+        let (mut client, a) = pair();
+        let (b, mut target) = pair();
+
+        let start = Instant::now();
+        let relay = thread::spawn(move || {
+            let mut copied = (0, 0);
+            relay_sockets(&a, &b, Some(64 * 1024), None, &mut copied).unwrap();
+            copied
+        });
+        client.write_all(&[1; 32 * 1024]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut received = vec![];
+        target.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), 32 * 1024);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        drop(target);
+        assert_eq!(relay.join().unwrap(), (32 * 1024, 0));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (mut client, a) = pair();
+        let (b, mut target) = pair();
+
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let relay = thread::spawn(move || {
+            let mut copied = (0, 0);
+            let result = relay_sockets(&a, &b, None, Some(timeout), &mut copied);
+            (result, copied)
+        });
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(100));
+            client.write_all(b"ping").unwrap();
+            target.read_exact(&mut [0; 4]).unwrap();
+        }
+
+        let (result, copied) = relay.join().unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        // the counts survive the error
+        assert_eq!(copied, (12, 0));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(target.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn bidirectional() {
+        let (mut client, a) = pair();
+        let (b, mut target) = pair();
+
+        let relay = thread::spawn(move || relay(&a, &TcpMock(b)).unwrap());
+
+        let request = vec![1; 200 * 1024];
+        client.write_all(&request).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut received = vec![];
+        target.read_to_end(&mut received).unwrap();
+        assert!(received == request);
+
+        target.write_all(b"response").unwrap();
+        drop(target);
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"response");
+
+        assert_eq!(relay.join().unwrap(), (200 * 1024, 8));
+    }
+
+    #[test]
+    fn copy_bidirectional_streams() {
+        let (mut client, a) = pair();
+        let (b, mut target) = pair();
+
+        let copy = thread::spawn(move || copy_bidirectional(&TcpMock(a), &TcpMock(b)).unwrap());
+        client.write_all(b"request").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut received = vec![];
+        target.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"request");
+
+        target.write_all(b"response").unwrap();
+        drop(target);
+        let mut received = vec![];
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"response");
+
+        assert_eq!(copy.join().unwrap(), (7, 8));
+    }
+
+    #[test]
+    fn buffered() {
+        let (mut client, a) = pair();
+        let (b, mut target) = pair();
+
+        let copy = thread::spawn(move || {
+            let mut copied = 0;
+            copy_buffered(&a, &b, &Activity::new(None), &mut copied).unwrap();
+            b.shutdown(Shutdown::Write).unwrap();
+            copied
+        });
+        client.write_all(&[1; 100 * 1024]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut received = vec![];
+        target.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), 100 * 1024);
+        assert_eq!(copy.join().unwrap(), 100 * 1024);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use {TargetAddr, encode_domain};
+
+/// A name resolver used where names are resolved locally rather than by the
+/// proxy, such as the proxy addresses of the builders and `SocksConnector`,
+/// or the targets of a `Socks4Builder` and `Socks5Builder::connect_each`.
+///
+/// This allows applications to supply their own resolver in place of the
+/// system's blocking `getaddrinfo`, such as an asynchronous resolver driven
+/// by a runtime, a caching resolver, or a mock in tests. It is implemented
+/// for closures taking a host and port.
+pub trait Resolver: Sync + Send {
+    /// Resolves a host name to a list of addresses with the specified port.
+    ///
+    /// Internationalized names are passed in their ASCII form.
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl<F> Resolver for F
+    where F: Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Sync + Send
+{
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self(host, port)
+    }
+}
+
+/// A `Resolver` using the system's resolver through `ToSocketAddrs`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        (host, port).to_socket_addrs().map(Iterator::collect)
+    }
+}
+
+/// A `Resolver` which caches the results of another resolver.
+///
+/// Results are keyed by host name, and are reused for any port until the TTL
+/// expires. The system resolver doesn't report the TTLs of DNS records, so a
+/// fixed TTL is used. Failed lookups are not cached.
+///
+/// Clones of a builder share the resolver passed to it, and so its cache.
+pub struct CachingResolver<R> {
+    resolver: R,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl<R: fmt::Debug> fmt::Debug for CachingResolver<R> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CachingResolver")
+            .field("resolver", &self.resolver)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Creates a cache in front of `resolver` whose entries expire after
+    /// `ttl`.
+    pub fn new(resolver: R, ttl: Duration) -> CachingResolver<R> {
+        CachingResolver {
+            resolver,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Discards all cached results.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let now = Instant::now();
+        if let Some(&(ref ips, expires)) = self.cache.lock().unwrap().get(host) {
+            if now < expires {
+                return Ok(ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect());
+            }
+        }
+
+        // don't hold the lock while resolving, so lookups of other names
+        // aren't blocked
+        let addrs = self.resolver.resolve(host, port)?;
+        let ips = addrs.iter().map(SocketAddr::ip).collect();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, &mut (_, expires)| now < expires);
+        cache.insert(host.to_owned(), (ips, now + self.ttl));
+        Ok(addrs)
+    }
+}
+
+// Resolves an address with a resolver, passing IP addresses through.
+pub(crate) fn resolve(resolver: &dyn Resolver, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+    match *addr {
+        TargetAddr::Ip(addr) => Ok(vec![addr]),
+        TargetAddr::Domain(ref domain, port) => resolver.resolve(&encode_domain(domain)?, port),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn caching() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+        let resolver = CachingResolver::new(move |_: &str, port| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::from(([10, 0, 0, 1], port))])
+        }, Duration::from_secs(60));
+
+        assert_eq!(resolver.resolve("example.com", 80).unwrap(), ["10.0.0.1:80".parse().unwrap()]);
+        assert_eq!(resolver.resolve("example.com", 443).unwrap(),
+                   ["10.0.0.1:443".parse().unwrap()]);
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        resolver.resolve("example.net", 80).unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        resolver.clear();
+        resolver.resolve("example.com", 80).unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+}
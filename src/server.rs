@@ -0,0 +1,1822 @@
+//! Building blocks for SOCKS5 server implementations.
+use socket2::{Domain, Socket, Type};
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6,
+               TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use {AddressType, AuthMethod, Command, ConnectError, Error, SocksConnector, TargetAddr};
+use relay::relay_sockets;
+use v5::{read_addr, write_addr, MAX_ADDR_LEN};
+
+#[cfg(feature = "tokio")]
+mod tokio_acceptor;
+
+#[cfg(feature = "tokio")]
+pub use self::tokio_acceptor::connect_direct_tokio;
+
+/// A SOCKS5 reply code, as defined in RFC 1928.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReplyCode {
+    /// The request succeeded.
+    Succeeded,
+    /// General SOCKS server failure.
+    GeneralFailure,
+    /// Connection not allowed by ruleset.
+    NotAllowed,
+    /// Network unreachable.
+    NetworkUnreachable,
+    /// Host unreachable.
+    HostUnreachable,
+    /// Connection refused.
+    ConnectionRefused,
+    /// TTL expired.
+    TtlExpired,
+    /// Command not supported.
+    CommandNotSupported,
+    /// Address type not supported.
+    AddressTypeNotSupported,
+    /// A reply code not assigned by RFC 1928.
+    Other(u8),
+}
+
+impl From<u8> for ReplyCode {
+    fn from(code: u8) -> ReplyCode {
+        match code {
+            0 => ReplyCode::Succeeded,
+            1 => ReplyCode::GeneralFailure,
+            2 => ReplyCode::NotAllowed,
+            3 => ReplyCode::NetworkUnreachable,
+            4 => ReplyCode::HostUnreachable,
+            5 => ReplyCode::ConnectionRefused,
+            6 => ReplyCode::TtlExpired,
+            7 => ReplyCode::CommandNotSupported,
+            8 => ReplyCode::AddressTypeNotSupported,
+            code => ReplyCode::Other(code),
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for ReplyCode {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<ReplyCode> {
+        u8::arbitrary(u).map(ReplyCode::from)
+    }
+}
+
+impl ReplyCode {
+    /// Returns the reply code describing an error connecting to a target.
+    pub fn from_io_error(err: &io::Error) -> ReplyCode {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => ReplyCode::ConnectionRefused,
+            io::ErrorKind::NotFound => ReplyCode::HostUnreachable,
+            io::ErrorKind::TimedOut => ReplyCode::TtlExpired,
+            _ => ReplyCode::GeneralFailure,
+        }
+    }
+}
+
+impl From<ReplyCode> for u8 {
+    fn from(code: ReplyCode) -> u8 {
+        match code {
+            ReplyCode::Succeeded => 0,
+            ReplyCode::GeneralFailure => 1,
+            ReplyCode::NotAllowed => 2,
+            ReplyCode::NetworkUnreachable => 3,
+            ReplyCode::HostUnreachable => 4,
+            ReplyCode::ConnectionRefused => 5,
+            ReplyCode::TtlExpired => 6,
+            ReplyCode::CommandNotSupported => 7,
+            ReplyCode::AddressTypeNotSupported => 8,
+            ReplyCode::Other(code) => code,
+        }
+    }
+}
+
+/// A SOCKS5 reply to a client's request.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    code: ReplyCode,
+    bound_addr: TargetAddr,
+}
+
+impl Reply {
+    /// Creates a reply with the specified code.
+    ///
+    /// The bound address defaults to `0.0.0.0:0`, which is appropriate for
+    /// failure replies.
+    pub fn new(code: ReplyCode) -> Reply {
+        let unspecified = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0);
+        Reply {
+            code,
+            bound_addr: TargetAddr::Ip(SocketAddr::V4(unspecified)),
+        }
+    }
+
+    /// Creates a successful reply carrying the specified bound address.
+    pub fn succeeded(bound_addr: TargetAddr) -> Reply {
+        Reply::new(ReplyCode::Succeeded).bound_addr(bound_addr)
+    }
+
+    /// Creates a successful reply to a CONNECT request.
+    ///
+    /// The bound address is the local address of `outbound`, the server's
+    /// connection to the target, as required by RFC 1928. Some clients, such
+    /// as FTP and peer-to-peer software, rely on it being accurate.
+    pub fn connected(outbound: &TcpStream) -> io::Result<Reply> {
+        Ok(Reply::succeeded(TargetAddr::Ip(outbound.local_addr()?)))
+    }
+
+    /// Replaces the bound address with the unspecified address and port of
+    /// the same family, to avoid revealing the server's outbound address.
+    pub fn mask(self) -> Reply {
+        let unspecified = match self.bound_addr {
+            TargetAddr::Ip(SocketAddr::V6(_)) => {
+                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0))
+            }
+            _ => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        self.bound_addr(TargetAddr::Ip(unspecified))
+    }
+
+    /// Sets the bound address sent in the reply.
+    pub fn bound_addr(mut self, bound_addr: TargetAddr) -> Reply {
+        self.bound_addr = bound_addr;
+        self
+    }
+
+    /// Returns the reply code.
+    pub fn code(&self) -> ReplyCode {
+        self.code
+    }
+
+    /// Returns the bound address.
+    pub fn get_bound_addr(&self) -> &TargetAddr {
+        &self.bound_addr
+    }
+
+    /// Encodes the reply.
+    ///
+    /// Returns an error if the bound address is a domain name which cannot be
+    /// encoded.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut packet = [0; MAX_ADDR_LEN + 3];
+        packet[0] = 5; // protocol version
+        packet[1] = self.code.into(); // reply code
+        packet[2] = 0; // reserved
+        let len = write_addr(&mut packet[3..], &self.bound_addr)?;
+        Ok(packet[..len + 3].to_vec())
+    }
+
+    /// Encodes the reply and writes it to a stream.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_bytes()?)
+    }
+}
+
+/// Reads a username/password authentication request (RFC 1929) from a
+/// client, returning the username and password.
+///
+/// An empty username or password is rejected with an `InvalidData` error
+/// wrapping `Error::InvalidUsername` or `Error::InvalidPassword`, and fields
+/// which aren't valid UTF-8 are also rejected. Each field is read according
+/// to its length prefix, so neither can run into the other.
+pub fn read_password_request<R: Read>(r: &mut R) -> io::Result<(String, String)> {
+    fn read_field<R: Read>(r: &mut R) -> io::Result<String> {
+        let mut len = [0];
+        r.read_exact(&mut len)?;
+        let mut field = vec![0; len[0] as usize];
+        r.read_exact(&mut field)?;
+        String::from_utf8(field)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "credentials are not UTF-8"))
+    }
+
+    let mut version = [0];
+    r.read_exact(&mut version)?;
+    if version[0] != 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid request version"));
+    }
+    let username = read_field(r)?;
+    let password = read_field(r)?;
+    if username.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, Error::InvalidUsername { len: 0 }));
+    }
+    if password.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, Error::InvalidPassword { len: 0 }));
+    }
+    Ok((username, password))
+}
+
+/// Writes the response to a username/password authentication request.
+pub fn write_password_response<W: Write>(w: &mut W, success: bool) -> io::Result<()> {
+    w.write_all(&[1, if success { 0 } else { 1 }])
+}
+
+// The number of tracked source addresses above which expired rate limiting
+// windows are purged.
+const RATE_TABLE_PURGE_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct LimiterState {
+    sessions: usize,
+    windows: HashMap<IpAddr, (Instant, u32)>,
+}
+
+/// Limits on the sessions accepted by a SOCKS server.
+///
+/// A server should call `acquire` for each incoming connection and hold on to
+/// the returned permit for the lifetime of the session. If a limit has been
+/// reached, the returned reply should be sent in response to the client's
+/// request, once it has been read, and the connection closed.
+/// `Socks5Server::connection_limiter` does this.
+///
+/// Clones of a limiter share their state.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    max_sessions: Option<usize>,
+    rate: Option<(u32, Duration)>,
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl Default for ConnectionLimiter {
+    fn default() -> ConnectionLimiter {
+        ConnectionLimiter::new()
+    }
+}
+
+impl ConnectionLimiter {
+    /// Creates a limiter which accepts every connection.
+    pub fn new() -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_sessions: None,
+            rate: None,
+            state: Arc::new(Mutex::new(LimiterState {
+                sessions: 0,
+                windows: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Sets the maximum number of concurrent sessions.
+    pub fn max_sessions(mut self, max_sessions: usize) -> ConnectionLimiter {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// Limits each source IP address to `connections` connections per
+    /// `period`.
+    pub fn per_ip_rate(mut self, connections: u32, period: Duration) -> ConnectionLimiter {
+        self.rate = Some((connections, period));
+        self
+    }
+
+    /// Returns the number of sessions currently holding a permit.
+    pub fn sessions(&self) -> usize {
+        self.state.lock().unwrap().sessions
+    }
+
+    /// Attempts to admit a new session from the specified source address.
+    ///
+    /// If a limit has been reached, a "connection not allowed by ruleset"
+    /// reply is returned instead of a permit.
+    pub fn acquire(&self, source: IpAddr) -> Result<SessionPermit, Reply> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(max_sessions) = self.max_sessions {
+            if state.sessions >= max_sessions {
+                return Err(Reply::new(ReplyCode::NotAllowed));
+            }
+        }
+
+        if let Some((connections, period)) = self.rate {
+            let now = Instant::now();
+            if state.windows.len() > RATE_TABLE_PURGE_THRESHOLD {
+                state.windows.retain(|_, &mut (start, _)| now.duration_since(start) < period);
+            }
+
+            let window = state.windows.entry(source).or_insert((now, 0));
+            if now.duration_since(window.0) >= period {
+                *window = (now, 0);
+            }
+            if window.1 >= connections {
+                return Err(Reply::new(ReplyCode::NotAllowed));
+            }
+            window.1 += 1;
+        }
+
+        state.sessions += 1;
+        Ok(SessionPermit { state: self.state.clone() })
+    }
+}
+
+/// A session admitted by a `ConnectionLimiter`.
+///
+/// The session is counted against the limiter until the permit is dropped.
+#[derive(Debug)]
+pub struct SessionPermit {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sessions -= 1;
+        }
+    }
+}
+
+/// A request received by a `Socks5Server`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    command: Command,
+    target: TargetAddr,
+    source: SocketAddr,
+    username: Option<String>,
+}
+
+impl Request {
+    /// Returns the command sent by the client.
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// Returns the target of the request.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Returns the address of the client.
+    pub fn source_addr(&self) -> SocketAddr {
+        self.source
+    }
+
+    /// Returns the username the client authenticated with, if password
+    /// authentication is required.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+}
+
+// How long `connect_direct` waits for each of a target's addresses.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens the outbound connection for a request directly to its target.
+///
+/// This is the simplest handler for `Socks5Server::serve`. Domain names are
+/// resolved locally, and each address is tried in turn for up to 10 seconds.
+/// Errors are reported to the client with the reply code returned by
+/// `ReplyCode::from_io_error`.
+pub fn connect_direct(request: &Request) -> Result<TcpStream, Reply> {
+    let failure = |e: io::Error| Reply::new(ReplyCode::from_io_error(&e));
+    let mut last = None;
+    for addr in request.target_addr().to_socket_addrs().map_err(failure)? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(failure(last.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "could not resolve to any addresses")
+    })))
+}
+
+/// Opens the outbound connection for a request through an upstream SOCKS5
+/// proxy, turning the server into a chaining relay.
+///
+/// Use it as the handler for `Socks5Server::serve` with a closure capturing
+/// the connector:
+///
+/// ```no_run
+/// use socks::{Socks5Builder, SocksConnector};
+/// use socks::server::{self, Socks5Server};
+///
+/// let connector = SocksConnector::new(Socks5Builder::new(), "upstream.example:1080").unwrap();
+/// let config = Socks5Server::new();
+/// let listener = config.bind("127.0.0.1:1080").unwrap();
+/// config.serve(listener, |request| server::connect_upstream(&connector, request)).unwrap();
+/// ```
+///
+/// Domain names are passed to the upstream proxy unresolved. Failure replies
+/// from the upstream proxy are forwarded to the client, and other errors
+/// are reported with the reply code returned by `ReplyCode::from_io_error`.
+pub fn connect_upstream(connector: &SocksConnector, request: &Request) -> Result<TcpStream, Reply> {
+    Outbound::connect(connector, request.target_addr()).map_err(|e| failure_reply(&e))
+}
+
+// Returns the reply reporting an error opening an outbound connection,
+// forwarding the reply code of an upstream proxy's failure reply.
+fn failure_reply(err: &io::Error) -> Reply {
+    let error = err.get_ref()
+        .and_then(|e| e.downcast_ref::<ConnectError>())
+        .and_then(|e| e.get_ref().get_ref())
+        .and_then(|e| e.downcast_ref::<Error>());
+    let code = match error {
+        Some(&Error::Socks5Reply { code }) => code,
+        _ => ReplyCode::from_io_error(err),
+    };
+    Reply::new(code)
+}
+
+/// A backend opening the outbound connections of a `Socks5Server`.
+///
+/// Implementations decide how targets are reached, which lets embedders
+/// chain to upstream proxies, apply their own DNS policy, or serve virtual
+/// in-process targets. It is implemented for `SocksConnector`, which
+/// connects through an upstream SOCKS5 proxy, and for closures taking a
+/// `&TargetAddr`. See `Socks5Server::serve_outbound`.
+pub trait Outbound: Sync {
+    /// Opens a connection to `target`.
+    ///
+    /// Failure replies from an upstream SOCKS5 proxy are forwarded to the
+    /// client, and other errors are reported with the reply code returned by
+    /// `ReplyCode::from_io_error`.
+    fn connect(&self, target: &TargetAddr) -> io::Result<TcpStream>;
+}
+
+impl<F> Outbound for F
+    where F: Fn(&TargetAddr) -> io::Result<TcpStream> + Sync
+{
+    fn connect(&self, target: &TargetAddr) -> io::Result<TcpStream> {
+        self(target)
+    }
+}
+
+impl Outbound for SocksConnector {
+    fn connect(&self, target: &TargetAddr) -> io::Result<TcpStream> {
+        SocksConnector::connect(self, target.clone()).map(|stream| stream.into_inner())
+    }
+}
+
+/// The reason a session served by a `Socks5Server` ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// Data was relayed until both sides closed their connections.
+    Closed,
+    /// The client offered no acceptable authentication method, or its
+    /// credentials were rejected.
+    AuthFailed,
+    /// The request was refused with the specified reply code.
+    Refused(ReplyCode),
+    /// Neither side sent any data for the server's idle timeout.
+    IdleTimeout,
+    /// An I/O error of the specified kind occurred.
+    Error(io::ErrorKind),
+    /// The handler or a policy hook panicked.
+    Panicked,
+}
+
+/// A record of a session served by a `Socks5Server`, passed to its access
+/// log when the session ends.
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    timestamp: SystemTime,
+    duration: Duration,
+    source: SocketAddr,
+    username: Option<String>,
+    target: Option<TargetAddr>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    termination: Termination,
+}
+
+impl AccessRecord {
+    /// Returns the time at which the client's connection was accepted.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Returns how long the session lasted.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Returns the address of the client.
+    pub fn source_addr(&self) -> SocketAddr {
+        self.source
+    }
+
+    /// Returns the username the client sent, if it attempted password
+    /// authentication.
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Returns the target of the client's request, if it got as far as
+    /// sending one.
+    pub fn target_addr(&self) -> Option<&TargetAddr> {
+        self.target.as_ref()
+    }
+
+    /// Returns the number of bytes relayed from the client to the target.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Returns the number of bytes relayed from the target to the client.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Returns the reason the session ended.
+    pub fn termination(&self) -> Termination {
+        self.termination
+    }
+}
+
+/// A destination for the access records of a `Socks5Server`.
+///
+/// It is implemented for closures taking an `&AccessRecord`.
+pub trait AccessLog: Sync + Send {
+    /// Records a session which has ended.
+    fn log(&self, record: &AccessRecord);
+}
+
+impl<F> AccessLog for F
+    where F: Fn(&AccessRecord) + Sync + Send
+{
+    fn log(&self, record: &AccessRecord) {
+        self(record)
+    }
+}
+
+/// Receives the events of sessions served by a `Socks5Server`, for example
+/// to update metrics.
+///
+/// Every method has an empty default implementation, so implementations
+/// only need to handle the events they are interested in. Methods are called
+/// on the thread serving the session.
+pub trait SessionEvents: Sync + Send {
+    /// Called when a client's connection is accepted.
+    fn accepted(&self, _source: SocketAddr) {}
+
+    /// Called when a client has negotiated an authentication method and, if
+    /// required, its credentials have been accepted.
+    ///
+    /// `username` is the username the client authenticated with, if
+    /// password authentication is required.
+    fn authenticated(&self, _source: SocketAddr, _username: Option<&str>) {}
+
+    /// Called when a client's request has been read, before the ruleset is
+    /// checked.
+    fn target(&self, _request: &Request) {}
+
+    /// Called when a session ends.
+    ///
+    /// The record carries the number of bytes relayed in each direction and
+    /// the reason the session ended.
+    fn closed(&self, _record: &AccessRecord) {}
+}
+
+/// A handle used to shut down `Socks5Server`s gracefully.
+///
+/// Once `shutdown` is called, servers configured with the handle stop
+/// accepting connections, and `serve` returns after the sessions in progress
+/// have ended and the worker threads have been joined. Clones of a handle
+/// share their state.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<(Mutex<ShutdownState>, Condvar)>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    stopping: bool,
+    deadline: Option<Instant>,
+    forced: bool,
+    // the addresses of the listeners being served, and the number of workers
+    // accepting from each
+    listeners: Vec<(SocketAddr, usize)>,
+    // clones of the sockets of each session in progress, which are shut down
+    // if the drain deadline passes
+    sessions: HashMap<u64, Vec<TcpStream>>,
+    next_session: u64,
+}
+
+impl ShutdownHandle {
+    /// Creates a new handle.
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Stops servers using this handle from accepting connections.
+    ///
+    /// Sessions in progress are allowed to finish. If `drain_timeout` is
+    /// set, those still running once it has elapsed are closed. This returns
+    /// immediately; `serve` returns once the servers have stopped.
+    pub fn shutdown(&self, drain_timeout: Option<Duration>) {
+        let listeners = {
+            let mut state = self.inner.0.lock().unwrap();
+            if state.stopping {
+                return;
+            }
+            state.stopping = true;
+            state.deadline = drain_timeout.map(|timeout| Instant::now() + timeout);
+            self.inner.1.notify_all();
+            state.listeners.clone()
+        };
+
+        // wake up the workers blocked accepting connections
+        for (addr, workers) in listeners {
+            for _ in 0..workers {
+                let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+            }
+        }
+    }
+
+    /// Determines if `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.0.lock().unwrap().stopping
+    }
+
+    fn add_listener(&self, mut addr: SocketAddr, workers: usize) -> SocketAddr {
+        if addr.ip().is_unspecified() {
+            let loopback = match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            addr.set_ip(loopback);
+        }
+        self.inner.0.lock().unwrap().listeners.push((addr, workers));
+        addr
+    }
+
+    fn remove_listener(&self, addr: SocketAddr) {
+        let mut state = self.inner.0.lock().unwrap();
+        if let Some(i) = state.listeners.iter().position(|&(a, _)| a == addr) {
+            state.listeners.remove(i);
+        }
+    }
+
+    // Notifies the server waiting for a shutdown that a worker has exited.
+    fn notify(&self) {
+        let _state = self.inner.0.lock().unwrap();
+        self.inner.1.notify_all();
+    }
+
+    // Waits until a shutdown is requested or all `workers` have exited, and
+    // then until the sessions in progress have ended or the drain deadline
+    // has passed, in which case the remaining sessions are closed.
+    fn wait(&self, exited: &AtomicUsize, workers: usize) {
+        let mut state = self.inner.0.lock().unwrap();
+        while !state.stopping && exited.load(Ordering::SeqCst) < workers {
+            state = self.inner.1.wait(state).unwrap();
+        }
+
+        let deadline = match state.deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        loop {
+            let now = Instant::now();
+            if state.sessions.is_empty() || now >= deadline {
+                break;
+            }
+            state = self.inner.1.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        state.forced = true;
+        for socket in state.sessions.values().flatten() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    // Determines if the sessions in progress have ended, closing those still
+    // running if the drain deadline has passed.
+    #[cfg(feature = "tokio")]
+    fn drained(&self) -> bool {
+        let mut state = self.inner.0.lock().unwrap();
+        if state.sessions.is_empty() {
+            return true;
+        }
+        match state.deadline {
+            Some(deadline) if Instant::now() >= deadline => {}
+            _ => return false,
+        }
+        state.forced = true;
+        for socket in state.sessions.values().flatten() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        true
+    }
+
+    fn register(&self) -> Registration {
+        let mut state = self.inner.0.lock().unwrap();
+        let id = state.next_session;
+        state.next_session += 1;
+        state.sessions.insert(id, vec![]);
+        Registration {
+            handle: self.clone(),
+            id,
+        }
+    }
+}
+
+// A session registered with a `ShutdownHandle`, which is unregistered when
+// dropped.
+struct Registration {
+    handle: ShutdownHandle,
+    id: u64,
+}
+
+impl Registration {
+    // Adds a socket to close if the session outlives the drain deadline.
+    fn add(&self, socket: &TcpStream) -> io::Result<()> {
+        let socket = socket.try_clone()?;
+        let mut state = self.handle.inner.0.lock().unwrap();
+        if state.forced {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        if let Some(sockets) = state.sessions.get_mut(&self.id) {
+            sockets.push(socket);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.handle.inner.0.lock() {
+            state.sessions.remove(&self.id);
+            self.handle.inner.1.notify_all();
+        }
+    }
+}
+
+/// A multithreaded SOCKS5 server.
+///
+/// Each worker thread accepts connections from a shared listener and serves
+/// one session at a time, relaying data between the client and the
+/// connection opened by the handler until both sides have closed. Only the
+/// CONNECT command is supported; other commands are refused with
+/// `ReplyCode::CommandNotSupported`. Clients don't need to authenticate
+/// unless a password verifier is set with `password_auth`.
+///
+/// A panic while serving a connection only closes that connection, and the
+/// worker goes on to accept the next one.
+#[derive(Clone)]
+pub struct Socks5Server {
+    backlog: i32,
+    workers: usize,
+    mask_bound_addr: bool,
+    verifier: Option<Arc<PasswordVerifier>>,
+    ruleset: Option<Arc<Ruleset>>,
+    bandwidth: Option<Arc<BandwidthPolicy>>,
+    access_log: Option<Arc<dyn AccessLog>>,
+    handshake_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    events: Option<Arc<dyn SessionEvents>>,
+    shutdown: Option<ShutdownHandle>,
+    limiter: Option<ConnectionLimiter>,
+}
+
+type PasswordVerifier = dyn Fn(&str, &str) -> bool + Sync + Send;
+type Ruleset = dyn Fn(&Request) -> bool + Sync + Send;
+type BandwidthPolicy = dyn Fn(&Request) -> Option<u64> + Sync + Send;
+
+impl fmt::Debug for Socks5Server {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Socks5Server")
+            .field("backlog", &self.backlog)
+            .field("workers", &self.workers)
+            .field("mask_bound_addr", &self.mask_bound_addr)
+            .field("verifier", &self.verifier.is_some())
+            .field("ruleset", &self.ruleset.is_some())
+            .field("bandwidth", &self.bandwidth.is_some())
+            .field("access_log", &self.access_log.is_some())
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("events", &self.events.is_some())
+            .field("shutdown", &self.shutdown.is_some())
+            .field("limiter", &self.limiter)
+            .finish()
+    }
+}
+
+impl Default for Socks5Server {
+    fn default() -> Socks5Server {
+        Socks5Server::new()
+    }
+}
+
+impl Socks5Server {
+    /// Creates a new server configuration.
+    pub fn new() -> Socks5Server {
+        Socks5Server {
+            backlog: 128,
+            workers: 16,
+            mask_bound_addr: false,
+            verifier: None,
+            ruleset: None,
+            bandwidth: None,
+            access_log: None,
+            handshake_timeout: Some(Duration::from_secs(10)),
+            idle_timeout: None,
+            events: None,
+            shutdown: None,
+            limiter: None,
+        }
+    }
+
+    /// Sets the length of the queue of pending connections for listeners
+    /// created by `bind`.
+    ///
+    /// Defaults to 128.
+    pub fn backlog(&mut self, backlog: i32) -> &mut Socks5Server {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Sets the number of worker threads, which bounds the number of
+    /// sessions served at once.
+    ///
+    /// Defaults to 16.
+    pub fn workers(&mut self, workers: usize) -> &mut Socks5Server {
+        self.workers = cmp::max(workers, 1);
+        self
+    }
+
+    /// Determines if the bound address is masked in replies to CONNECT
+    /// requests.
+    ///
+    /// By default, replies carry the local address of the outbound
+    /// connection. See `Reply::mask`.
+    pub fn mask_bound_addr(&mut self, mask: bool) -> &mut Socks5Server {
+        self.mask_bound_addr = mask;
+        self
+    }
+
+    /// Requires clients to authenticate with a username and password
+    /// (RFC 1929), which are checked by `verify`.
+    ///
+    /// Clients which don't offer password authentication are rejected, as
+    /// are those whose credentials `verify` returns `false` for. See
+    /// `CommandVerifier` for a verifier which defers to an external program.
+    pub fn password_auth<F>(&mut self, verify: F) -> &mut Socks5Server
+        where F: Fn(&str, &str) -> bool + Sync + Send + 'static
+    {
+        self.verifier = Some(Arc::new(verify));
+        self
+    }
+
+    /// Sets the ruleset deciding which requests are allowed.
+    ///
+    /// Requests for which `allow` returns `false` are refused with
+    /// `ReplyCode::NotAllowed` before the handler is called. With password
+    /// authentication, `Request::username` can be used to apply per-user
+    /// rules such as destination allowlists.
+    pub fn ruleset<F>(&mut self, allow: F) -> &mut Socks5Server
+        where F: Fn(&Request) -> bool + Sync + Send + 'static
+    {
+        self.ruleset = Some(Arc::new(allow));
+        self
+    }
+
+    /// Sets the policy limiting the bandwidth of sessions.
+    ///
+    /// `limit` is called with each allowed request, and returns the maximum
+    /// number of bytes per second relayed in each direction for its session,
+    /// or `None` for no limit. With password authentication,
+    /// `Request::username` can be used to apply per-user limits.
+    pub fn bandwidth_limit<F>(&mut self, limit: F) -> &mut Socks5Server
+        where F: Fn(&Request) -> Option<u64> + Sync + Send + 'static
+    {
+        self.bandwidth = Some(Arc::new(limit));
+        self
+    }
+
+    /// Sets the access log, which is passed a record of each session when it
+    /// ends.
+    pub fn access_log<L>(&mut self, log: L) -> &mut Socks5Server
+        where L: AccessLog + 'static
+    {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Sets the receiver of session events.
+    pub fn session_events<E>(&mut self, events: E) -> &mut Socks5Server
+        where E: SessionEvents + 'static
+    {
+        self.events = Some(Arc::new(events));
+        self
+    }
+
+    /// Sets the handle used to shut down the server.
+    ///
+    /// See `ShutdownHandle`.
+    pub fn shutdown_handle(&mut self, handle: ShutdownHandle) -> &mut Socks5Server {
+        self.shutdown = Some(handle);
+        self
+    }
+
+    /// Sets the limits on the sessions accepted from clients.
+    ///
+    /// A permit is acquired from `limiter` for each connection, keyed by the
+    /// client's IP address, and held until its session ends. Clients over a
+    /// limit are refused with `ReplyCode::NotAllowed` once their request has
+    /// been read.
+    pub fn connection_limiter(&mut self, limiter: ConnectionLimiter) -> &mut Socks5Server {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Sets the timeout for each read and write while negotiating with a
+    /// client, up to and including the reply to its request.
+    ///
+    /// This keeps clients which connect but never complete the handshake
+    /// from holding on to a worker. Sessions whose handshake times out end
+    /// with `Termination::Error`. Defaults to 10 seconds.
+    pub fn handshake_timeout(&mut self, timeout: Option<Duration>) -> &mut Socks5Server {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Sets the idle timeout of established sessions.
+    ///
+    /// A session is closed once neither the client nor the target has sent
+    /// any data for `timeout`, freeing its worker for other clients. Such
+    /// sessions end with `Termination::IdleTimeout`.
+    ///
+    /// Defaults to `None`, which keeps sessions open until both sides close
+    /// their connections.
+    pub fn idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Socks5Server {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Creates a listener bound to the specified address with the
+    /// configured backlog.
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpListener> {
+        let mut last = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_addr(addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(last.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+        }))
+    }
+
+    fn bind_addr(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+        Ok(socket.into())
+    }
+
+    /// Serves clients connecting to `listener`.
+    ///
+    /// `handler` is called with each request to open the outbound
+    /// connection, and returns the reply to send to the client if it can't.
+    /// This only returns if accepting connections fails, or once the server
+    /// has been shut down with its `ShutdownHandle`.
+    pub fn serve<F>(&self, listener: TcpListener, handler: F) -> io::Result<()>
+        where F: Fn(&Request) -> Result<TcpStream, Reply> + Sync
+    {
+        let wake_addr = match self.shutdown {
+            Some(ref shutdown) => {
+                Some(shutdown.add_listener(listener.local_addr()?, self.workers))
+            }
+            None => None,
+        };
+        let exited = AtomicUsize::new(0);
+
+        let result = thread::scope(|scope| {
+            let mut workers = vec![];
+            for _ in 0..self.workers {
+                let worker = thread::Builder::new()
+                    .name("socks-server".to_owned())
+                    .spawn_scoped(scope, || {
+                        let r = self.accept_loop(&listener, &handler);
+                        exited.fetch_add(1, Ordering::SeqCst);
+                        if let Some(ref shutdown) = self.shutdown {
+                            shutdown.notify();
+                        }
+                        r
+                    })?;
+                workers.push(worker);
+            }
+
+            if let Some(ref shutdown) = self.shutdown {
+                shutdown.wait(&exited, workers.len());
+            }
+
+            let mut result = Ok(());
+            for worker in workers {
+                let r = worker.join()
+                    .unwrap_or_else(|_| Err(io::Error::other("server thread panicked")));
+                result = result.and(r);
+            }
+            result
+        });
+
+        if let (Some(ref shutdown), Some(addr)) = (&self.shutdown, wake_addr) {
+            shutdown.remove_listener(addr);
+        }
+        result
+    }
+
+    /// Serves clients connecting to `listener`, opening outbound
+    /// connections with `outbound`.
+    ///
+    /// ```no_run
+    /// use std::io;
+    /// use std::net::TcpStream;
+    /// use socks::TargetAddr;
+    /// use socks::server::Socks5Server;
+    ///
+    /// let config = Socks5Server::new();
+    /// let listener = config.bind("127.0.0.1:1080").unwrap();
+    /// config.serve_outbound(listener, |target: &TargetAddr| -> io::Result<TcpStream> {
+    ///     match *target {
+    ///         TargetAddr::Ip(addr) if addr.ip().is_loopback() => {
+    ///             Err(io::Error::new(io::ErrorKind::PermissionDenied, "loopback target"))
+    ///         }
+    ///         _ => TcpStream::connect(target),
+    ///     }
+    /// }).unwrap();
+    /// ```
+    ///
+    /// This only returns if accepting connections fails.
+    pub fn serve_outbound<O>(&self, listener: TcpListener, outbound: O) -> io::Result<()>
+        where O: Outbound
+    {
+        self.serve(listener, |request: &Request| {
+            outbound.connect(request.target_addr()).map_err(|e| failure_reply(&e))
+        })
+    }
+
+    fn accept_loop<F>(&self, listener: &TcpListener, handler: &F) -> io::Result<()>
+        where F: Fn(&Request) -> Result<TcpStream, Reply>
+    {
+        let stopping = || self.shutdown.as_ref().is_some_and(ShutdownHandle::is_shutdown);
+        loop {
+            if stopping() {
+                return Ok(());
+            }
+            let (stream, source) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(ref e) if is_transient(e) => continue,
+                Err(e) => return Err(e),
+            };
+            // the connection may have been made to wake this worker up
+            if stopping() {
+                return Ok(());
+            }
+            self.session(stream, source, handler);
+        }
+    }
+
+    fn session<F>(&self, stream: TcpStream, source: SocketAddr, handler: &F)
+        where F: Fn(&Request) -> Result<TcpStream, Reply>
+    {
+        let start = Instant::now();
+        let mut record = AccessRecord {
+            timestamp: SystemTime::now(),
+            duration: Duration::from_secs(0),
+            source,
+            username: None,
+            target: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            termination: Termination::Closed,
+        };
+        if let Some(ref events) = self.events {
+            events.accepted(source);
+        }
+        let registration = self.shutdown.as_ref().map(ShutdownHandle::register);
+        // the permit is held until the session ends
+        let (permit, refusal) = match self.limiter.as_ref().map(|l| l.acquire(source.ip())) {
+            Some(Ok(permit)) => (Some(permit), None),
+            Some(Err(reply)) => (None, Some(reply)),
+            None => (None, None),
+        };
+
+        // errors and panics only affect the session they occur in
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.relay_session(stream, handler, &mut record, registration.as_ref(), refusal)
+        }));
+        drop(registration);
+        drop(permit);
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => record.termination = Termination::Error(e.kind()),
+            Err(_) => record.termination = Termination::Panicked,
+        }
+
+        record.duration = start.elapsed();
+        if let Some(ref events) = self.events {
+            events.closed(&record);
+        }
+        if let Some(ref log) = self.access_log {
+            log.log(&record);
+        }
+    }
+
+    fn relay_session<F>(&self,
+                        mut stream: TcpStream,
+                        handler: &F,
+                        record: &mut AccessRecord,
+                        registration: Option<&Registration>,
+                        refusal: Option<Reply>)
+                        -> io::Result<()>
+        where F: Fn(&Request) -> Result<TcpStream, Reply>
+    {
+        if let Some(registration) = registration {
+            registration.add(&stream)?;
+        }
+        stream.set_read_timeout(self.handshake_timeout)?;
+        stream.set_write_timeout(self.handshake_timeout)?;
+        let request = match self.read_request(&mut stream, record)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if let Some(ref events) = self.events {
+            events.target(&request);
+        }
+        if let Some(reply) = refusal {
+            return refuse(&mut stream, reply, record);
+        }
+        if let Some(ref allow) = self.ruleset {
+            if !allow(&request) {
+                return refuse(&mut stream, Reply::new(ReplyCode::NotAllowed), record);
+            }
+        }
+        let rate = self.bandwidth.as_ref().and_then(|limit| limit(&request));
+
+        let outbound = match handler(&request) {
+            Ok(outbound) => outbound,
+            Err(reply) => return refuse(&mut stream, reply, record),
+        };
+        if let Some(registration) = registration {
+            registration.add(&outbound)?;
+        }
+        let mut reply = Reply::connected(&outbound)?;
+        if self.mask_bound_addr {
+            reply = reply.mask();
+        }
+        reply.write_to(&mut stream)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        let mut copied = (0, 0);
+        let result = relay_sockets(&stream, &outbound, rate, self.idle_timeout, &mut copied);
+        record.bytes_sent = copied.0;
+        record.bytes_received = copied.1;
+        match result {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                record.termination = Termination::IdleTimeout;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Negotiates an authentication method and reads the client's request. If the
+    // request can't be served, the client is sent a failure reply and `None` is
+    // returned.
+    fn read_request(&self,
+                    stream: &mut TcpStream,
+                    record: &mut AccessRecord)
+                    -> io::Result<Option<Request>> {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header)?;
+        if header[0] != 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid greeting version"));
+        }
+        let mut methods = vec![0; header[1] as usize];
+        stream.read_exact(&mut methods)?;
+        let method = match self.verifier {
+            Some(_) => AuthMethod::Password,
+            None => AuthMethod::None,
+        };
+        if !methods.contains(&method.into()) {
+            stream.write_all(&[5, 0xff])?; // no acceptable methods
+            record.termination = Termination::AuthFailed;
+            return Ok(None);
+        }
+        stream.write_all(&[5, method.into()])?;
+
+        if let Some(ref verify) = self.verifier {
+            let (username, password) = read_password_request(stream)?;
+            let verified = verify(&username, &password);
+            record.username = Some(username);
+            write_password_response(stream, verified)?;
+            if !verified {
+                record.termination = Termination::AuthFailed;
+                return Ok(None);
+            }
+        }
+        if let Some(ref events) = self.events {
+            events.authenticated(record.source, record.username.as_deref());
+        }
+
+        let mut header = [0; 4];
+        stream.read_exact(&mut header)?;
+        if header[0] != 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid request version"));
+        }
+        if let AddressType::Other(_) = AddressType::from(header[3]) {
+            refuse(stream, Reply::new(ReplyCode::AddressTypeNotSupported), record)?;
+            return Ok(None);
+        }
+        let target = read_addr(&mut (&header[3..]).chain(&mut *stream))?;
+        record.target = Some(target.clone());
+
+        let command = Command::from(header[1]);
+        if command != Command::Connect {
+            refuse(stream, Reply::new(ReplyCode::CommandNotSupported), record)?;
+            return Ok(None);
+        }
+        Ok(Some(Request {
+            command,
+            target,
+            source: record.source,
+            username: record.username.clone(),
+        }))
+    }
+}
+
+/// A password verifier which runs an external program.
+///
+/// The username and password are written to the program's standard input,
+/// each followed by a newline, and the credentials are accepted if it exits
+/// successfully. This lets a small server reuse system accounts or another
+/// existing credential store without custom code. Credentials containing a
+/// newline are rejected without running the program.
+#[derive(Debug, Clone)]
+pub struct CommandVerifier {
+    program: OsString,
+    args: Vec<OsString>,
+    timeout: Duration,
+}
+
+impl CommandVerifier {
+    /// Creates a verifier which runs `program`.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> CommandVerifier {
+        CommandVerifier {
+            program: program.as_ref().to_owned(),
+            args: vec![],
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Adds an argument to pass to the program.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut CommandVerifier {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Sets how long the program may run before it is killed.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut CommandVerifier {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs the program to check a username and password.
+    ///
+    /// Returns a `TimedOut` error if the program is killed for running past
+    /// the timeout.
+    pub fn verify(&self, username: &str, password: &str) -> io::Result<bool> {
+        if username.contains('\n') || password.contains('\n') {
+            return Ok(false);
+        }
+
+        let mut child = process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // the program may exit without reading its input
+            let _ = write!(stdin, "{}\n{}\n", username, password);
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success());
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                child.wait()?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "password verifier timed out"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// Sends a failure reply to the client, recording it as the reason the
+// session ended.
+fn refuse(stream: &mut TcpStream, reply: Reply, record: &mut AccessRecord) -> io::Result<()> {
+    record.termination = Termination::Refused(reply.code());
+    reply.write_to(stream)
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(),
+             io::ErrorKind::ConnectionAborted |
+             io::ErrorKind::ConnectionReset |
+             io::ErrorKind::Interrupted)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn failure() {
+        let reply = Reply::new(ReplyCode::HostUnreachable);
+        assert_eq!(reply.to_bytes().unwrap(), [5, 4, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bound_addrs() {
+        let reply = Reply::succeeded(TargetAddr::Ip("10.0.0.1:1080".parse().unwrap()));
+        assert_eq!(reply.to_bytes().unwrap(), [5, 0, 0, 1, 10, 0, 0, 1, 4, 56]);
+
+        let reply = Reply::succeeded(TargetAddr::Ip("[::1]:1080".parse().unwrap()));
+        assert_eq!(reply.to_bytes().unwrap(),
+                   [5, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4, 56]);
+
+        let reply = Reply::succeeded(TargetAddr::Domain("proxy.example".to_owned(), 1080));
+        let mut expected = vec![5, 0, 0, 3, 13];
+        expected.extend_from_slice(b"proxy.example");
+        expected.extend_from_slice(&[4, 56]);
+        assert_eq!(reply.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn connect_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let outbound = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let local = outbound.local_addr().unwrap();
+
+        let reply = Reply::connected(&outbound).unwrap();
+        assert_eq!(*reply.get_bound_addr(), TargetAddr::Ip(local));
+        let port = local.port();
+        assert_eq!(reply.to_bytes().unwrap(),
+                   [5, 0, 0, 1, 127, 0, 0, 1, (port >> 8) as u8, port as u8]);
+
+        let reply = reply.mask();
+        assert_eq!(reply.to_bytes().unwrap(), [5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    fn reply_code(err: io::Error) -> ReplyCode {
+        let err = err.into_inner().unwrap().downcast::<::ConnectError>().unwrap();
+        match err.get_ref().get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::Socks5Reply { code }) => code,
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    // Starts a server relaying to its targets directly.
+    fn server(config: Socks5Server) -> SocketAddr {
+        let listener = config.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || config.serve(listener, connect_direct));
+        addr
+    }
+
+    #[test]
+    fn serve() {
+        let proxy = server(Socks5Server::new());
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let mut socket = target.accept().unwrap().0;
+            let mut buf = vec![];
+            socket.read_to_end(&mut buf).unwrap();
+            socket.write_all(&buf).unwrap();
+        });
+
+        let mut stream = ::Socks5Stream::connect(proxy, target_addr).unwrap();
+        assert_eq!(stream.proxy_addr().address_type(), AddressType::Ipv4);
+        stream.write_all(b"hello").unwrap();
+        stream.get_ref().shutdown(Shutdown::Write).unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        echo.join().unwrap();
+
+        let err = ::Socks5Listener::bind(proxy, target_addr).unwrap_err();
+        assert_eq!(reply_code(err), ReplyCode::CommandNotSupported);
+    }
+
+    #[test]
+    fn serve_masked() {
+        let mut config = Socks5Server::new();
+        config.mask_bound_addr(true);
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let stream = ::Socks5Stream::connect(proxy, target.local_addr().unwrap()).unwrap();
+        assert_eq!(*stream.proxy_addr(), TargetAddr::Ip("0.0.0.0:0".parse().unwrap()));
+
+        let err = ::Socks5Stream::connect(proxy, "127.0.0.1:1").unwrap_err();
+        assert_eq!(reply_code(err), ReplyCode::ConnectionRefused);
+    }
+
+    #[test]
+    fn serve_password_auth() {
+        let mut config = Socks5Server::new();
+        config.password_auth(|username, password| username == "user" && password == "pass");
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "pass").unwrap();
+
+        let err = ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "wrong")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let err = ::Socks5Stream::connect(proxy, target_addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn serve_upstream() {
+        let upstream = server(Socks5Server::new());
+        let connector = SocksConnector::new(::Socks5Builder::new(), upstream).unwrap();
+        let config = Socks5Server::new();
+        let listener = config.bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            config.serve(listener, |request| connect_upstream(&connector, request))
+        });
+
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = ::Socks5Stream::connect(proxy, target.local_addr().unwrap()).unwrap();
+        let mut socket = target.accept().unwrap().0;
+        socket.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let err = ::Socks5Stream::connect(proxy, "127.0.0.1:1").unwrap_err();
+        assert_eq!(reply_code(err), ReplyCode::ConnectionRefused);
+    }
+
+    #[test]
+    fn serve_outbound() {
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let config = Socks5Server::new();
+        let listener = config.bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        // all requests for the virtual host are sent to the target
+        thread::spawn(move || {
+            config.serve_outbound(listener, move |addr: &TargetAddr| -> io::Result<TcpStream> {
+                match *addr {
+                    TargetAddr::Domain(ref domain, _) if domain == "virtual.test" => {
+                        TcpStream::connect(target_addr)
+                    }
+                    _ => Err(io::Error::new(io::ErrorKind::NotFound, "unknown host")),
+                }
+            })
+        });
+
+        let mut stream = ::Socks5Stream::connect(proxy, "virtual.test:80").unwrap();
+        let mut socket = target.accept().unwrap().0;
+        socket.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let err = ::Socks5Stream::connect(proxy, "other.test:80").unwrap_err();
+        assert_eq!(reply_code(err), ReplyCode::HostUnreachable);
+    }
+
+    #[test]
+    fn per_user_policies() {
+        let mut config = Socks5Server::new();
+        config.password_auth(|_, password| password == "pass")
+            .ruleset(|request| request.username() != Some("blocked"))
+            .bandwidth_limit(|request| {
+                match request.username() {
+                    Some("slow") => Some(16 * 1024),
+                    _ => None,
+                }
+            });
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let err = ::Socks5Stream::connect_with_password(proxy, target_addr, "blocked", "pass")
+            .unwrap_err();
+        assert_eq!(reply_code(err), ReplyCode::NotAllowed);
+
+        let mut stream = ::Socks5Stream::connect_with_password(proxy, target_addr, "slow", "pass")
+            .unwrap();
+        let mut socket = target.accept().unwrap().0;
+        let start = Instant::now();
+        socket.write_all(&[0; 8 * 1024]).unwrap();
+        stream.read_exact(&mut [0; 8 * 1024]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_verifier() {
+        let mut verifier = CommandVerifier::new("sh");
+        verifier.arg("-c").arg(r#"read u; read p; [ "$u" = user ] && [ "$p" = pass ]"#);
+        assert!(verifier.verify("user", "pass").unwrap());
+        assert!(!verifier.verify("user", "wrong").unwrap());
+        assert!(!verifier.verify("user", "pass\nuser").unwrap());
+
+        assert!(CommandVerifier::new("/nonexistent").verify("user", "pass").is_err());
+
+        let mut verifier = CommandVerifier::new("sleep");
+        verifier.arg("10").timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        let err = verifier.verify("user", "pass").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn password_requests() {
+        let mut request = &b"\x01\x04user\x04pass"[..];
+        let (username, password) = read_password_request(&mut request).unwrap();
+        assert_eq!((&*username, &*password), ("user", "pass"));
+
+        let err = read_password_request(&mut &b"\x01\x00\x04pass"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::InvalidUsername { len: 0 }) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let err = read_password_request(&mut &b"\x01\x04user\x05pass"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut response = vec![];
+        write_password_response(&mut response, false).unwrap();
+        assert_eq!(response, [1, 1]);
+    }
+
+    #[test]
+    fn max_sessions() {
+        let limiter = ConnectionLimiter::new().max_sessions(2);
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let a = limiter.acquire(ip).unwrap();
+        let _b = limiter.acquire(ip).unwrap();
+        let reply = limiter.acquire(ip).unwrap_err();
+        assert_eq!(reply.code(), ReplyCode::NotAllowed);
+
+        drop(a);
+        assert_eq!(limiter.sessions(), 1);
+        limiter.acquire(ip).unwrap();
+    }
+
+    #[test]
+    fn per_ip_rate() {
+        let limiter = ConnectionLimiter::new().per_ip_rate(2, Duration::from_secs(60));
+        let a = "10.0.0.1".parse().unwrap();
+        let b = "10.0.0.2".parse().unwrap();
+
+        limiter.acquire(a).unwrap();
+        limiter.acquire(a).unwrap();
+        assert!(limiter.acquire(a).is_err());
+        limiter.acquire(b).unwrap();
+    }
+
+    #[test]
+    fn codes() {
+        for code in 0..=255u8 {
+            assert_eq!(u8::from(ReplyCode::from(code)), code);
+        }
+    }
+
+    #[test]
+    fn access_log() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut config = Socks5Server::new();
+        config.password_auth(|_, password| password == "pass")
+            .access_log(move |record: &AccessRecord| {
+                tx.lock().unwrap().send(record.clone()).unwrap();
+            });
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "wrong").unwrap_err();
+        let record = rx.recv().unwrap();
+        assert_eq!(record.termination(), Termination::AuthFailed);
+        assert_eq!(record.username(), Some("user"));
+        assert_eq!(record.target_addr(), None);
+
+        let mut stream = ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "pass")
+            .unwrap();
+        let mut socket = target.accept().unwrap().0;
+        stream.write_all(b"hello").unwrap();
+        stream.get_ref().shutdown(Shutdown::Write).unwrap();
+        socket.read_exact(&mut [0; 5]).unwrap();
+        socket.write_all(b"hi").unwrap();
+        drop(socket);
+        stream.read_to_end(&mut vec![]).unwrap();
+
+        let record = rx.recv().unwrap();
+        assert_eq!(record.termination(), Termination::Closed);
+        assert_eq!(record.source_addr(), stream.get_ref().local_addr().unwrap());
+        assert_eq!(record.target_addr(), Some(&TargetAddr::Ip(target_addr)));
+        assert_eq!((record.bytes_sent(), record.bytes_received()), (5, 2));
+    }
+
+    #[test]
+    fn session_events() {
+        struct Events(Mutex<Vec<String>>);
+
+        impl SessionEvents for Arc<Events> {
+            fn accepted(&self, _: SocketAddr) {
+                self.0.lock().unwrap().push("accepted".to_owned());
+            }
+
+            fn authenticated(&self, _: SocketAddr, username: Option<&str>) {
+                self.0.lock().unwrap().push(format!("authenticated {:?}", username));
+            }
+
+            fn target(&self, request: &Request) {
+                self.0.lock().unwrap().push(format!("target {:?}", request.target_addr()));
+            }
+
+            fn closed(&self, record: &AccessRecord) {
+                let event = format!("closed {:?} {} {}",
+                                    record.termination(),
+                                    record.bytes_sent(),
+                                    record.bytes_received());
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let events = Arc::new(Events(Mutex::new(vec![])));
+        let mut config = Socks5Server::new();
+        config.password_auth(|_, password| password == "pass")
+            .session_events(events.clone());
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let mut stream = ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "pass")
+            .unwrap();
+        let mut socket = target.accept().unwrap().0;
+        stream.write_all(b"hello").unwrap();
+        socket.read_exact(&mut [0; 5]).unwrap();
+        drop(socket);
+        drop(stream);
+
+        for _ in 0..100 {
+            if events.0.lock().unwrap().len() == 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*events.0.lock().unwrap(),
+                   ["accepted".to_owned(),
+                    "authenticated Some(\"user\")".to_owned(),
+                    format!("target {:?}", TargetAddr::Ip(target_addr)),
+                    "closed Closed 5 0".to_owned()]);
+    }
+
+    #[test]
+    fn shutdown() {
+        let handle = ShutdownHandle::new();
+        let mut config = Socks5Server::new();
+        config.workers(2).shutdown_handle(handle.clone());
+        let listener = config.bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || config.serve(listener, connect_direct));
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut stream = ::Socks5Stream::connect(proxy, target.local_addr().unwrap()).unwrap();
+        let mut socket = target.accept().unwrap().0;
+
+        let start = Instant::now();
+        handle.shutdown(Some(Duration::from_millis(200)));
+        // the session is drained until the deadline
+        stream.write_all(b"hello").unwrap();
+        socket.read_exact(&mut [0; 5]).unwrap();
+
+        server.join().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert!(::Socks5Stream::connect(proxy, target.local_addr().unwrap()).is_err());
+    }
+
+    #[test]
+    fn shutdown_idle() {
+        let handle = ShutdownHandle::new();
+        let mut config = Socks5Server::new();
+        config.shutdown_handle(handle.clone());
+        let listener = config.bind("0.0.0.0:0").unwrap();
+        let server = thread::spawn(move || config.serve(listener, connect_direct));
+
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown(None);
+        server.join().unwrap().unwrap();
+        assert!(handle.is_shutdown());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut config = Socks5Server::new();
+        config.idle_timeout(Some(Duration::from_millis(200)))
+            .access_log(move |record: &AccessRecord| {
+                tx.lock().unwrap().send(record.clone()).unwrap();
+            });
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut stream = ::Socks5Stream::connect(proxy, target.local_addr().unwrap()).unwrap();
+        let mut socket = target.accept().unwrap().0;
+        stream.write_all(b"ping").unwrap();
+        socket.read_exact(&mut [0; 4]).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+        let record = rx.recv().unwrap();
+        assert_eq!(record.termination(), Termination::IdleTimeout);
+        assert_eq!((record.bytes_sent(), record.bytes_received()), (4, 0));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut config = Socks5Server::new();
+        config.workers(1)
+            .handshake_timeout(Some(Duration::from_millis(100)))
+            .access_log(move |record: &AccessRecord| {
+                tx.lock().unwrap().send(record.clone()).unwrap();
+            });
+        let proxy = server(config);
+
+        // a client which never sends its greeting doesn't hold the worker
+        let mut idle = TcpStream::connect(proxy).unwrap();
+        let record = rx.recv().unwrap();
+        match record.termination() {
+            Termination::Error(io::ErrorKind::WouldBlock) |
+            Termination::Error(io::ErrorKind::TimedOut) => {}
+            termination => panic!("unexpected termination {:?}", termination),
+        }
+        assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _stream = ::Socks5Stream::connect(proxy, target.local_addr().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn connection_limiter() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let limiter = ConnectionLimiter::new().max_sessions(1);
+        let mut config = Socks5Server::new();
+        config.connection_limiter(limiter.clone())
+            .access_log(move |record: &AccessRecord| {
+                tx.lock().unwrap().send(record.clone()).unwrap();
+            });
+        let proxy = server(config);
+        let target = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let stream = ::Socks5Stream::connect(proxy, target_addr).unwrap();
+        let socket = target.accept().unwrap().0;
+        assert_eq!(limiter.sessions(), 1);
+
+        // a second client is refused after its request, per the protocol
+        let err = ::Socks5Stream::connect(proxy, target_addr).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<::ConnectError>().unwrap();
+        assert_eq!(err.kind(), ::ConnectErrorKind::NotAllowed);
+        let record = rx.recv().unwrap();
+        assert_eq!(record.termination(), Termination::Refused(ReplyCode::NotAllowed));
+
+        // the permit is held until the first session ends
+        drop(stream);
+        drop(socket);
+        rx.recv().unwrap();
+        assert_eq!(limiter.sessions(), 0);
+        let _stream = ::Socks5Stream::connect(proxy, target_addr).unwrap();
+    }
+}
@@ -0,0 +1,783 @@
+use socket2::SockRef;
+use std::cmp;
+use std::future::Future;
+use std::io::{self, Read};
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Sleep, Timeout};
+
+use {AddressType, AuthMethod, Command, TargetAddr};
+use server::{is_transient, read_password_request, AccessRecord, Registration, Reply, ReplyCode,
+             Request, SessionPermit, ShutdownHandle, Socks5Server, Termination, CONNECT_TIMEOUT};
+use v5::read_addr;
+
+type Connecting = Pin<Box<dyn Future<Output = Result<TcpStream, Reply>> + Send>>;
+type Connect = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+type Handler = dyn Fn(&Request) -> Connecting + Send + Sync;
+
+// How often a server which has been shut down checks if its sessions have
+// ended.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+// The size of the buffer used for each direction of a relay.
+const RELAY_BUF_LEN: usize = 16 * 1024;
+
+impl Socks5Server {
+    /// Serves clients connecting to `listener` on a tokio runtime.
+    ///
+    /// This is the asynchronous counterpart of `serve`. Each session is
+    /// served by its own task rather than a worker thread, so the number of
+    /// sessions served at once is not bounded by `workers`; use
+    /// `connection_limiter` to bound it instead. `handler` is called with
+    /// each request, and returns a future resolving to the outbound
+    /// connection or to the reply to send to the client if it can't be
+    /// opened. See `connect_direct_tokio`.
+    ///
+    /// The other settings apply as they do to `serve`. The password
+    /// verifier, ruleset and other callbacks are called on the runtime's
+    /// threads, so they should not block.
+    ///
+    /// The returned future must be polled on a tokio runtime with the time
+    /// driver enabled. It only completes if accepting connections fails, or
+    /// once the server has been shut down with its `ShutdownHandle` and the
+    /// sessions in progress have ended.
+    ///
+    /// Requires the `tokio` Cargo feature.
+    pub fn serve_tokio<F, R>(&self,
+                             listener: TcpListener,
+                             handler: F)
+                             -> impl Future<Output = io::Result<()>> + Send
+        where F: Fn(&Request) -> R + Send + Sync + 'static,
+              R: Future<Output = Result<TcpStream, Reply>> + Send + 'static
+    {
+        let handler: Arc<Handler> = Arc::new(move |request: &Request| -> Connecting {
+            Box::pin(handler(request))
+        });
+        Serve {
+            server: Arc::new(self.clone()),
+            listener,
+            handler,
+            wake_addr: None,
+            draining: None,
+        }
+    }
+}
+
+/// Opens the outbound connection for a request directly to its target on a
+/// tokio runtime.
+///
+/// This is the counterpart of `connect_direct` for
+/// `Socks5Server::serve_tokio`. Domain names are resolved locally, and each
+/// address is tried in turn for up to 10 seconds in total. Errors are
+/// reported to the client with the reply code returned by
+/// `ReplyCode::from_io_error`.
+///
+/// Requires the `tokio` Cargo feature.
+pub fn connect_direct_tokio(request: &Request)
+                            -> impl Future<Output = Result<TcpStream, Reply>> + Send + 'static {
+    let connect: Connect = match *request.target_addr() {
+        TargetAddr::Ip(addr) => Box::pin(TcpStream::connect(addr)),
+        TargetAddr::Domain(ref domain, port) => {
+            Box::pin(TcpStream::connect((domain.clone(), port)))
+        }
+    };
+    ConnectDirect(Box::pin(time::timeout(CONNECT_TIMEOUT, connect)))
+}
+
+struct ConnectDirect(Pin<Box<Timeout<Connect>>>);
+
+impl Future for ConnectDirect {
+    type Output = Result<TcpStream, Reply>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<TcpStream, Reply>> {
+        let result = match ready!(self.0.as_mut().poll(cx)) {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")),
+        };
+        Poll::Ready(result.map_err(|e| Reply::new(ReplyCode::from_io_error(&e))))
+    }
+}
+
+// The future returned by `Socks5Server::serve_tokio`, which accepts
+// connections and spawns a task serving each of them.
+struct Serve {
+    server: Arc<Socks5Server>,
+    listener: TcpListener,
+    handler: Arc<Handler>,
+    // the address the listener is registered under with the shutdown handle
+    wake_addr: Option<SocketAddr>,
+    // set once the server has been shut down, to wait for its sessions
+    draining: Option<Pin<Box<Sleep>>>,
+}
+
+impl Future for Serve {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let result = ready!(self.poll_accept(cx));
+        if let (Some(ref shutdown), Some(addr)) = (&self.server.shutdown, self.wake_addr) {
+            shutdown.remove_listener(addr);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl Serve {
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if let (Some(ref shutdown), None) = (&self.server.shutdown, self.wake_addr) {
+            self.wake_addr = Some(shutdown.add_listener(self.listener.local_addr()?, 1));
+        }
+        loop {
+            if let Some(ref mut draining) = self.draining {
+                if self.server.shutdown.as_ref().is_none_or(ShutdownHandle::drained) {
+                    return Poll::Ready(Ok(()));
+                }
+                ready!(draining.as_mut().poll(cx));
+                draining.as_mut().reset(time::Instant::now() + DRAIN_INTERVAL);
+                continue;
+            }
+            if self.stopping() {
+                self.draining = Some(Box::pin(time::sleep(DRAIN_INTERVAL)));
+                continue;
+            }
+            let (stream, source) = match ready!(self.listener.poll_accept(cx)) {
+                Ok(conn) => conn,
+                Err(ref e) if is_transient(e) => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            // the connection may have been made to wake this server up
+            if self.stopping() {
+                continue;
+            }
+            tokio::spawn(Session::new(self.server.clone(), self.handler.clone(), stream, source));
+        }
+    }
+
+    fn stopping(&self) -> bool {
+        self.server.shutdown.as_ref().is_some_and(ShutdownHandle::is_shutdown)
+    }
+}
+
+enum State {
+    Accepted,
+    Greeting,
+    Password,
+    Request,
+    // writing `Session::output` to the client, and then moving on to the
+    // next state
+    Writing(Box<State>),
+    Connecting(Connecting, Request),
+    Relaying(Relay),
+    Done,
+}
+
+// A task serving a client's connection, following the same steps as
+// `Socks5Server::relay_session`.
+struct Session {
+    server: Arc<Socks5Server>,
+    handler: Arc<Handler>,
+    stream: TcpStream,
+    start: Instant,
+    record: AccessRecord,
+    registration: Option<Registration>,
+    permit: Option<SessionPermit>,
+    refusal: Option<Reply>,
+    state: State,
+    // data read from the client which hasn't been parsed yet
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    // the handshake timeout of the pending read or write
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Future for Session {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let session = &mut *self;
+        // errors and panics only affect the session they occur in
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| session.poll_session(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(result)) => Ok(result),
+            Err(e) => Err(e),
+        };
+        session.state = State::Done;
+        session.registration = None;
+        session.permit = None;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => session.record.termination = Termination::Error(e.kind()),
+            Err(_) => session.record.termination = Termination::Panicked,
+        }
+
+        session.record.duration = session.start.elapsed();
+        if let Some(ref events) = session.server.events {
+            events.closed(&session.record);
+        }
+        if let Some(ref log) = session.server.access_log {
+            log.log(&session.record);
+        }
+        Poll::Ready(())
+    }
+}
+
+impl Session {
+    fn new(server: Arc<Socks5Server>,
+           handler: Arc<Handler>,
+           stream: TcpStream,
+           source: SocketAddr)
+           -> Session {
+        let record = AccessRecord {
+            timestamp: SystemTime::now(),
+            duration: Duration::from_secs(0),
+            source,
+            username: None,
+            target: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            termination: Termination::Closed,
+        };
+        if let Some(ref events) = server.events {
+            events.accepted(source);
+        }
+        let registration = server.shutdown.as_ref().map(ShutdownHandle::register);
+        // the permit is held until the session ends
+        let (permit, refusal) = match server.limiter.as_ref().map(|l| l.acquire(source.ip())) {
+            Some(Ok(permit)) => (Some(permit), None),
+            Some(Err(reply)) => (None, Some(reply)),
+            None => (None, None),
+        };
+
+        let mut session = Session {
+            server,
+            handler,
+            stream,
+            start: Instant::now(),
+            record,
+            registration,
+            permit,
+            refusal,
+            state: State::Accepted,
+            input: vec![],
+            output: vec![],
+            written: 0,
+            timer: None,
+        };
+        session.arm();
+        session
+    }
+
+    fn poll_session(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            let next = match self.state {
+                State::Accepted => {
+                    if let Some(ref registration) = self.registration {
+                        register(registration, &self.stream)?;
+                    }
+                    State::Greeting
+                }
+                State::Greeting => {
+                    let methods = ready!(self.poll_read_message(cx, read_greeting))?;
+                    self.select_method(&methods)
+                }
+                State::Password => {
+                    let (username, password) =
+                        ready!(self.poll_read_message(cx, |r| read_password_request(r)))?;
+                    self.verify(username, &password)
+                }
+                State::Request => {
+                    let request = ready!(self.poll_read_message(cx, read_request))?;
+                    self.check_request(request)?
+                }
+                State::Writing(_) => {
+                    ready!(self.poll_write(cx))?;
+                    self.arm();
+                    match mem::replace(&mut self.state, State::Done) {
+                        State::Writing(next) => *next,
+                        _ => unreachable!(),
+                    }
+                }
+                State::Connecting(ref mut connecting, _) => {
+                    let outbound = ready!(connecting.as_mut().poll(cx));
+                    match mem::replace(&mut self.state, State::Done) {
+                        State::Connecting(_, request) => self.connected(outbound, &request)?,
+                        _ => unreachable!(),
+                    }
+                }
+                State::Relaying(ref mut relay) => {
+                    let result = ready!(relay.poll_relay(cx, &mut self.stream));
+                    self.record.bytes_sent = relay.forward.total;
+                    self.record.bytes_received = relay.reverse.total;
+                    return match result {
+                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                            self.record.termination = Termination::IdleTimeout;
+                            Poll::Ready(Ok(()))
+                        }
+                        result => Poll::Ready(result),
+                    };
+                }
+                State::Done => return Poll::Ready(Ok(())),
+            };
+            self.state = next;
+        }
+    }
+
+    fn select_method(&mut self, methods: &[u8]) -> State {
+        let method = match self.server.verifier {
+            Some(_) => AuthMethod::Password,
+            None => AuthMethod::None,
+        };
+        if !methods.contains(&method.into()) {
+            self.record.termination = Termination::AuthFailed;
+            return self.write(vec![5, 0xff], State::Done); // no acceptable methods
+        }
+        let next = match method {
+            AuthMethod::Password => State::Password,
+            _ => self.authenticated(),
+        };
+        self.write(vec![5, method.into()], next)
+    }
+
+    fn verify(&mut self, username: String, password: &str) -> State {
+        let verified = match self.server.verifier {
+            Some(ref verify) => verify(&username, password),
+            None => true,
+        };
+        self.record.username = Some(username);
+        let next = if verified {
+            self.authenticated()
+        } else {
+            self.record.termination = Termination::AuthFailed;
+            State::Done
+        };
+        self.write(vec![1, if verified { 0 } else { 1 }], next)
+    }
+
+    fn authenticated(&self) -> State {
+        if let Some(ref events) = self.server.events {
+            events.authenticated(self.record.source, self.record.username.as_deref());
+        }
+        State::Request
+    }
+
+    fn check_request(&mut self,
+                     request: Result<(Command, TargetAddr), ReplyCode>)
+                     -> io::Result<State> {
+        let (command, target) = match request {
+            Ok(request) => request,
+            Err(code) => return self.refuse(Reply::new(code)),
+        };
+        self.record.target = Some(target.clone());
+        if command != Command::Connect {
+            return self.refuse(Reply::new(ReplyCode::CommandNotSupported));
+        }
+        let request = Request {
+            command,
+            target,
+            source: self.record.source,
+            username: self.record.username.clone(),
+        };
+
+        if let Some(ref events) = self.server.events {
+            events.target(&request);
+        }
+        if let Some(reply) = self.refusal.take() {
+            return self.refuse(reply);
+        }
+        if let Some(ref allow) = self.server.ruleset {
+            if !allow(&request) {
+                return self.refuse(Reply::new(ReplyCode::NotAllowed));
+            }
+        }
+        Ok(State::Connecting((self.handler)(&request), request))
+    }
+
+    fn connected(&mut self,
+                 outbound: Result<TcpStream, Reply>,
+                 request: &Request)
+                 -> io::Result<State> {
+        let outbound = match outbound {
+            Ok(outbound) => outbound,
+            Err(reply) => return self.refuse(reply),
+        };
+        if let Some(ref registration) = self.registration {
+            register(registration, &outbound)?;
+        }
+        let mut reply = Reply::succeeded(TargetAddr::Ip(outbound.local_addr()?));
+        if self.server.mask_bound_addr {
+            reply = reply.mask();
+        }
+        let rate = self.server.bandwidth.as_ref().and_then(|limit| limit(request));
+        // data the client sent after its request is relayed first
+        let pending = mem::take(&mut self.input);
+        let relay = Relay::new(outbound, rate, self.server.idle_timeout, &pending);
+        Ok(self.write(reply.to_bytes()?, State::Relaying(relay)))
+    }
+
+    // Sends a failure reply to the client, recording it as the reason the
+    // session ended.
+    fn refuse(&mut self, reply: Reply) -> io::Result<State> {
+        self.record.termination = Termination::Refused(reply.code());
+        Ok(self.write(reply.to_bytes()?, State::Done))
+    }
+
+    fn write(&mut self, message: Vec<u8>, next: State) -> State {
+        self.output = message;
+        self.written = 0;
+        State::Writing(Box::new(next))
+    }
+
+    // Starts the handshake timeout of the next read or write.
+    fn arm(&mut self) {
+        self.timer = self.server.handshake_timeout.map(|timeout| Box::pin(time::sleep(timeout)));
+    }
+
+    // Reads from the client until `parse` can decode a message from the data
+    // received, which is then consumed. `parse` must fail with an
+    // `UnexpectedEof` error if the message is incomplete.
+    fn poll_read_message<T, P>(&mut self, cx: &mut Context, parse: P) -> Poll<io::Result<T>>
+        where P: Fn(&mut &[u8]) -> io::Result<T>
+    {
+        loop {
+            let mut message = &self.input[..];
+            match parse(&mut message) {
+                Ok(value) => {
+                    let len = self.input.len() - message.len();
+                    self.input.drain(..len);
+                    return Poll::Ready(Ok(value));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            let mut buf = [0; 512];
+            let mut buf = ReadBuf::new(&mut buf);
+            match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                Poll::Ready(Ok(())) => self.input.extend_from_slice(buf.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return self.poll_timeout(cx),
+            }
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.written < self.output.len() {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.output[self.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(len)) => self.written += len,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return self.poll_timeout(cx),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_timeout<T>(&mut self, cx: &mut Context) -> Poll<io::Result<T>> {
+        let expired = match self.timer {
+            Some(ref mut timer) => timer.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if expired {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")));
+        }
+        Poll::Pending
+    }
+}
+
+// Adds a socket to close if the session outlives the drain deadline of a
+// shutdown.
+fn register(registration: &Registration, socket: &TcpStream) -> io::Result<()> {
+    let socket: net::TcpStream = SockRef::from(socket).try_clone()?.into();
+    registration.add(&socket)
+}
+
+fn read_greeting(r: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let mut header = [0; 2];
+    r.read_exact(&mut header)?;
+    if header[0] != 5 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid greeting version"));
+    }
+    let mut methods = vec![0; header[1] as usize];
+    r.read_exact(&mut methods)?;
+    Ok(methods)
+}
+
+// Reads a request, returning the reply code to refuse it with if its address
+// type isn't supported.
+fn read_request(r: &mut &[u8]) -> io::Result<Result<(Command, TargetAddr), ReplyCode>> {
+    let mut header = [0; 4];
+    r.read_exact(&mut header)?;
+    if header[0] != 5 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid request version"));
+    }
+    if let AddressType::Other(_) = AddressType::from(header[3]) {
+        return Ok(Err(ReplyCode::AddressTypeNotSupported));
+    }
+    let target = read_addr(&mut (&header[3..]).chain(&mut *r))?;
+    Ok(Ok((Command::from(header[1]), target)))
+}
+
+// Relays data in both directions between a client and the outbound
+// connection, like `relay_sockets`.
+struct Relay {
+    outbound: TcpStream,
+    forward: Pipe,
+    reverse: Pipe,
+    idle_timeout: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl Relay {
+    fn new(outbound: TcpStream,
+           rate: Option<u64>,
+           idle_timeout: Option<Duration>,
+           pending: &[u8])
+           -> Relay {
+        Relay {
+            outbound,
+            forward: Pipe::new(rate, pending),
+            reverse: Pipe::new(rate, &[]),
+            idle_timeout,
+            idle: idle_timeout.map(|timeout| Box::pin(time::sleep(timeout))),
+        }
+    }
+
+    // Relays data until both sides have closed, or fails with a `TimedOut`
+    // error once no data has been read from either side for the idle timeout.
+    fn poll_relay(&mut self, cx: &mut Context, client: &mut TcpStream) -> Poll<io::Result<()>> {
+        let mut active = false;
+        let forward = self.forward.poll_copy(cx, client, &mut self.outbound, &mut active)?;
+        let reverse = self.reverse.poll_copy(cx, &mut self.outbound, client, &mut active)?;
+        if forward.is_ready() && reverse.is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+
+        if let (Some(idle), Some(timeout)) = (self.idle.as_mut(), self.idle_timeout) {
+            if active {
+                idle.as_mut().reset(time::Instant::now() + timeout);
+            }
+            if idle.as_mut().poll(cx).is_ready() {
+                let err = io::Error::new(io::ErrorKind::TimedOut, "relay idle timeout expired");
+                return Poll::Ready(Err(err));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+// One direction of a relay, limited to `rate` bytes per second if set.
+struct Pipe {
+    buf: Box<[u8]>,
+    // the range of `buf` which has been read but not yet written
+    pos: usize,
+    len: usize,
+    eof: bool,
+    done: bool,
+    total: u64,
+    rate: Option<u64>,
+    start: Instant,
+    limited: u64,
+    // the delay before writing data read over the rate limit
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pipe {
+    fn new(rate: Option<u64>, pending: &[u8]) -> Pipe {
+        let mut buf = vec![0; cmp::max(RELAY_BUF_LEN, pending.len())].into_boxed_slice();
+        buf[..pending.len()].copy_from_slice(pending);
+        Pipe {
+            buf,
+            pos: 0,
+            len: pending.len(),
+            eof: false,
+            done: false,
+            total: 0,
+            rate: rate.map(|rate| cmp::max(rate, 1)),
+            start: Instant::now(),
+            limited: 0,
+            delay: None,
+        }
+    }
+
+    // Copies data from `src` to `dst` until `src` reaches EOF, and then shuts
+    // down the write half of `dst`. `active` is set if any data is read.
+    fn poll_copy(&mut self,
+                 cx: &mut Context,
+                 src: &mut TcpStream,
+                 dst: &mut TcpStream,
+                 active: &mut bool)
+                 -> Poll<io::Result<()>> {
+        loop {
+            if self.done {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(ref mut delay) = self.delay {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            if self.pos < self.len {
+                let unwritten = &self.buf[self.pos..self.len];
+                let len = ready!(Pin::new(&mut *dst).poll_write(cx, unwritten))?;
+                if len == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.pos += len;
+                self.total += len as u64;
+                continue;
+            }
+            if self.eof {
+                let _ = ready!(Pin::new(&mut *dst).poll_shutdown(cx));
+                self.done = true;
+                continue;
+            }
+
+            // don't read more than a second's worth of data at once
+            let chunk = match self.rate {
+                Some(rate) => cmp::min(self.buf.len() as u64, rate) as usize,
+                None => self.buf.len(),
+            };
+            let mut buf = ReadBuf::new(&mut self.buf[..chunk]);
+            ready!(Pin::new(&mut *src).poll_read(cx, &mut buf))?;
+            let len = buf.filled().len();
+            if len == 0 {
+                self.eof = true;
+                continue;
+            }
+            *active = true;
+            self.pos = 0;
+            self.len = len;
+
+            if let Some(rate) = self.rate {
+                self.limited += len as u64;
+                let due = Duration::from_secs_f64(self.limited as f64 / rate as f64);
+                let elapsed = self.start.elapsed();
+                if due > elapsed {
+                    self.delay = Some(Box::pin(time::sleep(due - elapsed)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::Shutdown;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::thread;
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    fn server(config: Socks5Server) -> (SocketAddr, thread::JoinHandle<io::Result<()>>) {
+        let listener = config.bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let server = thread::spawn(move || {
+            let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+            let listener = {
+                let _guard = runtime.enter();
+                TcpListener::from_std(listener).unwrap()
+            };
+            runtime.block_on(config.serve_tokio(listener, connect_direct_tokio))
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn serve() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let mut config = Socks5Server::new();
+        config.password_auth(|_, password| password == "pass")
+            .access_log(move |record: &AccessRecord| {
+                tx.lock().unwrap().send(record.clone()).unwrap();
+            });
+        let (proxy, _) = server(config);
+        let target = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "wrong").unwrap_err();
+        let record = rx.recv().unwrap();
+        assert_eq!(record.termination(), Termination::AuthFailed);
+        assert_eq!(record.username(), Some("user"));
+
+        let mut stream =
+            ::Socks5Stream::connect_with_password(proxy, target_addr, "user", "pass").unwrap();
+        let mut socket = target.accept().unwrap().0;
+        assert_eq!(stream.proxy_addr(), &TargetAddr::Ip(socket.peer_addr().unwrap()));
+        stream.write_all(b"hello").unwrap();
+        stream.get_ref().shutdown(Shutdown::Write).unwrap();
+        let mut buf = vec![];
+        socket.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        socket.write_all(b"hi").unwrap();
+        drop(socket);
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hi");
+
+        let record = rx.recv().unwrap();
+        assert_eq!(record.termination(), Termination::Closed);
+        assert_eq!(record.target_addr(), Some(&TargetAddr::Ip(target_addr)));
+        assert_eq!((record.bytes_sent(), record.bytes_received()), (5, 2));
+    }
+
+    #[test]
+    fn refused() {
+        let limiter = ::server::ConnectionLimiter::new().max_sessions(1);
+        let mut config = Socks5Server::new();
+        config.connection_limiter(limiter.clone())
+            .ruleset(|request| request.target_addr().port() != 1);
+        let (proxy, _) = server(config);
+        let target = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let err = ::Socks5Stream::connect(proxy, "127.0.0.1:1").unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<::ConnectError>().unwrap();
+        assert_eq!(err.kind(), ::ConnectErrorKind::NotAllowed);
+
+        let stream = ::Socks5Stream::connect(proxy, target_addr).unwrap();
+        let socket = target.accept().unwrap().0;
+        let err = ::Socks5Stream::connect(proxy, target_addr).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<::ConnectError>().unwrap();
+        assert_eq!(err.kind(), ::ConnectErrorKind::NotAllowed);
+        assert_eq!(limiter.sessions(), 1);
+        drop((stream, socket));
+    }
+
+    #[test]
+    fn shutdown() {
+        let handle = ShutdownHandle::new();
+        let mut config = Socks5Server::new();
+        config.shutdown_handle(handle.clone());
+        let (proxy, server) = server(config);
+        let target = net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut stream = ::Socks5Stream::connect(proxy, target.local_addr().unwrap()).unwrap();
+        let mut socket = target.accept().unwrap().0;
+
+        let start = Instant::now();
+        handle.shutdown(Some(Duration::from_millis(200)));
+        // the session is drained until the deadline
+        stream.write_all(b"hello").unwrap();
+        socket.read_exact(&mut [0; 5]).unwrap();
+
+        server.join().unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert!(::Socks5Stream::connect(proxy, target.local_addr().unwrap()).is_err());
+    }
+}
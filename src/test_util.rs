@@ -0,0 +1,128 @@
+//! Fixtures shared by the unit tests and benchmarks.
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+/// An in-memory stream which reads from a fixed input and records what is
+/// written to it.
+#[derive(Debug)]
+pub struct Mock {
+    pub input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+    write_first: bool,
+}
+
+impl Mock {
+    pub fn new(input: Vec<u8>) -> Mock {
+        Mock {
+            input: Cursor::new(input),
+            output: vec![],
+            write_first: false,
+        }
+    }
+
+    /// Makes reads panic until something has been written, for testing that
+    /// a request is sent before its response is read.
+    pub fn write_first(mut self) -> Mock {
+        self.write_first = true;
+        self
+    }
+}
+
+impl Read for Mock {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        assert!(!self.write_first || !self.output.is_empty(), "read before a write");
+        self.input.read(buf)
+    }
+}
+
+impl Write for Mock {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A stream over a `TcpStream`, standing in for the crate's proxied streams
+/// in tests of adapters generic over them.
+#[derive(Debug)]
+pub struct TcpMock(pub TcpStream);
+
+impl AsRef<TcpStream> for TcpMock {
+    fn as_ref(&self) -> &TcpStream {
+        &self.0
+    }
+}
+
+impl Read for TcpMock {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TcpMock {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Returns both ends of a loopback connection.
+pub fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    (client, listener.accept().unwrap().0)
+}
+
+/// Spawns a proxy which accepts a single no-auth CONNECT request for an IPv4
+/// target, returning the proxy's end of the connection.
+pub fn fake_proxy() -> (SocketAddr, thread::JoinHandle<TcpStream>) {
+    fake_proxy_replying([127, 0, 0, 1, 0, 80])
+}
+
+/// Like `fake_proxy`, but replies with the given IPv4 address and port.
+pub fn fake_proxy_replying(bound: [u8; 6]) -> (SocketAddr, thread::JoinHandle<TcpStream>) {
+    let mut reply = [5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    reply[4..].copy_from_slice(&bound);
+    spawn_proxy(move |listener| serve(&listener, &reply))
+}
+
+/// Like `fake_proxy`, but replies with the given failure code.
+pub fn failing_proxy(code: u8) -> (SocketAddr, thread::JoinHandle<TcpStream>) {
+    spawn_proxy(move |listener| serve(&listener, &[5, code, 0, 1, 0, 0, 0, 0, 0, 0]))
+}
+
+/// Spawns a proxy which accepts `connections` no-auth requests for IPv4
+/// targets in turn, sending each the same reply, and returns the proxy's ends
+/// of the connections.
+pub fn fake_proxy_serving(connections: usize,
+                          reply: [u8; 10])
+                          -> (SocketAddr, thread::JoinHandle<Vec<TcpStream>>) {
+    spawn_proxy(move |listener| (0..connections).map(|_| serve(&listener, &reply)).collect())
+}
+
+fn spawn_proxy<F, T>(f: F) -> (SocketAddr, thread::JoinHandle<T>)
+    where F: FnOnce(TcpListener) -> T + Send + 'static,
+          T: Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = listener.local_addr().unwrap();
+    (proxy, thread::spawn(move || f(listener)))
+}
+
+fn serve(listener: &TcpListener, reply: &[u8]) -> TcpStream {
+    let mut socket = listener.accept().unwrap().0;
+    let mut greeting = [0; 3];
+    socket.read_exact(&mut greeting).unwrap();
+    socket.write_all(&[5, 0]).unwrap();
+    let mut request = [0; 10];
+    socket.read_exact(&mut request).unwrap();
+    socket.write_all(reply).unwrap();
+    socket
+}
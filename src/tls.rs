@@ -0,0 +1,206 @@
+//! TLS connections through a SOCKS5 proxy.
+//!
+//! Requires the `rustls` Cargo feature. Two independent uses of TLS are
+//! supported: to the target, with `connect_tls` and `wrap`, and to the proxy
+//! itself, with `Socks5Builder::proxy_tls`.
+//!
+//! The TLS server name of a target is taken from the original target rather
+//! than from anything the proxy reports, so certificates are verified against
+//! the host that was asked for even when DNS resolution happens on the proxy.
+//!
+//! Client certificates for mutual TLS are part of the `ClientConfig`, set up
+//! with `ConfigBuilder::with_client_auth_cert`, and apply to whichever of the
+//! target or proxy the config is used with.
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+use {Command, Socks5Builder, Socks5Stream, TargetAddr, ToTargetAddr, encode_domain,
+     invalid_domain};
+
+/// A TLS stream over a connection through a SOCKS5 proxy.
+pub type TlsStream = StreamOwned<ClientConnection, Socks5Stream>;
+
+/// Returns the TLS server name of a target.
+///
+/// Internationalized domain names are converted to punycode, as for the
+/// SOCKS request itself.
+pub fn server_name(target: &TargetAddr) -> io::Result<ServerName<'static>> {
+    match *target {
+        TargetAddr::Ip(addr) => Ok(ServerName::from(addr.ip())),
+        TargetAddr::Domain(ref domain, _) => {
+            let ascii = encode_domain(domain)?.into_owned();
+            ServerName::try_from(ascii)
+                .map_err(|_| invalid_domain(domain, "not a valid TLS server name"))
+        }
+    }
+}
+
+/// Performs a TLS handshake over an established proxied stream.
+///
+/// The server name is that of the stream's `target_addr`.
+pub fn wrap(mut stream: Socks5Stream, config: Arc<ClientConfig>) -> io::Result<TlsStream> {
+    let name = server_name(stream.target_addr())?;
+    let mut conn = ClientConnection::new(config, name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(conn, stream))
+}
+
+/// Connects to a target server through a SOCKS5 proxy and performs a TLS
+/// handshake with it.
+///
+/// Use `wrap` with a stream from `Socks5Builder::connect` if the proxy
+/// requires authentication.
+pub fn connect_tls<T, U>(proxy: T, target: U, config: Arc<ClientConfig>) -> io::Result<TlsStream>
+    where T: ToSocketAddrs,
+          U: ToTargetAddr
+{
+    wrap(Socks5Stream::connect(proxy, target)?, config)
+}
+
+// Settings for connecting to a SOCKS5 proxy over TLS.
+#[derive(Clone)]
+pub(crate) struct ProxyTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl fmt::Debug for ProxyTls {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ProxyTls")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl Socks5Builder {
+    /// Connects to the proxy over TLS, verifying its certificate against
+    /// `server_name` according to `config`.
+    ///
+    /// Some providers expose SOCKS endpoints which only accept TLS
+    /// connections. The proxy's certificate is verified independently of any
+    /// TLS used with the target. Connections must then be made with
+    /// `connect_tls_proxy`; other methods opening a connection to the proxy
+    /// return an `InvalidInput` error rather than falling back to plaintext.
+    ///
+    /// Requires the `rustls` Cargo feature.
+    pub fn proxy_tls(&mut self,
+                     config: Arc<ClientConfig>,
+                     server_name: ServerName<'static>)
+                     -> &mut Socks5Builder {
+        self.proxy_tls = Some(ProxyTls {
+            config,
+            server_name,
+        });
+        self
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy reached over TLS,
+    /// as configured by `proxy_tls`.
+    ///
+    /// Requires the `rustls` Cargo feature.
+    pub fn connect_tls_proxy<T, U>(&self, proxy: T, target: U) -> io::Result<ProxyTlsStream>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let tls = match self.proxy_tls {
+            Some(ref tls) => tls,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "proxy TLS is not configured"))
+            }
+        };
+        let socket = TcpStream::connect(&*self.proxy_addrs(proxy)?)?;
+        let proxy = socket.peer_addr().ok();
+        let target = target.to_target_addr()?;
+        let conn = ClientConnection::new(tls.config.clone(), tls.server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stream = StreamOwned::new(conn, socket);
+        let proxy_addr = self.handshake(&mut stream, Command::Connect, &target, proxy)?;
+
+        Ok(ProxyTlsStream {
+            stream,
+            proxy_addr,
+            target,
+        })
+    }
+}
+
+/// A connection to a target through a SOCKS5 proxy reached over TLS.
+#[derive(Debug)]
+pub struct ProxyTlsStream {
+    stream: StreamOwned<ClientConnection, TcpStream>,
+    proxy_addr: TargetAddr,
+    target: TargetAddr,
+}
+
+impl ProxyTlsStream {
+    /// Returns the proxy-side address of the connection between the proxy and
+    /// target server.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.proxy_addr
+    }
+
+    /// Returns the target address passed to `connect_tls_proxy`.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Returns a shared reference to the inner TLS stream.
+    pub fn get_ref(&self) -> &StreamOwned<ClientConnection, TcpStream> {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the inner TLS stream.
+    pub fn get_mut(&mut self) -> &mut StreamOwned<ClientConnection, TcpStream> {
+        &mut self.stream
+    }
+
+    /// Consumes the `ProxyTlsStream`, returning the inner TLS stream.
+    pub fn into_inner(self) -> StreamOwned<ClientConnection, TcpStream> {
+        self.stream
+    }
+}
+
+impl Read for ProxyTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ProxyTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn server_names() {
+        let target = TargetAddr::Domain("example.com".to_owned(), 443);
+        assert_eq!(server_name(&target).unwrap(),
+                   ServerName::try_from("example.com").unwrap());
+
+        let target = TargetAddr::Ip("[::1]:443".parse().unwrap());
+        assert_eq!(server_name(&target).unwrap(),
+                   ServerName::from("::1".parse::<::std::net::IpAddr>().unwrap()));
+
+        let target = TargetAddr::Domain("a..example".to_owned(), 443);
+        assert_eq!(server_name(&target).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
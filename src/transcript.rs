@@ -0,0 +1,141 @@
+//! Transcripts of the negotiation with a proxy.
+//!
+//! Requires the `transcript` Cargo feature. When enabled, the bytes exchanged
+//! while negotiating a connection are recorded and attached to any
+//! `ConnectError`, so that a protocol trace can be included in bug reports
+//! against misbehaving proxies.
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use ConnectError;
+
+/// The direction of a chunk of a `Transcript`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Bytes sent to the proxy.
+    Sent,
+    /// Bytes received from the proxy.
+    Received,
+}
+
+/// A record of the bytes exchanged with a proxy.
+///
+/// Credentials are replaced with `*` bytes of the same length.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    chunks: Vec<(Direction, Vec<u8>)>,
+}
+
+impl Transcript {
+    /// Returns the chunks of the transcript, in the order they were sent or
+    /// received.
+    pub fn chunks(&self) -> &[(Direction, Vec<u8>)] {
+        &self.chunks
+    }
+
+    fn push(&mut self, direction: Direction, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some(&mut (last, ref mut chunk)) if last == direction => chunk.extend_from_slice(data),
+            _ => self.chunks.push((direction, data.to_vec())),
+        }
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for &(direction, ref chunk) in &self.chunks {
+            fmt.write_str(match direction {
+                Direction::Sent => ">",
+                Direction::Received => "<",
+            })?;
+            for byte in chunk {
+                write!(fmt, " {:02x}", byte)?;
+            }
+            fmt.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+// A stream which records the bytes passing through it.
+pub(crate) struct Recorder<'a, S> {
+    stream: S,
+    secrets: Vec<&'a [u8]>,
+    transcript: Transcript,
+}
+
+impl<'a, S> Recorder<'a, S> {
+    pub(crate) fn new(stream: S, secrets: Vec<&'a [u8]>) -> Recorder<'a, S> {
+        Recorder {
+            stream,
+            secrets: secrets.into_iter().filter(|s| !s.is_empty()).collect(),
+            transcript: Transcript::default(),
+        }
+    }
+
+    // Attaches the transcript so far to a `ConnectError`.
+    pub(crate) fn attach(&self, mut err: io::Error) -> io::Error {
+        if let Some(err) = err.get_mut().and_then(|e| e.downcast_mut::<ConnectError>()) {
+            err.transcript = Some(self.transcript.clone());
+        }
+        err
+    }
+
+    fn mask(&self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for secret in &self.secrets {
+            let mut i = 0;
+            while i + secret.len() <= data.len() {
+                if data[i..i + secret.len()] == **secret {
+                    for byte in &mut data[i..i + secret.len()] {
+                        *byte = b'*';
+                    }
+                    i += secret.len();
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        data
+    }
+}
+
+impl<'a, S: Read> Read for Recorder<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        self.transcript.push(Direction::Received, &buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<'a, S: Write> Write for Recorder<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        let masked = self.mask(&buf[..len]);
+        self.transcript.push(Direction::Sent, &masked);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn masked_credentials() {
+        let mut recorder = Recorder::new(Cursor::new(vec![]), vec![&b"user"[..], b"hunter2", b""]);
+        recorder.write_all(&[5, 1, 2]).unwrap();
+        recorder.write_all(b"\x01\x04user\x07hunter2").unwrap();
+
+        assert_eq!(recorder.transcript.chunks(),
+                   &[(Direction::Sent, b"\x05\x01\x02\x01\x04****\x07*******".to_vec())]);
+        assert_eq!(recorder.transcript.to_string(),
+                   "> 05 01 02 01 04 2a 2a 2a 2a 07 2a 2a 2a 2a 2a 2a 2a\n");
+    }
+}
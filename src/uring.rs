@@ -0,0 +1,320 @@
+//! Batched UDP I/O through a SOCKS5 proxy using io_uring.
+//!
+//! Requires the `io-uring` Cargo feature, and is only available on Linux.
+use io_uring::{opcode, types, IoUring};
+use libc;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use {Socks5Datagram, TargetAddr};
+use v5::{MAX_ADDR_LEN, parse_udp_header, write_addr};
+
+// Tags in the user data of submissions which aren't receives. Receives are
+// identified by the index of their buffer.
+const SEND: u64 = 1 << 32;
+const CANCEL: u64 = 1 << 33;
+
+// The largest number of buffers which can be registered with a ring.
+const MAX_BUFFERS: usize = 1024;
+
+/// Batched sends and receives on a `Socks5Datagram`, using io_uring with
+/// registered buffers.
+///
+/// Each batch is submitted with a single system call, which helps
+/// applications relaying large numbers of small messages, such as tunneled
+/// VPN traffic, which are otherwise bound by per-message system call
+/// overhead.
+///
+/// Receives are kept in flight between calls to `recv_batch`, so messages
+/// arriving in the meantime are read directly into the registered buffers.
+/// Fragmented messages are not reassembled, and are discarded.
+pub struct UringDatagram<'a> {
+    socket: &'a Socks5Datagram,
+    ring: IoUring,
+    // the first `depth` buffers are used for receives, and the rest for
+    // sends
+    buffers: Vec<Box<[u8]>>,
+    depth: usize,
+    // receive buffers with no read in flight
+    idle: Vec<usize>,
+    // receives which have completed but not yet been returned
+    completed: VecDeque<(usize, i32)>,
+    sends_done: usize,
+    send_error: Option<i32>,
+}
+
+impl<'a> fmt::Debug for UringDatagram<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("UringDatagram")
+            .field("socket", self.socket)
+            .field("depth", &self.depth)
+            .finish()
+    }
+}
+
+impl<'a> UringDatagram<'a> {
+    /// Sets up a ring for the socket which keeps up to `depth` sends and
+    /// receives in flight, with buffers of `buf_len` bytes.
+    ///
+    /// Each buffer holds an entire message, including the SOCKS header of up
+    /// to 262 bytes, so `buf_len` must be at least that.
+    pub fn new(socket: &'a Socks5Datagram,
+               depth: usize,
+               buf_len: usize)
+               -> io::Result<UringDatagram<'a>> {
+        if depth == 0 || depth * 2 > MAX_BUFFERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid depth"));
+        }
+        if buf_len < MAX_ADDR_LEN + 3 || buf_len > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid buffer length"));
+        }
+
+        let ring = IoUring::new(depth as u32 * 2)?;
+        let mut buffers: Vec<Box<[u8]>> = (0..depth * 2)
+            .map(|_| vec![0; buf_len].into_boxed_slice())
+            .collect();
+        let iovecs = buffers.iter_mut()
+            .map(|buf| {
+                libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                }
+            })
+            .collect::<Vec<_>>();
+        // the buffers live as long as the ring; see the `Drop` impl
+        unsafe {
+            ring.submitter().register_buffers(&iovecs)?;
+        }
+
+        Ok(UringDatagram {
+            socket,
+            ring,
+            buffers,
+            depth,
+            idle: (0..depth).collect(),
+            completed: VecDeque::new(),
+            sends_done: 0,
+            send_error: None,
+        })
+    }
+
+    /// Sends a batch of messages.
+    ///
+    /// Returns once every message has been sent. Messages which don't fit in
+    /// a buffer along with their header are rejected before anything is
+    /// sent.
+    pub fn send_batch(&mut self, messages: &[(&[u8], TargetAddr)]) -> io::Result<()> {
+        // a batch larger than the ring is sent in chunks, so everything is
+        // checked up front
+        let mut header = [0; MAX_ADDR_LEN];
+        for &(data, ref addr) in messages {
+            if 3 + write_addr(&mut header, addr)? + data.len() > self.buffers[0].len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"));
+            }
+        }
+
+        for chunk in messages.chunks(self.depth) {
+            self.send_chunk(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, messages: &[(&[u8], TargetAddr)]) -> io::Result<()> {
+        let mut lens = Vec::with_capacity(messages.len());
+        for (i, &(data, ref addr)) in messages.iter().enumerate() {
+            let buf = &mut self.buffers[self.depth + i];
+            buf[..3].copy_from_slice(&[0, 0, 0]);
+            let header_len = 3 + write_addr(&mut buf[3..], addr)?;
+            let len = header_len + data.len();
+            buf[header_len..len].copy_from_slice(data);
+            lens.push(len);
+        }
+
+        let fd = types::Fd(self.socket.get_ref().as_raw_fd());
+        self.sends_done = 0;
+        self.send_error = None;
+        for (i, &len) in lens.iter().enumerate() {
+            let index = self.depth + i;
+            let entry = opcode::WriteFixed::new(fd,
+                                                self.buffers[index].as_ptr(),
+                                                len as u32,
+                                                index as u16)
+                .build()
+                .user_data(SEND | i as u64);
+            // a send slot is always free, since sends are waited for
+            unsafe {
+                self.ring.submission().push(&entry).expect("submission queue full");
+            }
+        }
+
+        while self.sends_done < lens.len() {
+            self.ring.submit_and_wait(1)?;
+            self.reap();
+        }
+        match self.send_error {
+            Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+            None => Ok(()),
+        }
+    }
+
+    /// Receives a batch of messages, passing each to `f` along with the
+    /// address of its sender.
+    ///
+    /// Blocks until at least one message is available, and returns the
+    /// number of messages received.
+    pub fn recv_batch<F>(&mut self, mut f: F) -> io::Result<usize>
+        where F: FnMut(&[u8], &TargetAddr)
+    {
+        let mut count = 0;
+        loop {
+            while let Some((index, result)) = self.completed.pop_front() {
+                if result < 0 {
+                    if count > 0 {
+                        // report the error next time
+                        self.completed.push_front((index, result));
+                        return Ok(count);
+                    }
+                    self.idle.push(index);
+                    return Err(io::Error::from_raw_os_error(-result));
+                }
+
+                self.idle.push(index);
+                let message = &self.buffers[index][..result as usize];
+                if let Ok((header, len)) = parse_udp_header(message) {
+                    if header.fragment() == 0 {
+                        f(&message[len..], header.addr());
+                        count += 1;
+                    }
+                }
+            }
+            if count > 0 {
+                self.fill();
+                self.ring.submit()?;
+                return Ok(count);
+            }
+
+            self.fill();
+            self.ring.submit_and_wait(1)?;
+            self.reap();
+        }
+    }
+
+    // Starts a receive into each idle buffer.
+    fn fill(&mut self) {
+        let fd = types::Fd(self.socket.get_ref().as_raw_fd());
+        while let Some(index) = self.idle.pop() {
+            let buf = &mut self.buffers[index];
+            let entry = opcode::ReadFixed::new(fd, buf.as_mut_ptr(), buf.len() as u32, index as u16)
+                .build()
+                .user_data(index as u64);
+            // there are at most `depth` receives and `depth` sends in flight
+            unsafe {
+                self.ring.submission().push(&entry).expect("submission queue full");
+            }
+        }
+    }
+
+    fn reap(&mut self) {
+        for entry in self.ring.completion() {
+            let data = entry.user_data();
+            if data & SEND != 0 {
+                self.sends_done += 1;
+                if entry.result() < 0 && self.send_error.is_none() {
+                    self.send_error = Some(-entry.result());
+                }
+            } else if data & CANCEL == 0 {
+                self.completed.push_back((data as usize, entry.result()));
+            }
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.depth - self.idle.len() - self.completed.len()
+    }
+}
+
+impl<'a> Drop for UringDatagram<'a> {
+    fn drop(&mut self) {
+        // The kernel may write into the buffers until the receives in flight
+        // complete, so cancel them and wait.
+        let indices = (0..self.depth)
+            .filter(|i| !self.idle.contains(i) && !self.completed.iter().any(|c| c.0 == *i))
+            .collect::<Vec<_>>();
+        let mut cancelled = true;
+        for index in indices {
+            let entry = opcode::AsyncCancel::new(index as u64).build().user_data(CANCEL);
+            while cancelled && unsafe { self.ring.submission().push(&entry).is_err() } {
+                cancelled = self.ring.submit().is_ok();
+            }
+        }
+        while cancelled && self.in_flight() > 0 {
+            cancelled = self.ring.submit_and_wait(1).is_ok();
+            self.reap();
+        }
+
+        if !cancelled {
+            // leak the buffers rather than risk them being written to after
+            // they're freed
+            mem::forget(mem::take(&mut self.buffers));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+
+    use super::*;
+    use test_util::fake_proxy_replying;
+
+    #[test]
+    fn batches() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let (proxy, server) = fake_proxy_replying([127, 0, 0, 1, (port >> 8) as u8, port as u8]);
+        let socket = Socks5Datagram::bind(proxy, "127.0.0.1:0").unwrap();
+        let _control = server.join().unwrap();
+        let local = socket.get_ref().local_addr().unwrap();
+
+        let mut uring = match UringDatagram::new(&socket, 2, 512) {
+            Ok(uring) => uring,
+            // io_uring may be disabled
+            Err(_) => return,
+        };
+
+        let target = TargetAddr::Ip("10.0.0.1:53".parse().unwrap());
+        let messages = [(&b"a"[..], target.clone()), (b"bc", target.clone()), (b"d", target)];
+        uring.send_batch(&messages).unwrap();
+        let mut buf = [0; 64];
+        for payload in &[&b"a"[..], b"bc", b"d"] {
+            let len = relay.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..10], [0, 0, 0, 1, 10, 0, 0, 1, 0, 53]);
+            assert_eq!(&buf[10..len], *payload);
+        }
+
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 2, 0, 53, b'x'], local).unwrap();
+        relay.send_to(&[0, 0, 1, 1, 10, 0, 0, 2, 0, 53, b'y'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 2, 0, 53, b'z'], local).unwrap();
+        let mut received = vec![];
+        while received.len() < 2 {
+            uring.recv_batch(|data, addr| {
+                    assert_eq!(*addr, TargetAddr::Ip("10.0.0.2:53".parse().unwrap()));
+                    received.push(data.to_vec());
+                })
+                .unwrap();
+        }
+        assert_eq!(received, [b"x", b"z"]);
+
+        // nothing is sent if a message in a later chunk is too long
+        let target = TargetAddr::Ip("10.0.0.1:53".parse().unwrap());
+        let long = [0; 512];
+        let messages = [(&b"a"[..], target.clone()), (b"b", target.clone()), (&long, target)];
+        let err = uring.send_batch(&messages).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        relay.set_nonblocking(true).unwrap();
+        assert_eq!(relay.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
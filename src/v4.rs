@@ -1,10 +1,28 @@
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-use std::io::{self, Read, Write};
+use std::env;
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
 use std::net::{SocketAddr, ToSocketAddrs, SocketAddrV4, SocketAddrV6, TcpStream, Ipv4Addr};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ftp;
+use resolver::resolve;
+use {BeforeConnect, ConnectError, ConnectTiming, Error, Resolver, SocketHandle, SystemResolver,
+     ToTargetAddr, TargetAddr, check_bind_peer, check_domain_len, connect_proxy, encode_domain,
+     socket_hook};
+use v5::Command;
+#[cfg(feature = "transcript")]
+use transcript::Recorder;
+
+// Sends a request and reads the proxy's reply.
+fn negotiate<S: Read + Write>(socket: &mut S, packet: &[u8]) -> io::Result<SocketAddrV4> {
+    socket.write_all(packet)?;
+    read_response(socket)
+}
 
-use {ToTargetAddr, TargetAddr};
-
-fn read_response(socket: &mut TcpStream) -> io::Result<SocketAddrV4> {
+pub(crate) fn read_response<R: Read>(socket: &mut R) -> io::Result<SocketAddrV4> {
     let mut response = [0u8; 8];
     socket.read_exact(&mut response)?;
     let mut response = &response[..];
@@ -15,16 +33,9 @@ fn read_response(socket: &mut TcpStream) -> io::Result<SocketAddrV4> {
 
     match response.read_u8()? {
         90 => {}
-        91 => return Err(io::Error::new(io::ErrorKind::Other, "request rejected or failed")),
-        92 => {
-            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
-                                      "request rejected because SOCKS server cannot connect to \
-                                       idnetd on the client"))
-        }
-        93 => {
-            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
-                                      "request rejected because the client program and identd \
-                                       report different user-ids"))
+        91 => return Err(io::Error::other(Error::Socks4Reply { code: 91 })),
+        code @ 92..=93 => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, Error::Socks4Reply { code }))
         }
         _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response code")),
     }
@@ -40,6 +51,9 @@ fn read_response(socket: &mut TcpStream) -> io::Result<SocketAddrV4> {
 pub struct Socks4Stream {
     socket: TcpStream,
     proxy_addr: SocketAddrV4,
+    bound_addr: Option<SocketAddrV4>,
+    target: TargetAddr,
+    timing: ConnectTiming,
 }
 
 impl Socks4Stream {
@@ -55,21 +69,26 @@ impl Socks4Stream {
         where T: ToSocketAddrs,
               U: ToTargetAddr
     {
-        Self::connect_raw(1, proxy, target, userid)
+        Self::connect_raw(Command::Connect, proxy, target, userid, None)
     }
 
-    fn connect_raw<T, U>(command: u8, proxy: T, target: U, userid: &str) -> io::Result<Socks4Stream>
+    fn connect_raw<T, U>(command: Command,
+                         proxy: T,
+                         target: U,
+                         userid: &str,
+                         before_connect: Option<&BeforeConnect>)
+                         -> io::Result<Socks4Stream>
         where T: ToSocketAddrs,
               U: ToTargetAddr
     {
-        let mut socket = TcpStream::connect(proxy)?;
+        validate_userid(userid)?;
 
         let target = target.to_target_addr()?;
 
         let mut packet = vec![];
         let _ = packet.write_u8(4); // version
-        let _ = packet.write_u8(command); // command code
-        match target.to_target_addr()? {
+        let _ = packet.write_u8(command.into()); // command code
+        match target {
             TargetAddr::Ip(addr) => {
                 let addr = match addr {
                     SocketAddr::V4(addr) => addr,
@@ -84,6 +103,7 @@ impl Socks4Stream {
                 let _ = packet.write_u8(0);
             }
             TargetAddr::Domain(ref host, port) => {
+                let host = encode_domain(host)?;
                 let _ = packet.write_u16::<BigEndian>(port);
                 let _ = packet.write_u32::<BigEndian>(Ipv4Addr::new(0, 0, 0, 1).into());
                 let _ = packet.write_all(userid.as_bytes());
@@ -93,21 +113,79 @@ impl Socks4Stream {
             }
         }
 
-        socket.write_all(&packet)?;
-        let proxy_addr = read_response(&mut socket)?;
+        let start = Instant::now();
+        let mut socket = connect_proxy(proxy, before_connect)
+            .map_err(|(proxy, e)| ConnectError::unreachable(proxy, &target, e))?;
+        let connected = Instant::now();
+
+        let proxy = socket.peer_addr().ok();
+        let context = |e: io::Error| ConnectError::wrap(proxy, &target, e);
+        #[cfg(not(feature = "transcript"))]
+        let proxy_addr = negotiate(&mut socket, &packet).map_err(context)?;
+        #[cfg(feature = "transcript")]
+        let proxy_addr = {
+            let mut recorder = Recorder::new(&mut socket, vec![userid.as_bytes()]);
+            negotiate(&mut recorder, &packet).map_err(|e| recorder.attach(context(e)))?
+        };
 
         Ok(Socks4Stream {
             socket: socket,
             proxy_addr: proxy_addr,
+            bound_addr: None,
+            target,
+            timing: ConnectTiming {
+                tcp_connect: connected - start,
+                auth: Duration::from_secs(0),
+                request: connected.elapsed(),
+            },
         })
     }
 
     /// Returns the proxy-side address of the connection between the proxy and
     /// target server.
+    ///
+    /// Use `peer_addr` for the address of the proxy server itself.
     pub fn proxy_addr(&self) -> SocketAddrV4 {
         self.proxy_addr
     }
 
+    /// Returns the address of the proxy-side TCP listener as reported in the
+    /// first reply to a BIND request, for streams returned by
+    /// `Socks4Listener::accept`.
+    ///
+    /// For these streams, `proxy_addr` is the address of the remote process
+    /// reported in the second reply.
+    pub fn bound_addr(&self) -> Option<SocketAddrV4> {
+        self.bound_addr
+    }
+
+    /// Returns a breakdown of the time taken to establish the connection.
+    pub fn timing(&self) -> ConnectTiming {
+        self.timing
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    ///
+    /// This is distinct from `proxy_addr`, which is the address the proxy
+    /// uses for its connection to the target server.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    /// Returns the local address of the connection to the proxy server.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Moves the inner `TcpStream` into or out of nonblocking mode.
+    ///
+    /// The handshake with the proxy is always performed in blocking mode;
+    /// once the stream has been established, reads and writes pass
+    /// `WouldBlock` errors through unchanged.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
     /// Returns a shared reference to the inner `TcpStream`.
     pub fn get_ref(&self) -> &TcpStream {
         &self.socket
@@ -124,16 +202,30 @@ impl Socks4Stream {
     }
 }
 
+impl AsRef<TcpStream> for Socks4Stream {
+    fn as_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
 impl Read for Socks4Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.socket.read(buf)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.socket.read_vectored(bufs)
+    }
 }
 
 impl<'a> Read for &'a Socks4Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         (&self.socket).read(buf)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        (&self.socket).read_vectored(bufs)
+    }
 }
 
 impl Write for Socks4Stream {
@@ -141,6 +233,10 @@ impl Write for Socks4Stream {
         self.socket.write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.socket.write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.socket.flush()
     }
@@ -151,16 +247,201 @@ impl<'a> Write for &'a Socks4Stream {
         (&self.socket).write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        (&self.socket).write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         (&self.socket).flush()
     }
 }
 
+fn validate_userid(userid: &str) -> io::Result<()> {
+    if userid.contains('\0') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, Error::InvalidUserid));
+    }
+    Ok(())
+}
+
+/// A reusable SOCKS4 client configuration.
+///
+/// The user ID is validated once and then sent with every request made
+/// through the builder.
+#[derive(Clone, Default)]
+pub struct Socks4Builder {
+    userid: String,
+    max_domain_len: Option<usize>,
+    resolver: Option<Arc<dyn Resolver>>,
+    before_connect: Option<Arc<BeforeConnect>>,
+    traffic_class: Option<u32>,
+}
+
+impl fmt::Debug for Socks4Builder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Socks4Builder")
+            .field("userid", &self.userid)
+            .field("max_domain_len", &self.max_domain_len)
+            .field("resolver", &self.resolver.is_some())
+            .field("before_connect", &self.before_connect.is_some())
+            .field("traffic_class", &self.traffic_class)
+            .finish()
+    }
+}
+
+impl Socks4Builder {
+    /// Creates a new builder with an empty user ID.
+    pub fn new() -> Socks4Builder {
+        Socks4Builder::default()
+    }
+
+    /// Sets the user ID sent to the proxy.
+    ///
+    /// Returns an error if the user ID contains a NUL byte, since it cannot
+    /// be represented in a SOCKS4 request.
+    pub fn userid(&mut self, userid: &str) -> io::Result<&mut Socks4Builder> {
+        validate_userid(userid)?;
+        self.userid = userid.to_owned();
+        Ok(self)
+    }
+
+    /// Sets the user ID to the login name of the current user.
+    ///
+    /// The name is read from the `LOGNAME`, `USER` or `USERNAME` environment
+    /// variables, in that order.
+    pub fn userid_from_env(&mut self) -> io::Result<&mut Socks4Builder> {
+        let userid = ["LOGNAME", "USER", "USERNAME"]
+            .iter()
+            .filter_map(|var| env::var(var).ok())
+            .find(|userid| !userid.is_empty());
+        match userid {
+            Some(userid) => self.userid(&userid),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "login name not found")),
+        }
+    }
+
+    /// Returns the configured user ID.
+    pub fn get_userid(&self) -> &str {
+        &self.userid
+    }
+
+    /// Sets the maximum length in bytes of domain name targets.
+    ///
+    /// SOCKS4A places no limit on the length of domain names, but many
+    /// proxies, including Tor, reject names longer than 255 bytes. Connecting
+    /// to a longer name than allowed fails with an `InvalidInput` error
+    /// wrapping `Error::DomainTooLong` before anything is sent to the proxy.
+    /// Defaults to no limit.
+    pub fn max_domain_len(&mut self, len: usize) -> &mut Socks4Builder {
+        self.max_domain_len = Some(len);
+        self
+    }
+
+    /// Resolves domain name targets locally with the specified resolver,
+    /// rather than sending them to the proxy with the SOCKS4A extension.
+    ///
+    /// This is needed for proxies which don't support SOCKS4A. The first IPv4
+    /// address returned by the resolver is used. The resolver is also used to
+    /// look up the proxy's address, which otherwise uses `SystemResolver`.
+    pub fn resolver<R>(&mut self, resolver: R) -> &mut Socks4Builder
+        where R: Resolver + 'static
+    {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Sets a function called with each socket opened to the proxy after it
+    /// is created but before it is connected.
+    ///
+    /// See `Socks5Builder::before_connect` for details.
+    pub fn before_connect<F>(&mut self, f: F) -> &mut Socks4Builder
+        where F: Fn(SocketHandle<'_>, &SocketAddr) -> io::Result<()> + Sync + Send + 'static
+    {
+        self.before_connect = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets the `IP_TOS` or `IPV6_TCLASS` value of connections to the proxy.
+    ///
+    /// See `Socks5Builder::traffic_class` for details.
+    pub fn traffic_class(&mut self, class: Option<u32>) -> &mut Socks4Builder {
+        self.traffic_class = class;
+        self
+    }
+
+    fn check_target(&self, target: &TargetAddr) -> io::Result<()> {
+        match self.max_domain_len {
+            Some(max) => check_domain_len(target, max),
+            None => Ok(()),
+        }
+    }
+
+    // Checks the target, resolving it if a resolver is configured.
+    fn prepare_target(&self, target: TargetAddr) -> io::Result<TargetAddr> {
+        self.check_target(&target)?;
+        let resolver = match self.resolver {
+            Some(ref resolver) if target.is_domain() => resolver,
+            _ => return Ok(target),
+        };
+        match resolve(&**resolver, &target)?.into_iter().find(SocketAddr::is_ipv4) {
+            Some(addr) => Ok(TargetAddr::Ip(addr)),
+            None => {
+                Err(io::Error::new(io::ErrorKind::NotFound,
+                                   "target did not resolve to any IPv4 addresses"))
+            }
+        }
+    }
+
+    fn proxy_addrs<T: ToTargetAddr>(&self, proxy: T) -> io::Result<Vec<SocketAddr>> {
+        let proxy = proxy.to_target_addr()?;
+        match self.resolver {
+            Some(ref resolver) => resolve(&**resolver, &proxy),
+            None => resolve(&SystemResolver, &proxy),
+        }
+    }
+
+    /// Connects to a target server through a SOCKS4 proxy.
+    ///
+    /// See `Socks4Stream::connect` for details.
+    pub fn connect<T, U>(&self, proxy: T, target: U) -> io::Result<Socks4Stream>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let proxy = self.proxy_addrs(proxy)?;
+        let target = self.prepare_target(target.to_target_addr()?)?;
+        let hook = socket_hook(self.traffic_class, &self.before_connect);
+        Socks4Stream::connect_raw(Command::Connect, &*proxy, target, &self.userid, hook.as_deref())
+    }
+
+    /// Initiates a BIND request to the specified proxy.
+    ///
+    /// See `Socks4Listener::bind` for details.
+    pub fn bind<T, U>(&self, proxy: T, target: U) -> io::Result<Socks4Listener>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let proxy = self.proxy_addrs(proxy)?;
+        let target = self.prepare_target(target.to_target_addr()?)?;
+        let hook = socket_hook(self.traffic_class, &self.before_connect);
+        Socks4Stream::connect_raw(Command::Bind, &*proxy, target, &self.userid, hook.as_deref())
+            .map(Socks4Listener::new)
+    }
+}
+
 /// A SOCKS4 BIND client.
 #[derive(Debug)]
-pub struct Socks4Listener(Socks4Stream);
+pub struct Socks4Listener {
+    stream: Socks4Stream,
+    verify_peer: bool,
+}
 
 impl Socks4Listener {
+    fn new(stream: Socks4Stream) -> Socks4Listener {
+        Socks4Listener {
+            stream,
+            verify_peer: false,
+        }
+    }
+
     /// Initiates a BIND request to the specified proxy.
     ///
     /// The proxy will filter incoming connections based on the value of
@@ -169,7 +450,8 @@ impl Socks4Listener {
         where T: ToSocketAddrs,
               U: ToTargetAddr
     {
-        Socks4Stream::connect_raw(2, proxy, target, userid).map(Socks4Listener)
+        Socks4Stream::connect_raw(Command::Bind, proxy, target, userid, None)
+            .map(Socks4Listener::new)
     }
 
     /// The address of the proxy-side TCP listener.
@@ -177,11 +459,11 @@ impl Socks4Listener {
     /// This should be forwarded to the remote process, which should open a
     /// connection to it.
     pub fn proxy_addr(&self) -> io::Result<SocketAddr> {
-        if self.0.proxy_addr.ip().octets() != [0, 0, 0, 0] {
-            Ok(SocketAddr::V4(self.0.proxy_addr()))
+        if !self.stream.proxy_addr.ip().is_unspecified() {
+            Ok(SocketAddr::V4(self.stream.proxy_addr()))
         } else {
-            let port = self.0.proxy_addr.port();
-            let peer = match self.0.socket.peer_addr()? {
+            let port = self.stream.proxy_addr.port();
+            let peer = match self.stream.socket.peer_addr()? {
                 SocketAddr::V4(addr) => SocketAddr::V4(SocketAddrV4::new(*addr.ip(), port)),
                 SocketAddr::V6(addr) => SocketAddr::V6(SocketAddrV6::new(*addr.ip(), port, 0, 0)),
             };
@@ -189,20 +471,111 @@ impl Socks4Listener {
         }
     }
 
+    /// Returns the address of the proxy-side TCP listener exactly as reported
+    /// by the proxy.
+    ///
+    /// Unlike `proxy_addr`, an unspecified address is returned as is.
+    pub fn bound_addr(&self) -> SocketAddrV4 {
+        self.stream.proxy_addr
+    }
+
+    /// Returns the address of the proxy-side TCP listener formatted as the
+    /// argument of an FTP `PORT` command.
+    ///
+    /// `PORT` only supports IPv4 addresses; use `eprt_argument` if the proxy
+    /// may be reached over IPv6.
+    pub fn port_argument(&self) -> io::Result<String> {
+        ftp::checked_port_argument(&self.proxy_addr()?)
+    }
+
+    /// Returns the address of the proxy-side TCP listener formatted as the
+    /// argument of an FTP `EPRT` command.
+    pub fn eprt_argument(&self) -> io::Result<String> {
+        Ok(ftp::eprt_argument(&self.proxy_addr()?))
+    }
+
+    /// Sets whether `accept` checks that the incoming connection comes from
+    /// the `target` passed to `bind`, rather than trusting the proxy's
+    /// filtering.
+    ///
+    /// Only IP addresses are compared, since the remote process's port is
+    /// typically ephemeral. A domain name target is resolved locally.
+    /// Defaults to `false`.
+    pub fn set_verify_peer(&mut self, verify_peer: bool) {
+        self.verify_peer = verify_peer;
+    }
+
     /// Waits for the remote process to connect to the proxy server.
     ///
     /// The value of `proxy_addr` should be forwarded to the remote process
     /// before this method is called.
     pub fn accept(mut self) -> io::Result<Socks4Stream> {
-        self.0.proxy_addr = read_response(&mut self.0.socket)?;
-        Ok(self.0)
+        let peer = read_response(&mut self.stream.socket)?;
+        if self.verify_peer {
+            check_bind_peer(&self.stream.target, &TargetAddr::Ip(SocketAddr::V4(peer)))?;
+        }
+        self.stream.bound_addr = Some(self.stream.proxy_addr);
+        self.stream.proxy_addr = peer;
+        Ok(self.stream)
+    }
+
+    /// Like `accept`, but gives up if the remote process has not connected
+    /// within `timeout`.
+    ///
+    /// On timeout, the listener is returned so that the caller can either
+    /// wait again or drop it to abandon the BIND. The timeout covers the
+    /// whole of the proxy's reply. A zero `timeout` is rejected, as with
+    /// `TcpStream::set_read_timeout`.
+    pub fn accept_timeout(self,
+                          timeout: Duration)
+                          -> io::Result<Result<Socks4Stream, Socks4Listener>> {
+        if timeout == Duration::from_secs(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero timeout"));
+        }
+
+        let deadline = Instant::now() + timeout;
+        let old_timeout = self.stream.socket.read_timeout()?;
+        let ready = self.wait_for_reply(deadline);
+        self.stream.socket.set_read_timeout(old_timeout)?;
+
+        if ready? {
+            self.accept().map(Ok)
+        } else {
+            Ok(Err(self))
+        }
+    }
+
+    // Waits until the whole reply can be read without blocking, returning
+    // `false` if `deadline` passes first. None of the reply is consumed, so
+    // that a timeout leaves the control stream intact.
+    fn wait_for_reply(&self, deadline: Instant) -> io::Result<bool> {
+        let mut reply = [0; 8];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            self.stream.socket.set_read_timeout(Some(deadline - now))?;
+            match self.stream.socket.peek(&mut reply) {
+                // an early EOF is reported by `accept`
+                Ok(0) | Ok(8) => return Ok(true),
+                // the rest of the reply is still in flight
+                Ok(_) => thread::sleep(Duration::from_millis(1)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{Read, Write};
-    use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs, TcpStream};
+    use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
 
     use super::*;
 
@@ -247,6 +620,32 @@ mod test {
         assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
     }
 
+    #[test]
+    fn userid_validation() {
+        let mut builder = Socks4Builder::new();
+        builder.userid("foo").unwrap();
+        assert_eq!(builder.get_userid(), "foo");
+
+        let err = builder.userid("foo\0bar").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(builder.get_userid(), "foo");
+
+        let err = Socks4Stream::connect("127.0.0.1:1", "127.0.0.1:80", "foo\0bar").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn max_domain_len() {
+        let mut builder = Socks4Builder::new();
+        builder.max_domain_len(10);
+        let err = builder.connect("127.0.0.1:1", ("example.com", 80)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::DomainTooLong { len: 11, max: 10, .. }) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
     #[test]
     fn bind() {
         // First figure out our local address that we'll be connecting from
@@ -263,4 +662,81 @@ mod test {
         end.read_to_end(&mut result).unwrap();
         assert_eq!(result, b"hello world");
     }
+
+    #[test]
+    fn accept_timeout() {
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut socket = proxy.accept().unwrap().0;
+            let mut request = [0; 9];
+            socket.read_exact(&mut request).unwrap();
+            socket.write_all(&[0, 90, 0x10, 0x00, 10, 0, 0, 1]).unwrap();
+            socket.write_all(&[0, 90, 0x04, 0x38]).unwrap();
+            rx.recv().unwrap();
+            socket.write_all(&[10, 0, 0, 2]).unwrap();
+            socket
+        });
+
+        let listener = Socks4Listener::bind(proxy_addr, "10.0.0.2:0", "").unwrap();
+        // half of the reply arrives before the deadline
+        let listener = listener.accept_timeout(Duration::from_millis(50)).unwrap().unwrap_err();
+        assert_eq!(listener.proxy_addr().unwrap(), "10.0.0.1:4096".parse::<SocketAddr>().unwrap());
+
+        tx.send(()).unwrap();
+        assert_eq!(listener.bound_addr(), "10.0.0.1:4096".parse().unwrap());
+        let stream = listener.accept_timeout(Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!(stream.proxy_addr(), "10.0.0.2:1080".parse().unwrap());
+        assert_eq!(stream.bound_addr(), Some("10.0.0.1:4096".parse().unwrap()));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn local_resolution() {
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut socket = proxy.accept().unwrap().0;
+            let mut request = [0; 9];
+            socket.read_exact(&mut request).unwrap();
+            socket.write_all(&[0, 90, 0, 80, 10, 0, 0, 1]).unwrap();
+            request
+        });
+
+        let mut builder = Socks4Builder::new();
+        builder.resolver(move |host: &str, port| {
+            match host {
+                "proxy.test" => Ok(vec![SocketAddr::new(proxy_addr.ip(), port)]),
+                _ => Ok(vec!["[::1]:80".parse().unwrap(), SocketAddr::from(([10, 0, 0, 1], port))]),
+            }
+        });
+        builder.connect(("proxy.test", proxy_addr.port()), "example.com:80").unwrap();
+        assert_eq!(server.join().unwrap(), [4, 1, 0, 80, 10, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn shared_vectored_io() {
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut socket = proxy.accept().unwrap().0;
+            let mut request = [0; 9];
+            socket.read_exact(&mut request).unwrap();
+            socket.write_all(&[0, 90, 0, 80, 10, 0, 0, 1]).unwrap();
+            let mut buf = [0; 11];
+            socket.read_exact(&mut buf).unwrap();
+            socket.write_all(&buf).unwrap();
+        });
+
+        let stream = Socks4Stream::connect(proxy_addr, "10.0.0.1:80", "").unwrap();
+        let len = (&stream).write_vectored(&[IoSlice::new(b"hello "), IoSlice::new(b"world")])
+            .unwrap();
+        assert_eq!(len, 11);
+        let (mut first, mut second) = ([0; 6], [0; 5]);
+        (&stream).read_exact(&mut first).unwrap();
+        (&stream).read_exact(&mut second).unwrap();
+        assert_eq!((&first, &second), (b"hello ", b"world"));
+        server.join().unwrap();
+    }
 }
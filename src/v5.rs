@@ -1,23 +1,52 @@
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use std::borrow::Cow;
 use std::cmp;
-use std::io::{self, Read, Write};
-use std::net::{SocketAddr, ToSocketAddrs, SocketAddrV4, SocketAddrV6, TcpStream, Ipv4Addr,
-               Ipv6Addr, UdpSocket};
-use std::ptr;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+#[cfg(windows)]
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs, SocketAddrV4, SocketAddrV6, TcpStream,
+               Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::ops::Range;
+#[cfg(windows)]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use {ToTargetAddr, TargetAddr};
+use ftp;
+use server::ReplyCode;
+use resolver::resolve;
+use {BeforeConnect, ConnectError, ConnectErrorKind, ConnectTiming, Error, Resolver, SocketHandle,
+     SystemResolver, ToTargetAddr, TargetAddr, check_bind_peer, check_domain_len, connect_proxy,
+     domain_too_long, encode_domain, set_traffic_class, socket_hook};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(unix)]
+use socket2::MaybeUninitSlice;
 use writev::WritevExt;
+#[cfg(unix)]
+use libc;
+#[cfg(feature = "chap")]
+use chap;
+#[cfg(feature = "transcript")]
+use transcript::Recorder;
+#[cfg(feature = "rustls")]
+use tls::ProxyTls;
 
-const MAX_ADDR_LEN: usize = 260;
+pub(crate) const MAX_ADDR_LEN: usize = 260;
 
-fn read_addr<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
-    match socket.read_u8()? {
-        1 => {
+pub(crate) fn read_addr<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
+    match AddressType::from(socket.read_u8()?) {
+        AddressType::Ipv4 => {
             let ip = Ipv4Addr::from(socket.read_u32::<BigEndian>()?);
             let port = socket.read_u16::<BigEndian>()?;
             Ok(TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port))))
         }
-        3 => {
+        AddressType::Domain => {
             let len = socket.read_u8()?;
             let mut domain = vec![0; len as usize];
             socket.read_exact(&mut domain)?;
@@ -26,34 +55,26 @@ fn read_addr<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
             let port = socket.read_u16::<BigEndian>()?;
             Ok(TargetAddr::Domain(domain, port))
         }
-        4 => {
+        AddressType::Ipv6 => {
             let mut ip = [0; 16];
             socket.read_exact(&mut ip)?;
             let ip = Ipv6Addr::from(ip);
             let port = socket.read_u16::<BigEndian>()?;
             Ok(TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))))
         }
-        _ => Err(io::Error::new(io::ErrorKind::Other, "unsupported address type")),
+        AddressType::Other(_) => Err(io::Error::other("unsupported address type")),
     }
 }
 
-fn read_response(socket: &mut TcpStream) -> io::Result<TargetAddr> {
+fn read_response<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
 
     if socket.read_u8()? != 5 {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
     }
 
-    match socket.read_u8()? {
-        0 => {}
-        1 => return Err(io::Error::new(io::ErrorKind::Other, "general SOCKS server failure")),
-        2 => return Err(io::Error::new(io::ErrorKind::Other, "connection not allowed by ruleset")),
-        3 => return Err(io::Error::new(io::ErrorKind::Other, "network unreachable")),
-        4 => return Err(io::Error::new(io::ErrorKind::Other, "host unreachable")),
-        5 => return Err(io::Error::new(io::ErrorKind::Other, "connection refused")),
-        6 => return Err(io::Error::new(io::ErrorKind::Other, "TTL expired")),
-        7 => return Err(io::Error::new(io::ErrorKind::Other, "command not supported")),
-        8 => return Err(io::Error::new(io::ErrorKind::Other, "address kind not supported")),
-        _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
+    match ReplyCode::from(socket.read_u8()?) {
+        ReplyCode::Succeeded => {}
+        code => return Err(io::Error::other(Error::Socks5Reply { code })),
     }
 
     if socket.read_u8()? != 0 {
@@ -63,26 +84,202 @@ fn read_response(socket: &mut TcpStream) -> io::Result<TargetAddr> {
     read_addr(socket)
 }
 
-fn write_addr(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
+// Reads a reply, validating it as configured.
+pub(crate) fn read_reply<R: Read>(socket: &mut R,
+                                  validation: Validation)
+                                  -> io::Result<TargetAddr> {
+    match validation {
+        Validation::Standard => read_response(socket),
+        Validation::Strict => read_response_strict(socket),
+        Validation::Lenient => read_response_lenient(socket),
+    }
+}
+
+fn read_response_lenient<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
+    if socket.read_u8()? != 5 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
+    }
+
+    match ReplyCode::from(socket.read_u8()?) {
+        ReplyCode::Succeeded => {}
+        code => return Err(io::Error::other(Error::Socks5Reply { code })),
+    }
+
+    // some proxies don't zero the reserved byte
+    socket.read_u8()?;
+
+    read_addr(socket)
+}
+
+// Returns the length of the reply starting with `reply`, as far as it can be
+// told from the bytes read so far. Malformed and failure replies end as soon
+// as they can be reported as such.
+fn reply_len(reply: &[u8]) -> usize {
+    if reply.first().is_some_and(|&ver| ver != 5) ||
+       reply.get(1).is_some_and(|&code| code != 0) {
+        return reply.len();
+    }
+    match reply.get(3).map(|&atyp| AddressType::from(atyp)) {
+        Some(AddressType::Ipv4) => 10,
+        Some(AddressType::Domain) => reply.get(4).map_or(5, |&len| 7 + len as usize),
+        Some(AddressType::Ipv6) => 22,
+        Some(AddressType::Other(_)) => 4,
+        None => 4,
+    }
+}
+
+// In lenient mode, replaces an unspecified bound address with the proxy's own
+// address, which is what proxies reporting one usually mean.
+fn substitute_unspecified(validation: Validation,
+                          addr: TargetAddr,
+                          proxy: Option<SocketAddr>)
+                          -> TargetAddr {
+    match (validation, addr, proxy) {
+        (Validation::Lenient, TargetAddr::Ip(addr), Some(proxy)) if addr.ip().is_unspecified() => {
+            TargetAddr::Ip(SocketAddr::new(proxy.ip(), addr.port()))
+        }
+        (_, addr, _) => addr,
+    }
+}
+
+fn read_response_strict<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
+    let mut buf = [0; MAX_ADDR_LEN + 3];
+    let mut len = 0;
+    loop {
+        match socket.read(&mut buf[len..])? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated reply")),
+            n => len += n,
+        }
+
+        // let malformed and failure replies be reported as such
+        if buf[0] != 5 || (len >= 2 && buf[1] != 0) {
+            break;
+        }
+        let expected = match buf[..len].get(3).map(|&atyp| AddressType::from(atyp)) {
+            Some(AddressType::Ipv4) => 10,
+            Some(AddressType::Domain) if len > 4 => 7 + buf[4] as usize,
+            Some(AddressType::Ipv6) => 22,
+            Some(AddressType::Domain) | None => continue,
+            Some(AddressType::Other(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported address type"))
+            }
+        };
+        if len > expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected data after reply"));
+        }
+        if len == expected {
+            break;
+        }
+    }
+
+    let addr = read_response(&mut &buf[..len])?;
+    if let TargetAddr::Domain(ref domain, _) = addr {
+        if domain.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty bound domain name"));
+        }
+    }
+    Ok(addr)
+}
+
+/// A well-known SOCKS5 client whose greeting can be imitated by a
+/// `Socks5Builder`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Fingerprint {
+    /// curl, which offers "no authentication" followed by username/password
+    /// authentication if a password is configured.
+    Curl,
+    /// Firefox, which offers username/password authentication alone if a
+    /// password is configured, and "no authentication" otherwise.
+    Firefox,
+}
+
+impl Fingerprint {
+    fn methods(self, password: bool) -> Vec<AuthMethod> {
+        match (self, password) {
+            (Fingerprint::Curl, true) => vec![AuthMethod::None, AuthMethod::Password],
+            (Fingerprint::Firefox, true) => vec![AuthMethod::Password],
+            (_, false) => vec![AuthMethod::None],
+        }
+    }
+}
+
+/// What to do if a SOCKS5 proxy selects "no authentication" although
+/// credentials were offered.
+///
+/// A proxy which is expected to require authentication but doesn't may be
+/// misconfigured or an impostor. Since "no authentication" is offered
+/// alongside credentials by default, such a downgrade is otherwise
+/// indistinguishable from a normal connection. `Socks5Builder::require_auth`
+/// can be used instead to not offer "no authentication" at all.
+#[derive(Clone, Default)]
+pub enum DowngradePolicy {
+    /// Continue with the unauthenticated session.
+    #[default]
+    Proceed,
+    /// Call the function with the target address, then continue with the
+    /// unauthenticated session.
+    Warn(Arc<dyn Fn(&TargetAddr) + Sync + Send>),
+    /// Fail with a `PermissionDenied` error wrapping `Error::AuthDowngrade`
+    /// before the request is sent.
+    Fail,
+}
+
+impl fmt::Debug for DowngradePolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DowngradePolicy::Proceed => fmt.write_str("Proceed"),
+            DowngradePolicy::Warn(_) => fmt.write_str("Warn(..)"),
+            DowngradePolicy::Fail => fmt.write_str("Fail"),
+        }
+    }
+}
+
+/// How strictly replies from a SOCKS5 proxy are validated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum Validation {
+    /// Replies must be well formed: the version and reserved bytes must be
+    /// correct, and the address type must be known.
+    #[default]
+    Standard,
+    /// In addition to the standard checks, a reply must not be followed by
+    /// any other data in the same read, and a bound domain name must not be
+    /// empty.
+    ///
+    /// This is intended for use with untrusted or buggy proxies. Since data
+    /// relayed from the target can immediately follow the reply, it should
+    /// not be used with protocols in which the server speaks first.
+    Strict,
+    /// Tolerates quirks of some older proxies, such as old Dante and srelay
+    /// builds.
+    ///
+    /// A nonzero reserved byte in replies is ignored, and an unspecified
+    /// (`0.0.0.0` or `::`) bound address is replaced by the address of the
+    /// proxy itself. Data from the target arriving in the same packet as the
+    /// reply is always tolerated outside of strict mode.
+    Lenient,
+}
+
+pub(crate) fn write_addr(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
     let start_len = packet.len();
     match *target {
         TargetAddr::Ip(SocketAddr::V4(addr)) => {
-            packet.write_u8(1).unwrap();
+            packet.write_u8(AddressType::Ipv4.into()).unwrap();
             packet.write_u32::<BigEndian>((*addr.ip()).into()).unwrap();
             packet.write_u16::<BigEndian>(addr.port()).unwrap();
         }
         TargetAddr::Ip(SocketAddr::V6(addr)) => {
-            packet.write_u8(4).unwrap();
+            packet.write_u8(AddressType::Ipv6.into()).unwrap();
             packet.write_all(&addr.ip().octets()).unwrap();
             packet.write_u16::<BigEndian>(addr.port()).unwrap();
         }
         TargetAddr::Domain(ref domain, port) => {
-            packet.write_u8(3).unwrap();
-            if domain.len() > u8::max_value() as usize {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, "domain name too long"));
+            let ascii = encode_domain(domain)?;
+            packet.write_u8(AddressType::Domain.into()).unwrap();
+            if ascii.len() > u8::MAX as usize {
+                return Err(domain_too_long(domain, ascii.len(), u8::MAX as usize));
             }
-            packet.write_u8(domain.len() as u8).unwrap();
-            packet.write_all(domain.as_bytes()).unwrap();
+            packet.write_u8(ascii.len() as u8).unwrap();
+            packet.write_all(ascii.as_bytes()).unwrap();
             packet.write_u16::<BigEndian>(port).unwrap();
         }
     }
@@ -90,589 +287,4052 @@ fn write_addr(mut packet: &mut [u8], target: &TargetAddr) -> io::Result<usize> {
     Ok(start_len - packet.len())
 }
 
-/// Authentication methods
-#[derive(Debug)]
-enum Authentication<'a> {
-    Password { username: &'a str, password: &'a str },
-    None
+fn unspecified_addr() -> TargetAddr {
+    TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)))
 }
 
-impl<'a> Authentication<'a> {
-    fn id(&self) -> u8 {
-        match *self {
-            Authentication::Password { .. } => 2,
-            Authentication::None => 0
-        }
+// Checks that a username and password can be encoded in an RFC 1929
+// request.
+pub(crate) fn check_password(username: &str, password: &str) -> io::Result<()> {
+    if username.is_empty() || username.len() > 255 {
+        let err = Error::InvalidUsername { len: username.len() };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
     }
+    if password.is_empty() || password.len() > 255 {
+        let err = Error::InvalidPassword { len: password.len() };
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+    }
+    Ok(())
+}
 
-    fn is_no_auth(&self) -> bool {
-        if let Authentication::None = *self {
-            true
-        } else {
-            false
+// Negotiates an authentication method, offering those in `auth` in order,
+// and authenticates with it, applying the downgrade policy of `config`.
+fn authenticate<S>(socket: &mut S,
+                   auth: &[Authentication],
+                   target: &TargetAddr,
+                   config: &Socks5Builder)
+                   -> io::Result<()>
+    where S: Read + Write
+{
+    socket.write_all(&greeting(auth))?;
+    let selected = select_method(socket, auth)?;
+    check_downgrade(auth, selected, target, &config.downgrade)
+}
+
+// Applies a downgrade policy if the proxy selected "no authentication" even
+// though credentials were offered.
+fn check_downgrade(auth: &[Authentication],
+                   selected: AuthMethod,
+                   target: &TargetAddr,
+                   policy: &DowngradePolicy)
+                   -> io::Result<()> {
+    if selected != AuthMethod::None || auth.iter().all(|a| a.id() == 0) {
+        return Ok(());
+    }
+    match *policy {
+        DowngradePolicy::Proceed => Ok(()),
+        DowngradePolicy::Warn(ref warn) => {
+            warn(target);
+            Ok(())
+        }
+        DowngradePolicy::Fail => {
+            let advertised = auth.iter().map(|a| AuthMethod::from(a.id())).collect();
+            Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                               Error::AuthDowngrade { advertised }))
         }
     }
 }
 
-/// A SOCKS5 client.
-#[derive(Debug)]
-pub struct Socks5Stream {
-    socket: TcpStream,
-    proxy_addr: TargetAddr,
+fn greeting(auth: &[Authentication]) -> Vec<u8> {
+    let mut packet = vec![
+        5, // protocol version
+        auth.len() as u8, // method count
+    ];
+    packet.extend(auth.iter().map(Authentication::id)); // methods
+    packet
 }
 
-impl Socks5Stream {
-    /// Connects to a target server through a SOCKS5 proxy.
-    pub fn connect<T, U>(proxy: T, target: U) -> io::Result<Socks5Stream>
-        where T: ToSocketAddrs,
-              U: ToTargetAddr
-    {
-        Self::connect_raw(1, proxy, target, &Authentication::None)
+// Reads the method selected by the proxy, and authenticates with it.
+fn select_method<S>(socket: &mut S, auth: &[Authentication]) -> io::Result<AuthMethod>
+    where S: Read + Write
+{
+    let mut buf = [0; 2];
+    socket.read_exact(&mut buf)?;
+    let response_version = buf[0];
+    let selected_method = buf[1];
+
+    if response_version != 5 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
     }
 
-    /// Connects to a target server through a SOCKS5 proxy using given
-    /// username and password.
-    pub fn connect_with_password<T, U>(proxy: T, target: U, username: &str, password: &str) -> io::Result<Socks5Stream>
-        where T: ToSocketAddrs,
-              U: ToTargetAddr
-    {
-        let auth = Authentication::Password { username, password };
-        Self::connect_raw(1, proxy, target, &auth)
+    let advertised = || auth.iter().map(|a| AuthMethod::from(a.id())).collect();
+
+    if selected_method == 0xff {
+        let err = Error::NoAcceptableAuthMethod { advertised: advertised() };
+        return Err(io::Error::other(err))
     }
 
-    fn connect_raw<T, U>(command: u8, proxy: T, target: U, auth: &Authentication) -> io::Result<Socks5Stream>
-        where T: ToSocketAddrs,
-              U: ToTargetAddr
-    {
-        let mut socket = TcpStream::connect(proxy)?;
+    match auth.iter().find(|a| a.id() == selected_method) {
+        Some(&Authentication::None) => {}
+        Some(&Authentication::Password { username, password }) => {
+            Socks5Stream::password_authentication(socket, username, password)?
+        }
+        #[cfg(feature = "chap")]
+        Some(&Authentication::Chap { username, secret }) => {
+            chap::authenticate(socket, username, secret)?
+        }
+        None => {
+            let err = Error::UnexpectedAuthMethod {
+                selected: AuthMethod::from(selected_method),
+                advertised: advertised(),
+            };
+            return Err(io::Error::other(err));
+        }
+    }
+    Ok(AuthMethod::from(selected_method))
+}
 
-        let target = target.to_target_addr()?;
+// Sends a request and returns the address from the proxy's reply.
+fn request<S: Read + Write>(socket: &mut S,
+                            command: Command,
+                            target: &TargetAddr,
+                            validation: Validation)
+                            -> io::Result<TargetAddr> {
+    let mut packet = [0; MAX_ADDR_LEN + 3];
+    let len = encode_request(&mut packet, command, target)?;
+    socket.write_all(&packet[..len])?;
 
-        let packet_len = if auth.is_no_auth() { 3 } else { 4 };
-        let packet = [
-            5, // protocol version
-            if auth.is_no_auth() { 1 } else { 2 }, // method count
-            auth.id(), // method
-            0, // no auth (always offered)
-        ];
-        socket.write_all(&packet[..packet_len])?;
+    read_reply(socket, validation)
+}
 
-        let mut buf = [0; 2];
-        socket.read_exact(&mut buf)?;
-        let response_version = buf[0];
-        let selected_method = buf[1];
+fn encode_request(packet: &mut [u8; MAX_ADDR_LEN + 3],
+                  command: Command,
+                  target: &TargetAddr)
+                  -> io::Result<usize> {
+    packet[0] = 5; // protocol version
+    packet[1] = command.into(); // command
+    packet[2] = 0; // reserved
+    let len = write_addr(&mut packet[3..], target)?;
+    Ok(len + 3)
+}
 
-        if response_version != 5 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
-        }
+/// A SOCKS5 authentication method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    /// No authentication.
+    None,
+    /// GSSAPI authentication (RFC 1961).
+    Gssapi,
+    /// Username/password authentication (RFC 1929).
+    Password,
+    /// Challenge-handshake authentication.
+    Chap,
+    /// A method not known to this crate.
+    Other(u8),
+}
 
-        if selected_method == 0xff {
-            return Err(io::Error::new(io::ErrorKind::Other, "no acceptable auth methods"))
+impl From<u8> for AuthMethod {
+    fn from(id: u8) -> AuthMethod {
+        match id {
+            0 => AuthMethod::None,
+            1 => AuthMethod::Gssapi,
+            2 => AuthMethod::Password,
+            3 => AuthMethod::Chap,
+            id => AuthMethod::Other(id),
         }
+    }
+}
 
-        if selected_method != auth.id() && selected_method != Authentication::None.id() {
-            return Err(io::Error::new(io::ErrorKind::Other, "unknown auth method"))
-        }
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for AuthMethod {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<AuthMethod> {
+        u8::arbitrary(u).map(AuthMethod::from)
+    }
+}
 
-        match *auth {
-            Authentication::Password { username, password } if selected_method == auth.id() => {
-                Self::password_authentication(&mut socket, username, password)?
-            },
-            _ => ()
+impl From<AuthMethod> for u8 {
+    fn from(method: AuthMethod) -> u8 {
+        match method {
+            AuthMethod::None => 0,
+            AuthMethod::Gssapi => 1,
+            AuthMethod::Password => 2,
+            AuthMethod::Chap => 3,
+            AuthMethod::Other(id) => id,
         }
+    }
+}
 
-        let mut packet = [0; MAX_ADDR_LEN + 3];
-        packet[0] = 5; // protocol version
-        packet[1] = command; // command
-        packet[2] = 0; // reserved
-        let len = write_addr(&mut packet[3..], &target)?;
-        socket.write_all(&packet[..len + 3])?;
+/// A SOCKS5 command.
+///
+/// SOCKS4 uses the same values for the commands it supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// Connect to a target.
+    Connect,
+    /// Listen for a connection from a target.
+    Bind,
+    /// Relay UDP messages.
+    UdpAssociate,
+    /// A command not known to this crate.
+    Other(u8),
+}
 
-        let proxy_addr = read_response(&mut socket)?;
+impl From<u8> for Command {
+    fn from(id: u8) -> Command {
+        match id {
+            1 => Command::Connect,
+            2 => Command::Bind,
+            3 => Command::UdpAssociate,
+            id => Command::Other(id),
+        }
+    }
+}
 
-        Ok(Socks5Stream {
-            socket: socket,
-            proxy_addr: proxy_addr,
-        })
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for Command {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Command> {
+        u8::arbitrary(u).map(Command::from)
     }
+}
 
-    fn password_authentication(socket: &mut TcpStream, username: &str, password: &str) -> io::Result<()> {
-        if username.len() < 1 || username.len() > 255 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid username"))
-        };
-        if password.len() < 1 || password.len() > 255 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid password"))
+impl From<Command> for u8 {
+    fn from(command: Command) -> u8 {
+        match command {
+            Command::Connect => 1,
+            Command::Bind => 2,
+            Command::UdpAssociate => 3,
+            Command::Other(id) => id,
         }
+    }
+}
 
-        let mut packet = [0; 515];
-        let packet_size = 3 + username.len() + password.len();
-        packet[0] = 1; // version
-        packet[1] = username.len() as u8;
-        packet[2..2 + username.len()].copy_from_slice(username.as_bytes());
-        packet[2 + username.len()] = password.len() as u8;
-        packet[3 + username.len()..packet_size].copy_from_slice(password.as_bytes());
-        socket.write_all(&packet[..packet_size])?;
+/// The type of an address in a SOCKS5 request, reply, or UDP header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressType {
+    /// An IPv4 address.
+    Ipv4,
+    /// A domain name.
+    Domain,
+    /// An IPv6 address.
+    Ipv6,
+    /// An address type not known to this crate.
+    Other(u8),
+}
 
-        let mut buf = [0; 2];
-        socket.read_exact(&mut buf)?;
-        if buf[0] != 1 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
-        }
-        if buf[1] != 0 {
-            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "password authentication failed"));
+impl From<u8> for AddressType {
+    fn from(id: u8) -> AddressType {
+        match id {
+            1 => AddressType::Ipv4,
+            3 => AddressType::Domain,
+            4 => AddressType::Ipv6,
+            id => AddressType::Other(id),
         }
+    }
+}
 
-        Ok(())
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for AddressType {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<AddressType> {
+        u8::arbitrary(u).map(AddressType::from)
     }
+}
 
-    /// Returns the proxy-side address of the connection between the proxy and
-    /// target server.
-    pub fn proxy_addr(&self) -> &TargetAddr {
-        &self.proxy_addr
+impl From<AddressType> for u8 {
+    fn from(address_type: AddressType) -> u8 {
+        match address_type {
+            AddressType::Ipv4 => 1,
+            AddressType::Domain => 3,
+            AddressType::Ipv6 => 4,
+            AddressType::Other(id) => id,
+        }
     }
+}
 
-    /// Returns a shared reference to the inner `TcpStream`.
-    pub fn get_ref(&self) -> &TcpStream {
-        &self.socket
+/// Authentication methods
+#[derive(Debug)]
+enum Authentication<'a> {
+    Password { username: &'a str, password: &'a str },
+    #[cfg(feature = "chap")]
+    Chap { username: &'a str, secret: &'a [u8] },
+    None
+}
+
+impl<'a> Authentication<'a> {
+    fn id(&self) -> u8 {
+        let method = match *self {
+            Authentication::Password { .. } => AuthMethod::Password,
+            #[cfg(feature = "chap")]
+            Authentication::Chap { .. } => AuthMethod::Chap,
+            Authentication::None => AuthMethod::None,
+        };
+        method.into()
     }
+}
 
-    /// Returns a mutable reference to the inner `TcpStream`.
-    pub fn get_mut(&mut self) -> &mut TcpStream {
-        &mut self.socket
+/// Credentials for a SOCKS5 authentication method.
+///
+/// The set of variants depends on the enabled Cargo features, so matches
+/// must include a wildcard arm.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum Credentials {
+    /// Username/password authentication (RFC 1929).
+    Password {
+        /// The username.
+        username: String,
+        /// The password.
+        password: String,
+    },
+    /// Challenge-handshake authentication.
+    ///
+    /// Requires the `chap` Cargo feature.
+    #[cfg(feature = "chap")]
+    Chap {
+        /// The user name.
+        username: String,
+        /// The shared secret.
+        secret: Vec<u8>,
+    },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // don't leak secrets
+        match *self {
+            Credentials::Password { ref username, .. } => {
+                fmt.debug_struct("Password")
+                    .field("username", username)
+                    .finish()
+            }
+            #[cfg(feature = "chap")]
+            Credentials::Chap { ref username, .. } => {
+                fmt.debug_struct("Chap")
+                    .field("username", username)
+                    .finish()
+            }
+        }
+    }
+}
+
+impl Credentials {
+    /// Returns the authentication method these credentials are used with.
+    pub fn method(&self) -> AuthMethod {
+        AuthMethod::from(self.as_auth().id())
     }
 
-    /// Consumes the `Socks5Stream`, returning the inner `TcpStream`.
-    pub fn into_inner(self) -> TcpStream {
-        self.socket
+    fn as_auth(&self) -> Authentication<'_> {
+        match *self {
+            Credentials::Password { ref username, ref password } => {
+                Authentication::Password { username, password }
+            }
+            #[cfg(feature = "chap")]
+            Credentials::Chap { ref username, ref secret } => {
+                Authentication::Chap { username, secret }
+            }
+        }
     }
 }
 
-impl Read for Socks5Stream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.socket.read(buf)
+// Replaces the credentials for the same method, if present, or appends them.
+fn set_credentials(list: &mut Vec<Credentials>, credentials: Credentials) {
+    match list.iter().position(|c| c.method() == credentials.method()) {
+        Some(i) => list[i] = credentials,
+        None => list.push(credentials),
     }
 }
 
-impl<'a> Read for &'a Socks5Stream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        (&self.socket).read(buf)
+// Reorders addresses to alternate between address families, starting with
+// the family of the first address.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
     }
 }
 
-impl Write for Socks5Stream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.socket.write(buf)
+// Determines if a connection attempt failed for a reason which doesn't
+// depend on the target: the proxy can't be reached, rejects our credentials,
+// or refuses by policy.
+fn fails_for_every_target(e: &io::Error) -> bool {
+    let kind = e.get_ref().and_then(|e| e.downcast_ref::<ConnectError>()).map(ConnectError::kind);
+    matches!(kind,
+             Some(ConnectErrorKind::ProxyUnreachable) |
+             Some(ConnectErrorKind::Auth) |
+             Some(ConnectErrorKind::NotAllowed))
+}
+
+// Returns the number of bytes which can be read from a socket, or zero if
+// the peer has closed the connection, without reading anything or changing
+// the socket's blocking mode. A `WouldBlock` error is returned if nothing
+// has arrived.
+#[cfg(windows)]
+fn pending_len(socket: &TcpStream) -> io::Result<usize> {
+    use std::os::windows::io::AsRawSocket;
+    use winapi::um::winsock2;
+
+    let revents = poll_now(socket, winsock2::POLLRDNORM)?;
+    if revents & (winsock2::POLLERR | winsock2::POLLNVAL) != 0 {
+        return Err(io::ErrorKind::ConnectionReset.into());
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.socket.flush()
+    let mut len = 0;
+    let socket = socket.as_raw_socket() as winsock2::SOCKET;
+    if unsafe { winsock2::ioctlsocket(socket, winsock2::FIONREAD, &mut len) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(len as usize)
 }
 
-impl<'a> Write for &'a Socks5Stream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        (&self.socket).write(buf)
+// Polls a socket for `events` without waiting, returning the events which
+// occurred, or a `WouldBlock` error if none did. Unlike switching the socket
+// to nonblocking mode, this leaves its blocking mode alone.
+#[cfg(windows)]
+fn poll_now<S: std::os::windows::io::AsRawSocket>(socket: &S, events: i16) -> io::Result<i16> {
+    use winapi::um::winsock2;
+
+    let mut fd = winsock2::WSAPOLLFD {
+        fd: socket.as_raw_socket() as winsock2::SOCKET,
+        events,
+        revents: 0,
+    };
+    if unsafe { winsock2::WSAPoll(&mut fd, 1, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if fd.revents == 0 {
+        return Err(io::ErrorKind::WouldBlock.into());
     }
+    Ok(fd.revents)
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        (&self.socket).flush()
+// Views an initialized buffer as a possibly uninitialized one, as taken by
+// `socket2`'s receive functions, which only ever write initialized bytes.
+#[cfg(unix)]
+fn as_uninit(buf: &mut [u8]) -> &mut [mem::MaybeUninit<u8>] {
+    unsafe { &mut *(buf as *mut [u8] as *mut [mem::MaybeUninit<u8>]) }
+}
+
+fn no_addresses() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+}
+
+type CredentialProvider = dyn Fn(&TargetAddr) -> Credentials + Sync + Send;
+
+/// A reusable SOCKS5 client configuration.
+///
+/// By default, each configured authentication method is offered to the proxy
+/// in addition to "no authentication", in the order in which they were
+/// configured. The `methods` method can be used to control exactly which
+/// methods are offered.
+#[derive(Clone, Default)]
+pub struct Socks5Builder {
+    credentials: Vec<Credentials>,
+    provider: Option<Arc<CredentialProvider>>,
+    methods: Option<Vec<AuthMethod>>,
+    require_auth: bool,
+    fingerprint: Option<Fingerprint>,
+    downgrade: DowngradePolicy,
+    validation: Validation,
+    max_domain_len: Option<usize>,
+    accept_timeout: Option<Duration>,
+    attempt_delay: Option<Duration>,
+    resolver: Option<Arc<dyn Resolver>>,
+    before_connect: Option<Arc<BeforeConnect>>,
+    traffic_class: Option<u32>,
+    #[cfg(feature = "rustls")]
+    pub(crate) proxy_tls: Option<ProxyTls>,
+}
+
+impl fmt::Debug for Socks5Builder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut fmt = fmt.debug_struct("Socks5Builder");
+        fmt.field("credentials", &self.credentials)
+            .field("provider", &self.provider.is_some())
+            .field("methods", &self.methods)
+            .field("require_auth", &self.require_auth)
+            .field("fingerprint", &self.fingerprint)
+            .field("downgrade", &self.downgrade)
+            .field("validation", &self.validation)
+            .field("max_domain_len", &self.max_domain_len)
+            .field("accept_timeout", &self.accept_timeout)
+            .field("attempt_delay", &self.attempt_delay)
+            .field("resolver", &self.resolver.is_some())
+            .field("before_connect", &self.before_connect.is_some())
+            .field("traffic_class", &self.traffic_class);
+        #[cfg(feature = "rustls")]
+        fmt.field("proxy_tls", &self.proxy_tls);
+        fmt.finish()
     }
 }
 
-/// A SOCKS5 BIND client.
-#[derive(Debug)]
-pub struct Socks5Listener(Socks5Stream);
+impl Socks5Builder {
+    /// Creates a new builder which only offers "no authentication".
+    pub fn new() -> Socks5Builder {
+        Socks5Builder::default()
+    }
 
-impl Socks5Listener {
-    /// Initiates a BIND request to the specified proxy.
+    /// Offers username/password authentication (RFC 1929) with the given
+    /// credentials.
+    pub fn password(&mut self, username: &str, password: &str) -> &mut Socks5Builder {
+        self.credentials(Credentials::Password {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+
+    /// Offers challenge-handshake authentication with the given user name
+    /// and shared secret.
     ///
-    /// The proxy will filter incoming connections based on the value of
-    /// `target`.
-    pub fn bind<T, U>(proxy: T, target: U) -> io::Result<Socks5Listener>
-        where T: ToSocketAddrs,
-              U: ToTargetAddr
-    {
-        Socks5Stream::connect_raw(2, proxy, target, &Authentication::None).map(Socks5Listener)
+    /// The challenge is answered with HMAC-MD5 keyed by the secret.
+    ///
+    /// Requires the `chap` Cargo feature.
+    #[cfg(feature = "chap")]
+    pub fn chap(&mut self, username: &str, secret: &[u8]) -> &mut Socks5Builder {
+        self.credentials(Credentials::Chap {
+            username: username.to_owned(),
+            secret: secret.to_owned(),
+        })
     }
-    /// Initiates a BIND request to the specified proxy using given username
-    /// and password.
+
+    /// Offers the given credentials, replacing any previously configured
+    /// credentials for the same method.
+    pub fn credentials(&mut self, credentials: Credentials) -> &mut Socks5Builder {
+        set_credentials(&mut self.credentials, credentials);
+        self
+    }
+
+    /// Sets a function which is called with the target address on each
+    /// connection to look up the credentials to offer.
     ///
-    /// The proxy will filter incoming connections based on the value of
-    /// `target`.
-    pub fn bind_with_password<T, U>(proxy: T, target: U, username: &str, password: &str) -> io::Result<Socks5Listener>
-        where T: ToSocketAddrs,
-              U: ToTargetAddr
+    /// This allows secrets to be fetched only when they are needed, or to
+    /// vary by destination. The returned credentials take precedence over any
+    /// statically configured credentials for the same method. For UDP
+    /// associations, the provider is passed the unspecified address.
+    pub fn credential_provider<F>(&mut self, provider: F) -> &mut Socks5Builder
+        where F: Fn(&TargetAddr) -> Credentials + Sync + Send + 'static
     {
-        let auth = Authentication::Password { username, password };
-        Socks5Stream::connect_raw(2, proxy, target, &auth).map(Socks5Listener)
+        self.provider = Some(Arc::new(provider));
+        self
     }
 
-    /// The address of the proxy-side TCP listener.
+    /// Sets the authentication methods offered to the proxy, in order of
+    /// preference.
     ///
-    /// This should be forwarded to the remote process, which should open a
-    /// connection to it.
-    pub fn proxy_addr(&self) -> &TargetAddr {
-        &self.0.proxy_addr
+    /// Exactly these methods are advertised, in the given order, with any
+    /// repeated methods dropped. In particular, "no authentication" is only
+    /// offered if `AuthMethod::None` is included. Credentials must be
+    /// configured for every other method in the list before connecting.
+    pub fn methods(&mut self, methods: &[AuthMethod]) -> &mut Socks5Builder {
+        self.methods = Some(methods.to_vec());
+        self
     }
 
-    /// Waits for the remote process to connect to the proxy server.
+    /// Determines if "no authentication" is never offered to the proxy.
     ///
-    /// The value of `proxy_addr` should be forwarded to the remote process
-    /// before this method is called.
-    pub fn accept(mut self) -> io::Result<Socks5Stream> {
-        self.0.proxy_addr = read_response(&mut self.0.socket)?;
-        Ok(self.0)
+    /// If set, "no authentication" is left out of the default method list,
+    /// and connecting fails with an `InvalidInput` error if it is included in
+    /// the list set by `methods`. This guarantees that sessions are never
+    /// unauthenticated, since a proxy selecting a method which was not
+    /// offered is an error. Defaults to `false`.
+    pub fn require_auth(&mut self, require_auth: bool) -> &mut Socks5Builder {
+        self.require_auth = require_auth;
+        self
     }
-}
 
-/// A SOCKS5 UDP client.
-#[derive(Debug)]
-pub struct Socks5Datagram {
-    socket: UdpSocket,
-    // keeps the session alive
-    stream: Socks5Stream,
-}
+    /// Offers authentication methods in the same way as a well-known client,
+    /// so that the greeting sent to the proxy is identical to that client's.
+    ///
+    /// The greeting is otherwise distinctive, since the default method order
+    /// differs from that of other clients. Only username/password credentials
+    /// are offered. A method list set by `methods` takes precedence.
+    pub fn fingerprint(&mut self, fingerprint: Fingerprint) -> &mut Socks5Builder {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Sets what happens if the proxy selects "no authentication" although
+    /// credentials were offered.
+    ///
+    /// Defaults to `DowngradePolicy::Proceed`.
+    pub fn downgrade_policy(&mut self, policy: DowngradePolicy) -> &mut Socks5Builder {
+        self.downgrade = policy;
+        self
+    }
+
+    /// Sets how strictly the proxy's replies are validated.
+    ///
+    /// Defaults to `Validation::Standard`.
+    pub fn validation(&mut self, validation: Validation) -> &mut Socks5Builder {
+        self.validation = validation;
+        self
+    }
+
+    /// Sets the maximum length in bytes of domain name targets.
+    ///
+    /// Connecting to a longer name fails with an `InvalidInput` error wrapping
+    /// `Error::DomainTooLong` before anything is sent to the proxy. Names can
+    /// never be longer than 255 bytes in a SOCKS5 request, so larger values
+    /// have no effect. Defaults to 255.
+    pub fn max_domain_len(&mut self, len: usize) -> &mut Socks5Builder {
+        self.max_domain_len = Some(len);
+        self
+    }
+
+    /// Sets the default timeout for `Socks5Listener::accept` on listeners
+    /// created by `bind`.
+    ///
+    /// Defaults to `None`, which waits indefinitely.
+    pub fn accept_timeout(&mut self, timeout: Option<Duration>) -> &mut Socks5Builder {
+        self.accept_timeout = timeout;
+        self
+    }
+
+    /// Races the addresses of targets connected to with `connect_each`,
+    /// starting a new attempt every `delay` rather than waiting for each
+    /// attempt to fail.
+    ///
+    /// This is the "happy eyeballs" algorithm of RFC 8305, applied to
+    /// connections through the proxy: the target's addresses are reordered
+    /// to alternate between IPv6 and IPv4, starting with the family of the
+    /// first address, and the first connection to succeed is kept while the
+    /// others are shut down. A new attempt is also started as soon as the
+    /// previous one fails. RFC 8305 recommends a delay of 250 milliseconds.
+    ///
+    /// Defaults to `None`, which tries each address in turn.
+    pub fn attempt_delay(&mut self, delay: Option<Duration>) -> &mut Socks5Builder {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// Sets the resolver used to look up the proxy's address and the targets
+    /// of `connect_each`.
+    ///
+    /// Targets are otherwise sent to the proxy to resolve. Defaults to
+    /// `SystemResolver`.
+    pub fn resolver<R>(&mut self, resolver: R) -> &mut Socks5Builder
+        where R: Resolver + 'static
+    {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Sets a function called with each socket opened to the proxy after it
+    /// is created but before it is connected.
+    ///
+    /// The function is passed the socket's handle and the proxy address it
+    /// is about to be connected to, and can set options which must be in
+    /// place before the connection is made, such as `SO_MARK` on Linux,
+    /// `VpnService.protect` on Android, or QoS markings. Returning an error
+    /// abandons that address of the proxy.
+    pub fn before_connect<F>(&mut self, f: F) -> &mut Socks5Builder
+        where F: Fn(SocketHandle<'_>, &SocketAddr) -> io::Result<()> + Sync + Send + 'static
+    {
+        self.before_connect = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets the `IP_TOS` or `IPV6_TCLASS` value of connections to the proxy,
+    /// depending on their address family, so that tunneled traffic can be
+    /// marked for QoS.
+    ///
+    /// The value is a whole TOS byte, so a DSCP code point must be shifted
+    /// left by two bits. It is set before the connection is made, ahead of
+    /// any `before_connect` hook. Setting an IPv6 traffic class is not
+    /// supported on all platforms. Defaults to `None`, which leaves the
+    /// operating system's default in place.
+    pub fn traffic_class(&mut self, class: Option<u32>) -> &mut Socks5Builder {
+        self.traffic_class = class;
+        self
+    }
+
+    // Returns the hook called with sockets before they are connected to the
+    // proxy.
+    pub(crate) fn socket_hook(&self) -> Option<Arc<BeforeConnect>> {
+        socket_hook(self.traffic_class, &self.before_connect)
+    }
+
+    // Resolves an address with the configured resolver.
+    fn resolve(&self, addr: &TargetAddr) -> io::Result<Vec<SocketAddr>> {
+        match self.resolver {
+            Some(ref resolver) => resolve(&**resolver, addr),
+            None => resolve(&SystemResolver, addr),
+        }
+    }
+
+    pub(crate) fn proxy_addrs<T: ToTargetAddr>(&self, proxy: T) -> io::Result<Vec<SocketAddr>> {
+        self.resolve(&proxy.to_target_addr()?)
+    }
+
+    // Returns an error if the proxy must be reached over TLS, which only
+    // `connect_tls_proxy` does.
+    fn check_plaintext(&self) -> io::Result<()> {
+        #[cfg(feature = "rustls")]
+        {
+            if self.proxy_tls.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "the proxy must be reached with connect_tls_proxy"));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_target(&self, target: &TargetAddr) -> io::Result<()> {
+        let limit = u8::MAX as usize;
+        let max = self.max_domain_len.map_or(limit, |len| cmp::min(len, limit));
+        check_domain_len(target, max)
+    }
+
+    fn credentials_for(&self, target: &TargetAddr) -> Cow<'_, [Credentials]> {
+        match self.provider {
+            Some(ref provider) => {
+                let mut credentials = self.credentials.clone();
+                set_credentials(&mut credentials, provider(target));
+                Cow::Owned(credentials)
+            }
+            None => Cow::Borrowed(&self.credentials),
+        }
+    }
+
+    fn auth<'a>(&self, credentials: &'a [Credentials]) -> io::Result<Vec<Authentication<'a>>> {
+        let methods = match self.methods {
+            Some(ref methods) => Some(Cow::Borrowed(&methods[..])),
+            None => {
+                let password = credentials.iter().any(|c| c.method() == AuthMethod::Password);
+                self.fingerprint.map(|f| Cow::Owned(f.methods(password)))
+            }
+        };
+        let mut auth = match methods {
+            Some(ref methods) => {
+                let mut auth = vec![];
+                for (i, &method) in methods.iter().enumerate() {
+                    if methods[..i].contains(&method) {
+                        continue;
+                    }
+                    if method == AuthMethod::None {
+                        auth.push(Authentication::None);
+                        continue;
+                    }
+
+                    match credentials.iter().find(|c| c.method() == method) {
+                        Some(credentials) => auth.push(credentials.as_auth()),
+                        None => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                      "no credentials configured for auth method"))
+                        }
+                    }
+                }
+                auth
+            }
+            None => credentials.iter().map(Credentials::as_auth).collect(),
+        };
+
+        if self.require_auth {
+            if auth.iter().any(|a| a.id() == 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "authentication is required"));
+            }
+        } else if methods.is_none() {
+            auth.push(Authentication::None);
+        }
+
+        if auth.is_empty() || auth.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "between 1 and 255 auth methods must be offered"));
+        }
+        Ok(auth)
+    }
+
+    fn connect_raw<T, U>(&self,
+                         command: Command,
+                         proxy: T,
+                         target: U)
+                         -> io::Result<Socks5Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        let target = target.to_target_addr()?;
+        self.check_target(&target)?;
+        let credentials = self.credentials_for(&target);
+        let auth = self.auth(&credentials)?;
+        Socks5Stream::connect_sending(command, proxy, target, &auth, &[], self)
+    }
+
+    // Connects to a target over a newly opened connection to the proxy,
+    // which took `tcp_connect` to open.
+    pub(crate) fn establish(&self,
+                            socket: TcpStream,
+                            target: TargetAddr,
+                            tcp_connect: Duration)
+                            -> io::Result<Socks5Stream> {
+        self.check_plaintext()?;
+        self.check_target(&target)?;
+        let credentials = self.credentials_for(&target);
+        let auth = self.auth(&credentials)?;
+        Socks5Stream::establish(socket, Command::Connect, target, &auth, &[], self, tcp_connect)
+    }
+
+    // Authenticates and sends a request over an established connection to
+    // the proxy, returning the address from the proxy's reply. `proxy` is the
+    // proxy's address, if known.
+    pub(crate) fn handshake<S>(&self,
+                               socket: &mut S,
+                               command: Command,
+                               target: &TargetAddr,
+                               proxy: Option<SocketAddr>)
+                               -> io::Result<TargetAddr>
+        where S: Read + Write
+    {
+        self.check_target(target)?;
+        let credentials = self.credentials_for(target);
+        authenticate(socket, &self.auth(&credentials)?, target, self)?;
+        let proxy_addr = request(socket, command, target, self.validation)?;
+        Ok(substitute_unspecified(self.validation, proxy_addr, proxy))
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy.
+    ///
+    /// The proxy's address is looked up with the configured resolver.
+    pub fn connect<T, U>(&self, proxy: T, target: U) -> io::Result<Socks5Stream>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        self.connect_raw(Command::Connect, &*self.proxy_addrs(proxy)?, target)
+    }
+
+    /// Connects to each of several target servers through a SOCKS5 proxy,
+    /// making up to `threads` connections concurrently.
+    ///
+    /// The proxy is resolved once up front, and an error is returned if that
+    /// fails. Otherwise, the result of each connection is returned in the
+    /// same order as `targets`.
+    ///
+    /// There is no asynchronous counterpart, since this crate does not depend
+    /// on an async runtime; async code can run this on a blocking task.
+    pub fn connect_many<T, U>(&self,
+                              proxy: T,
+                              targets: &[U],
+                              threads: usize)
+                              -> io::Result<Vec<io::Result<Socks5Stream>>>
+        where T: ToTargetAddr,
+              U: ToTargetAddr + Sync
+    {
+        let proxy = self.proxy_addrs(proxy)?;
+        let results = Mutex::new((0..targets.len()).map(|_| None).collect::<Vec<_>>());
+        let next = AtomicUsize::new(0);
+
+        thread::scope(|scope| -> io::Result<()> {
+            for _ in 0..cmp::min(cmp::max(threads, 1), targets.len()) {
+                thread::Builder::new()
+                    .name("socks-connect".to_owned())
+                    .spawn_scoped(scope, || {
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let target = match targets.get(i) {
+                                Some(target) => target,
+                                None => break,
+                            };
+                            let result = target.to_target_addr()
+                                .and_then(|target| {
+                                    self.connect_raw(Command::Connect, &*proxy, target)
+                                });
+                            results.lock().unwrap()[i] = Some(result);
+                        }
+                    })?;
+            }
+            Ok(())
+        })?;
+
+        let results = results.into_inner().unwrap();
+        Ok(results.into_iter().map(|r| r.expect("connection not attempted")).collect())
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, giving up if
+    /// `stop` is set.
+    ///
+    /// The connection is made on a separate thread while the calling thread
+    /// checks `stop` every 50 milliseconds, so that a hanging proxy can be
+    /// abandoned, for example when a signal handler sets the flag on Ctrl-C.
+    /// If it is set before the connection is established, an `Interrupted`
+    /// error is returned and the connection to the proxy is shut down,
+    /// including one which is only opened afterwards. The proxy is resolved
+    /// on the calling thread.
+    pub fn connect_interruptible<T, U>(&self,
+                                       proxy: T,
+                                       target: U,
+                                       stop: &AtomicBool)
+                                       -> io::Result<Socks5Stream>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let target = target.to_target_addr()?;
+        self.check_plaintext()?;
+        self.check_target(&target)?;
+        let proxy = self.proxy_addrs(proxy)?;
+
+        let (tx, rx) = mpsc::channel();
+        // The connection to the proxy once it is opened, and whether the
+        // caller has given up. Both are checked under one lock so that a
+        // connection which opens after `stop` is set is never used.
+        let state = Arc::new(Mutex::new((None, false)));
+        let builder = self.clone();
+        let hook = self.socket_hook();
+        let shared = state.clone();
+        thread::Builder::new()
+            .name("socks-connect".to_owned())
+            .spawn(move || {
+                let start = Instant::now();
+                let result = connect_proxy(&*proxy, hook.as_deref())
+                    .map_err(|(proxy, e)| ConnectError::unreachable(proxy, &target, e))
+                    .and_then(|socket| {
+                        let mut shared = shared.lock().unwrap();
+                        if shared.1 {
+                            let _ = socket.shutdown(Shutdown::Both);
+                            return Err(io::Error::new(io::ErrorKind::Interrupted,
+                                                      "connection interrupted"));
+                        }
+                        shared.0 = Some(socket.try_clone()?);
+                        drop(shared);
+                        builder.establish(socket, target, start.elapsed())
+                    });
+                let _ = tx.send(result);
+            })?;
+
+        loop {
+            match rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("connect thread panicked"));
+                }
+            }
+            if stop.load(Ordering::SeqCst) {
+                let mut state = state.lock().unwrap();
+                state.1 = true;
+                if let Some(ref socket) = state.0 {
+                    let _ = socket.shutdown(Shutdown::Both);
+                }
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "connection interrupted"));
+            }
+        }
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, sending `data` to
+    /// the target as early as possible.
+    ///
+    /// See `Socks5Stream::connect_and_send` for details. If authentication is
+    /// configured, `data` is sent immediately after the proxy's reply.
+    pub fn connect_and_send<T, U>(&self, proxy: T, target: U, data: &[u8]) -> io::Result<Socks5Stream>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let target = target.to_target_addr()?;
+        self.check_target(&target)?;
+        let credentials = self.credentials_for(&target);
+        let auth = self.auth(&credentials)?;
+        let proxy = self.proxy_addrs(proxy)?;
+        let command = Command::Connect;
+        Socks5Stream::connect_sending(command, &*proxy, target, &auth, data, self)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, trying each of
+    /// the target's addresses in turn.
+    ///
+    /// See `Socks5Stream::connect_each` for details. Both the proxy and the
+    /// target are looked up with the configured resolver. If an attempt delay
+    /// is set with `attempt_delay`, the addresses are raced instead.
+    pub fn connect_each<T, U>(&self, proxy: T, target: U) -> io::Result<Socks5Stream>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let proxy = self.proxy_addrs(proxy)?;
+        let targets = self.resolve(&target.to_target_addr()?)?;
+        self.connect_addrs(proxy, targets)
+    }
+
+    // Connects to the first of `targets` which can be reached through the
+    // proxy.
+    fn connect_addrs(&self,
+                     proxy: Vec<SocketAddr>,
+                     targets: Vec<SocketAddr>)
+                     -> io::Result<Socks5Stream> {
+        if let Some(delay) = self.attempt_delay {
+            return self.connect_racing(proxy, interleave_families(targets), delay);
+        }
+
+        let mut last = None;
+        for target in targets {
+            match self.connect_raw(Command::Connect, &*proxy, target) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    if fails_for_every_target(&e) {
+                        return Err(e);
+                    }
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(no_addresses))
+    }
+
+    // Connects to each target on its own thread, starting a new attempt after
+    // `delay` or when the previous one fails, and returns the first stream to
+    // be established. The connections of the other attempts are shut down.
+    fn connect_racing(&self,
+                      proxy: Vec<SocketAddr>,
+                      targets: Vec<SocketAddr>,
+                      delay: Duration)
+                      -> io::Result<Socks5Stream> {
+        // the connections opened by the attempts, or `None` once the race is
+        // over
+        let sockets = Arc::new(Mutex::new(Some(vec![])));
+        let result = self.race(&sockets, proxy, targets, delay);
+
+        let winner = result.as_ref().ok().and_then(|stream| stream.local_addr().ok());
+        for socket in sockets.lock().unwrap().take().unwrap_or_default() {
+            if winner.is_none() || socket.local_addr().ok() != winner {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        }
+        result
+    }
+
+    fn race(&self,
+            sockets: &Arc<Mutex<Option<Vec<TcpStream>>>>,
+            proxy: Vec<SocketAddr>,
+            targets: Vec<SocketAddr>,
+            delay: Duration)
+            -> io::Result<Socks5Stream> {
+        let proxy = Arc::new(proxy);
+        let hook = self.socket_hook();
+        let (tx, rx) = mpsc::channel();
+        // dropped once no more attempts will be started, so that a panicking
+        // attempt can't leave us waiting forever
+        let mut tx = Some(tx);
+        let mut targets = targets.into_iter();
+        let mut pending = 0;
+        let mut last = None;
+
+        loop {
+            if let (Some(target), Some(tx)) = (targets.next(), &tx) {
+                let builder = self.clone();
+                let proxy = proxy.clone();
+                let hook = hook.clone();
+                let sockets = sockets.clone();
+                let tx = tx.clone();
+                thread::Builder::new()
+                    .name("socks-connect".to_owned())
+                    .spawn(move || {
+                        let start = Instant::now();
+                        let target = TargetAddr::Ip(target);
+                        let result = connect_proxy(&proxy[..], hook.as_deref())
+                            .map_err(|(proxy, e)| ConnectError::unreachable(proxy, &target, e))
+                            .and_then(|socket| {
+                                match *sockets.lock().unwrap() {
+                                    Some(ref mut sockets) => sockets.push(socket.try_clone()?),
+                                    None => {
+                                        return Err(io::Error::new(io::ErrorKind::Interrupted,
+                                                                  "connection race is over"))
+                                    }
+                                }
+                                builder.establish(socket, target, start.elapsed())
+                            });
+                        let _ = tx.send(result);
+                    })?;
+                pending += 1;
+            }
+            if targets.len() == 0 {
+                tx = None;
+            }
+            if pending == 0 {
+                return Err(last.unwrap_or_else(no_addresses));
+            }
+
+            let result = match tx {
+                Some(_) => rx.recv_timeout(delay),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match result {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    // another attempt may still succeed, but there's no point
+                    // in starting more
+                    if fails_for_every_target(&e) {
+                        tx = None;
+                    }
+                    pending -= 1;
+                    last = Some(e);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("connect thread panicked"));
+                }
+            }
+        }
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy reached over an
+    /// existing stream.
+    ///
+    /// See `Socks5Stream::connect_via` for details.
+    pub fn connect_via<S, U>(&self, mut stream: S, target: U) -> io::Result<Socks5Via<S>>
+        where S: Read + Write,
+              U: ToTargetAddr
+    {
+        let target = target.to_target_addr()?;
+        // the proxy's address isn't known for an arbitrary stream
+        let proxy_addr = self.handshake(&mut stream, Command::Connect, &target, None)?;
+        Ok(Socks5Via {
+            stream,
+            proxy_addr,
+            target,
+        })
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy exposed on a local
+    /// named pipe.
+    ///
+    /// See `Socks5Stream::connect_named_pipe` for details.
+    #[cfg(windows)]
+    pub fn connect_named_pipe<P, U>(&self, path: P, target: U) -> io::Result<Socks5Via<File>>
+        where P: AsRef<Path>,
+              U: ToTargetAddr
+    {
+        let pipe = OpenOptions::new().read(true).write(true).open(path)?;
+        self.connect_via(pipe, target)
+    }
+
+    /// Initiates a BIND request to the specified proxy.
+    ///
+    /// See `Socks5Listener::bind` for details.
+    pub fn bind<T, U>(&self, proxy: T, target: U) -> io::Result<Socks5Listener>
+        where T: ToTargetAddr,
+              U: ToTargetAddr
+    {
+        let proxy = self.proxy_addrs(proxy)?;
+        let mut listener = self.connect_raw(Command::Bind, &*proxy, target)
+            .map(Socks5Listener::new)?;
+        listener.accept_timeout = self.accept_timeout;
+        Ok(listener)
+    }
 
-impl Socks5Datagram {
     /// Creates a UDP socket bound to the specified address which will have its
     /// traffic routed through the specified proxy.
-    pub fn bind<T, U>(proxy: T, addr: U) -> io::Result<Socks5Datagram>
-        where T: ToSocketAddrs,
+    pub fn bind_datagram<T, U>(&self, proxy: T, addr: U) -> io::Result<Socks5Datagram>
+        where T: ToTargetAddr,
               U: ToSocketAddrs
     {
-        Self::bind_internal(proxy, addr, &Authentication::None)
+        self.check_plaintext()?;
+        let credentials = self.credentials_for(&unspecified_addr());
+        let auth = self.auth(&credentials)?;
+        let proxy = self.proxy_addrs(proxy)?;
+        let socket = UdpSocket::bind(addr)?;
+        Socks5Datagram::associate(&*proxy, socket, &auth, None, self)
     }
-    /// Creates a UDP socket bound to the specified address which will have its
-    /// traffic routed through the specified proxy. The given username and password
-    /// is used to authenticate to the SOCKS proxy.
-    pub fn bind_with_password<T, U>(proxy: T, addr: U, username: &str, password: &str) -> io::Result<Socks5Datagram>
+}
+
+// Authenticates and sends a request followed by `data`, returning the time
+// authentication completed and the address from the proxy's reply.
+//
+// Without authentication there's nothing to wait for before sending the
+// request, so everything is sent at once.
+fn negotiate<S>(socket: &mut S,
+                command: Command,
+                target: &TargetAddr,
+                auth: &[Authentication],
+                data: &[u8],
+                config: &Socks5Builder)
+                -> io::Result<(Instant, TargetAddr)>
+    where S: Read + Write
+{
+    if data.is_empty() || auth.iter().any(|a| a.id() != 0) {
+        authenticate(socket, auth, target, config)?;
+        let authenticated = Instant::now();
+        let proxy_addr = request(socket, command, target, config.validation)?;
+        socket.write_all(data)?;
+        return Ok((authenticated, proxy_addr));
+    }
+
+    let mut packet = greeting(auth);
+    let mut request = [0; MAX_ADDR_LEN + 3];
+    let len = encode_request(&mut request, command, target)?;
+    packet.extend_from_slice(&request[..len]);
+    packet.extend_from_slice(data);
+    socket.write_all(&packet)?;
+
+    select_method(socket, auth)?;
+    let authenticated = Instant::now();
+    let proxy_addr = read_reply(socket, config.validation)?;
+    Ok((authenticated, proxy_addr))
+}
+
+// The credentials to mask in a transcript.
+#[cfg(feature = "transcript")]
+fn secrets<'a>(auth: &[Authentication<'a>]) -> Vec<&'a [u8]> {
+    let mut secrets = vec![];
+    for auth in auth {
+        match *auth {
+            Authentication::Password { username, password } => {
+                secrets.push(username.as_bytes());
+                secrets.push(password.as_bytes());
+            }
+            #[cfg(feature = "chap")]
+            Authentication::Chap { username, secret } => {
+                secrets.push(username.as_bytes());
+                secrets.push(secret);
+            }
+            Authentication::None => {}
+        }
+    }
+    secrets
+}
+
+/// A SOCKS5 client.
+#[derive(Debug)]
+pub struct Socks5Stream {
+    socket: TcpStream,
+    proxy_addr: TargetAddr,
+    target: TargetAddr,
+    validation: Validation,
+    timing: ConnectTiming,
+}
+
+impl Socks5Stream {
+    /// Connects to a target server through a SOCKS5 proxy.
+    pub fn connect<T, U>(proxy: T, target: U) -> io::Result<Socks5Stream>
         where T: ToSocketAddrs,
-              U: ToSocketAddrs
+              U: ToTargetAddr
+    {
+        Self::connect_raw(Command::Connect, proxy, target, &[Authentication::None])
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given
+    /// username and password.
+    pub fn connect_with_password<T, U>(proxy: T, target: U, username: &str, password: &str) -> io::Result<Socks5Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
     {
         let auth = Authentication::Password { username, password };
-        Self::bind_internal(proxy, addr, &auth)
+        Self::connect_raw(Command::Connect, proxy, target, &[auth, Authentication::None])
     }
 
-    fn bind_internal<T, U>(proxy: T, addr: U, auth: &Authentication) -> io::Result<Socks5Datagram>
+    /// Connects to a target server through a SOCKS5 proxy, trying each of
+    /// the target's addresses in turn.
+    ///
+    /// The target is resolved locally, and a new connection to the proxy is
+    /// made for each of its addresses until one succeeds, mirroring the
+    /// behavior of `TcpStream::connect`. The error from the last attempt is
+    /// returned if none do. Attempts stop early if the proxy itself can't be
+    /// reached, authentication fails, or the proxy's ruleset forbids the
+    /// connection.
+    pub fn connect_each<T, U>(proxy: T, target: U) -> io::Result<Socks5Stream>
         where T: ToSocketAddrs,
               U: ToSocketAddrs
     {
-        // we don't know what our IP is from the perspective of the proxy, so
-        // don't try to pass `addr` in here.
-        let dst = TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)));
-        let stream = Socks5Stream::connect_raw(3, proxy, dst, auth)?;
+        let proxy = proxy.to_socket_addrs()?.collect();
+        Socks5Builder::new().connect_addrs(proxy, target.to_socket_addrs()?.collect())
+    }
 
-        let socket = UdpSocket::bind(addr)?;
-        socket.connect(&stream.proxy_addr)?;
+    /// Connects to a target server through a second SOCKS5 proxy, reached
+    /// over an existing stream to a first one.
+    ///
+    /// `stream` is typically a `Socks5Stream` whose target is the second
+    /// proxy. Calls can be nested to chain any number of proxies:
+    ///
+    /// ```no_run
+    /// use socks::Socks5Stream;
+    ///
+    /// let first = Socks5Stream::connect("127.0.0.1:1080", "proxy.example:1080").unwrap();
+    /// let second = Socks5Stream::connect_via(first, "example.com:80").unwrap();
+    /// ```
+    pub fn connect_via<S, U>(stream: S, target: U) -> io::Result<Socks5Via<S>>
+        where S: Read + Write,
+              U: ToTargetAddr
+    {
+        Socks5Builder::new().connect_via(stream, target)
+    }
 
-        Ok(Socks5Datagram {
+    /// Connects to a target server through a SOCKS5 proxy exposed on a local
+    /// named pipe, such as `\\.\pipe\proxy`.
+    ///
+    /// The handshake is performed over the pipe, which then carries the
+    /// connection to the target. Opening the pipe fails if all of its
+    /// instances are busy.
+    #[cfg(windows)]
+    pub fn connect_named_pipe<P, U>(path: P, target: U) -> io::Result<Socks5Via<File>>
+        where P: AsRef<Path>,
+              U: ToTargetAddr
+    {
+        Socks5Builder::new().connect_named_pipe(path, target)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, sending `data` to
+    /// the target as early as possible.
+    ///
+    /// Without authentication, the request and `data` are sent along with the
+    /// initial greeting rather than after the proxy's responses, saving round
+    /// trips for request/response protocols. If the proxy rejects the
+    /// request, `data` is discarded by the proxy.
+    pub fn connect_and_send<T, U>(proxy: T, target: U, data: &[u8]) -> io::Result<Socks5Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        Self::connect_sending(Command::Connect,
+                              proxy,
+                              target,
+                              &[Authentication::None],
+                              data,
+                              &Socks5Builder::new())
+    }
+
+    fn connect_raw<T, U>(command: Command,
+                         proxy: T,
+                         target: U,
+                         auth: &[Authentication])
+                         -> io::Result<Socks5Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        Self::connect_sending(command, proxy, target, auth, &[], &Socks5Builder::new())
+    }
+
+    fn connect_sending<T, U>(command: Command,
+                             proxy: T,
+                             target: U,
+                             auth: &[Authentication],
+                             data: &[u8],
+                             config: &Socks5Builder)
+                             -> io::Result<Socks5Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        let target = target.to_target_addr()?;
+        config.check_plaintext()?;
+        for auth in auth {
+            if let Authentication::Password { username, password } = *auth {
+                check_password(username, password)?;
+            }
+        }
+
+        let start = Instant::now();
+        let socket = connect_proxy(proxy, config.socket_hook().as_deref())
+            .map_err(|(proxy, e)| ConnectError::unreachable(proxy, &target, e))?;
+        Self::establish(socket, command, target, auth, data, config, start.elapsed())
+    }
+
+    // Negotiates with the proxy over a newly opened connection to it, which
+    // took `tcp_connect` to open.
+    fn establish(mut socket: TcpStream,
+                 command: Command,
+                 target: TargetAddr,
+                 auth: &[Authentication],
+                 data: &[u8],
+                 config: &Socks5Builder,
+                 tcp_connect: Duration)
+                 -> io::Result<Socks5Stream> {
+        let validation = config.validation;
+        let connected = Instant::now();
+        let proxy = socket.peer_addr().ok();
+        let context = |e: io::Error| ConnectError::wrap(proxy, &target, e);
+        #[cfg(not(feature = "transcript"))]
+        let (authenticated, proxy_addr) =
+            negotiate(&mut socket, command, &target, auth, data, config).map_err(context)?;
+        #[cfg(feature = "transcript")]
+        let (authenticated, proxy_addr) = {
+            let mut recorder = Recorder::new(&mut socket, secrets(auth));
+            negotiate(&mut recorder, command, &target, auth, data, config)
+                .map_err(|e| recorder.attach(context(e)))?
+        };
+        let proxy_addr = substitute_unspecified(validation, proxy_addr, proxy);
+
+        Ok(Socks5Stream {
             socket: socket,
-            stream: stream,
+            proxy_addr: proxy_addr,
+            target,
+            validation,
+            timing: ConnectTiming {
+                tcp_connect,
+                auth: authenticated - connected,
+                request: authenticated.elapsed(),
+            },
         })
     }
 
-    /// Like `UdpSocket::send_to`.
-    ///
-    /// # Note
-    ///
-    /// The SOCKS protocol inserts a header at the beginning of the message. The
-    /// header will be 10 bytes for an IPv4 address, 22 bytes for an IPv6
-    /// address, and 7 bytes plus the length of the domain for a domain address.
-    pub fn send_to<A>(&self, buf: &[u8], addr: A) -> io::Result<usize>
-        where A: ToTargetAddr
+    fn password_authentication<S>(socket: &mut S, username: &str, password: &str) -> io::Result<()>
+        where S: Read + Write
     {
-        let addr = addr.to_target_addr()?;
+        check_password(username, password)?;
+
+        let mut packet = [0; 515];
+        let packet_size = 3 + username.len() + password.len();
+        packet[0] = 1; // version
+        packet[1] = username.len() as u8;
+        packet[2..2 + username.len()].copy_from_slice(username.as_bytes());
+        packet[2 + username.len()] = password.len() as u8;
+        packet[3 + username.len()..packet_size].copy_from_slice(password.as_bytes());
+        socket.write_all(&packet[..packet_size])?;
+
+        let mut buf = [0; 2];
+        socket.read_exact(&mut buf)?;
+        if buf[0] != 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
+        }
+        if buf[1] != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "password authentication failed"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the proxy-side address of the connection between the proxy and
+    /// target server.
+    ///
+    /// Use `peer_addr` for the address of the proxy server itself. The
+    /// address type of the proxy's reply is available from
+    /// `TargetAddr::address_type`.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.proxy_addr
+    }
+
+    /// Returns a breakdown of the time taken to establish the connection.
+    ///
+    /// For a stream returned by `Socks5Listener::accept`, this describes the
+    /// initial BIND request.
+    pub fn timing(&self) -> ConnectTiming {
+        self.timing
+    }
+
+    /// Returns the target address passed to `connect`.
+    ///
+    /// For a stream returned by `Socks5Listener::accept`, this is the target
+    /// passed to `bind`.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    ///
+    /// This is distinct from `proxy_addr`, which is the address the proxy
+    /// uses for its connection to the target server.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    /// Returns the local address of the connection to the proxy server.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Moves the inner `TcpStream` into or out of nonblocking mode.
+    ///
+    /// The handshake with the proxy is always performed in blocking mode;
+    /// once the stream has been established, reads and writes pass
+    /// `WouldBlock` errors through unchanged, so it can be driven by an
+    /// event loop.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
+    /// Determines if the connection to the proxy appears to still be open,
+    /// without blocking or consuming any data.
+    ///
+    /// This is intended to validate idle connections, such as those in a
+    /// pool, before reusing them. A connection closed by the proxy is only
+    /// detected once the proxy's FIN or RST has arrived.
+    ///
+    /// The stream's blocking mode is not changed.
+    pub fn is_alive(&self) -> bool {
+        #[cfg(unix)]
+        let r = SockRef::from(&self.socket).recv_with_flags(&mut [mem::MaybeUninit::uninit()],
+                                                           libc::MSG_PEEK | libc::MSG_DONTWAIT);
+        #[cfg(windows)]
+        let r = pending_len(&self.socket);
+
+        match r {
+            Ok(len) => len > 0,
+            Err(ref e) => {
+                e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted
+            }
+        }
+    }
+
+    /// Enables TCP keepalive on the connection to the proxy, sending probes
+    /// after the connection has been idle for the specified time, or disables
+    /// it if `None`.
+    ///
+    /// The operating system sends the probes in the background, so a dead
+    /// proxy is detected even while the stream is unused.
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        let socket = SockRef::from(&self.socket);
+        match idle {
+            Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle)),
+            None => socket.set_keepalive(false),
+        }
+    }
+
+    /// Returns a shared reference to the inner `TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the inner `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /// Consumes the `Socks5Stream`, returning the inner `TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        self.socket
+    }
+}
+
+impl AsRef<TcpStream> for Socks5Stream {
+    fn as_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
+impl Read for Socks5Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.socket.read_vectored(bufs)
+    }
+}
+
+impl<'a> Read for &'a Socks5Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.socket).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        (&self.socket).read_vectored(bufs)
+    }
+}
+
+impl Write for Socks5Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.socket.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl<'a> Write for &'a Socks5Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.socket).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        (&self.socket).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.socket).flush()
+    }
+}
+
+/// A connection through a SOCKS5 proxy reached over another stream.
+///
+/// Returned by `Socks5Stream::connect_via`.
+#[derive(Debug)]
+pub struct Socks5Via<S> {
+    stream: S,
+    proxy_addr: TargetAddr,
+    target: TargetAddr,
+}
+
+impl<S> Socks5Via<S> {
+    /// Returns the proxy-side address of the connection between the proxy and
+    /// target server.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.proxy_addr
+    }
+
+    /// Returns the target address passed to `connect_via`.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Returns a shared reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `Socks5Via`, returning the inner stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read> Read for Socks5Via<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Socks5Via<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A SOCKS5 BIND client.
+#[derive(Debug)]
+pub struct Socks5Listener {
+    stream: Socks5Stream,
+    verify_peer: bool,
+    accept_timeout: Option<Duration>,
+    // the start of a reply whose remainder didn't arrive before a timeout
+    pending: Vec<u8>,
+}
+
+impl Socks5Listener {
+    fn new(stream: Socks5Stream) -> Socks5Listener {
+        Socks5Listener {
+            stream,
+            verify_peer: false,
+            accept_timeout: None,
+            pending: vec![],
+        }
+    }
+
+    /// Initiates a BIND request to the specified proxy.
+    ///
+    /// The proxy will filter incoming connections based on the value of
+    /// `target`.
+    pub fn bind<T, U>(proxy: T, target: U) -> io::Result<Socks5Listener>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        Socks5Stream::connect_raw(Command::Bind, proxy, target, &[Authentication::None])
+            .map(Socks5Listener::new)
+    }
+    /// Initiates a BIND request to the specified proxy using given username
+    /// and password.
+    ///
+    /// The proxy will filter incoming connections based on the value of
+    /// `target`.
+    pub fn bind_with_password<T, U>(proxy: T, target: U, username: &str, password: &str) -> io::Result<Socks5Listener>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        let auth = Authentication::Password { username, password };
+        Socks5Stream::connect_raw(Command::Bind, proxy, target, &[auth, Authentication::None])
+            .map(Socks5Listener::new)
+    }
+
+    /// The address of the proxy-side TCP listener.
+    ///
+    /// This should be forwarded to the remote process, which should open a
+    /// connection to it. Its `address_type` tells whether the proxy bound an
+    /// IPv4 or IPv6 listener, for example to choose between FTP's `PORT` and
+    /// `EPRT` commands.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.stream.proxy_addr
+    }
+
+    /// Returns the address of the proxy-side TCP listener formatted as the
+    /// argument of an FTP `PORT` command.
+    ///
+    /// An unspecified bound address is replaced with the address of the
+    /// proxy. `PORT` only supports IPv4 addresses; use `eprt_argument` if the
+    /// proxy may bind to an IPv6 address.
+    pub fn port_argument(&self) -> io::Result<String> {
+        ftp::checked_port_argument(&self.connectable_addr()?)
+    }
+
+    /// Returns the address of the proxy-side TCP listener formatted as the
+    /// argument of an FTP `EPRT` command.
+    ///
+    /// An unspecified bound address is replaced with the address of the
+    /// proxy.
+    pub fn eprt_argument(&self) -> io::Result<String> {
+        Ok(ftp::eprt_argument(&self.connectable_addr()?))
+    }
+
+    fn connectable_addr(&self) -> io::Result<SocketAddr> {
+        ftp::connectable_addr(&self.stream.proxy_addr, self.stream.peer_addr()?)
+    }
+
+    /// Sets whether `accept` checks that the incoming connection comes from
+    /// the `target` passed to `bind`, rather than trusting the proxy's
+    /// filtering.
+    ///
+    /// Only IP addresses are compared, since the remote process's port is
+    /// typically ephemeral. A domain name target is resolved locally if the
+    /// proxy reports the peer's IP address. Defaults to `false`.
+    pub fn set_verify_peer(&mut self, verify_peer: bool) {
+        self.verify_peer = verify_peer;
+    }
+
+    /// Sets how long `accept` and `accept_reply` wait for the remote process
+    /// to connect.
+    ///
+    /// The timeout bounds the whole reply, however slowly the proxy sends it.
+    /// If the reply hasn't arrived in time, they fail with a `TimedOut` error
+    /// wrapping `Error::AcceptTimedOut`. Any part of the reply which did
+    /// arrive is kept, so `accept_reply` can be called again to keep
+    /// waiting. A zero timeout is rejected, as with
+    /// `TcpStream::set_read_timeout`. Defaults to the
+    /// value set by `Socks5Builder::accept_timeout`, or `None` if the
+    /// listener wasn't created by a builder.
+    pub fn set_accept_timeout(&mut self, timeout: Option<Duration>) {
+        self.accept_timeout = timeout;
+    }
+
+    /// Waits for the remote process to connect to the proxy server.
+    ///
+    /// The value of `proxy_addr` should be forwarded to the remote process
+    /// before this method is called. The listener is consumed even if this
+    /// times out; use `accept_reply` to be able to wait again.
+    pub fn accept(mut self) -> io::Result<Socks5Stream> {
+        let peer = self.accept_reply()?;
+        self.stream.proxy_addr = peer;
+        Ok(self.stream)
+    }
+
+    /// Waits for the next reply on the control stream reporting a connection
+    /// from the remote process, and returns the peer's address.
+    ///
+    /// Unlike `accept`, the listener is kept, so that the control stream can
+    /// continue to be read. This is intended for proxies which deliver the
+    /// remote process's data over a separate connection, or which report
+    /// several connections on one control stream, rather than relaying data
+    /// over the control stream as specified by RFC 1928.
+    pub fn accept_reply(&mut self) -> io::Result<TargetAddr> {
+        let peer = self.read_accept_reply()?;
+        let proxy = self.stream.socket.peer_addr().ok();
+        let peer = substitute_unspecified(self.stream.validation, peer, proxy);
+        if self.verify_peer {
+            check_bind_peer(&self.stream.target, &peer)?;
+        }
+        Ok(peer)
+    }
+
+    // Reads a reply into `pending`, never past its end, by the accept
+    // timeout, and then parses it.
+    fn read_accept_reply(&mut self) -> io::Result<TargetAddr> {
+        let old_timeout = self.stream.socket.read_timeout()?;
+        let len = self.fill_reply();
+        self.stream.socket.set_read_timeout(old_timeout)?;
+        let len = len?;
+
+        let peer = read_reply(&mut &self.pending[..len], self.stream.validation);
+        self.pending.drain(..len);
+        peer
+    }
+
+    fn fill_reply(&mut self) -> io::Result<usize> {
+        let timeout = self.accept_timeout;
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "cannot set a 0 duration timeout"));
+        }
+        let deadline = Instant::now() + timeout.unwrap_or_default();
+        let timed_out = |timeout| {
+            io::Error::new(io::ErrorKind::TimedOut, Error::AcceptTimedOut { timeout })
+        };
+
+        let mut buf = [0; MAX_ADDR_LEN + 4];
+        loop {
+            let len = reply_len(&self.pending);
+            if self.pending.len() >= len {
+                return Ok(len);
+            }
+            if let Some(timeout) = timeout {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    return Err(timed_out(timeout));
+                }
+                self.stream.socket.set_read_timeout(Some(remaining))?;
+            }
+            match self.stream.socket.read(&mut buf[..len - self.pending.len()]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated reply"))
+                }
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(e) => {
+                    match (timeout, e.kind()) {
+                        (_, io::ErrorKind::Interrupted) => {}
+                        (Some(timeout), io::ErrorKind::WouldBlock) |
+                        (Some(timeout), io::ErrorKind::TimedOut) => return Err(timed_out(timeout)),
+                        _ => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a shared reference to the control stream.
+    pub fn control(&self) -> &Socks5Stream {
+        &self.stream
+    }
+
+    /// Consumes the `Socks5Listener`, returning the control stream.
+    ///
+    /// The stream's `proxy_addr` is the address of the proxy-side TCP
+    /// listener.
+    pub fn into_control(self) -> Socks5Stream {
+        self.stream
+    }
+}
+
+/// A configuration for `Socks5Datagram`s.
+///
+/// Authentication to the proxy uses the settings of a `Socks5Builder`.
+#[derive(Debug, Clone, Default)]
+pub struct Socks5DatagramBuilder {
+    socks: Socks5Builder,
+    reuse_address: bool,
+    reuse_port: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    relay_addr: Option<SocketAddr>,
+    reassemble: bool,
+    fragment_len: Option<usize>,
+    traffic_class: Option<u32>,
+}
+
+impl Socks5DatagramBuilder {
+    /// Creates a new builder which does not authenticate to the proxy.
+    pub fn new() -> Socks5DatagramBuilder {
+        Socks5DatagramBuilder::default()
+    }
+
+    /// Authenticates to the proxy as configured by `socks`.
+    pub fn socks(&mut self, socks: Socks5Builder) -> &mut Socks5DatagramBuilder {
+        self.socks = socks;
+        self
+    }
+
+    /// Sets the `SO_REUSEADDR` option on the UDP socket before binding it.
+    pub fn reuse_address(&mut self, reuse_address: bool) -> &mut Socks5DatagramBuilder {
+        self.reuse_address = reuse_address;
+        self
+    }
+
+    /// Sets the `SO_REUSEPORT` option on the UDP socket before binding it,
+    /// allowing several processes to bind the same address.
+    ///
+    /// Only available on Unix platforms.
+    #[cfg(unix)]
+    pub fn reuse_port(&mut self, reuse_port: bool) -> &mut Socks5DatagramBuilder {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Sets the read timeout of the UDP socket.
+    pub fn read_timeout(&mut self, timeout: Option<Duration>) -> &mut Socks5DatagramBuilder {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Sets the write timeout of the UDP socket.
+    pub fn write_timeout(&mut self, timeout: Option<Duration>) -> &mut Socks5DatagramBuilder {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Relays messages through the specified address rather than the one
+    /// reported by the proxy.
+    ///
+    /// Proxies behind NAT or listening on several interfaces sometimes report
+    /// a relay address which is unreachable from the client, such as
+    /// `0.0.0.0`.
+    pub fn relay_addr(&mut self, addr: SocketAddr) -> &mut Socks5DatagramBuilder {
+        self.relay_addr = Some(addr);
+        self
+    }
+
+    /// Determines if fragmented messages from the proxy are reassembled.
+    ///
+    /// If disabled, the default, receiving a fragment returns an error.
+    /// Incomplete messages are discarded if their fragments stop arriving for
+    /// 5 seconds.
+    pub fn reassemble(&mut self, reassemble: bool) -> &mut Socks5DatagramBuilder {
+        self.reassemble = reassemble;
+        self
+    }
+
+    /// Sets the maximum length of the data sent in a single message, with
+    /// larger messages split into fragments.
+    ///
+    /// A message can be split into at most 127 fragments. Messages are not
+    /// fragmented by default, and many proxies do not support fragmentation.
+    pub fn fragment_len(&mut self, len: Option<usize>) -> io::Result<&mut Socks5DatagramBuilder> {
+        if len == Some(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "fragment length must be nonzero"));
+        }
+        self.fragment_len = len;
+        Ok(self)
+    }
+
+    /// Sets the `IP_TOS` or `IPV6_TCLASS` value of the UDP socket.
+    ///
+    /// The control connection to the proxy is configured separately, with
+    /// `Socks5Builder::traffic_class`. Defaults to `None`.
+    pub fn traffic_class(&mut self, class: Option<u32>) -> &mut Socks5DatagramBuilder {
+        self.traffic_class = class;
+        self
+    }
+
+    fn bind_socket(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if self.reuse_address {
+            socket.set_reuse_address(true)?;
+        }
+        #[cfg(unix)]
+        {
+            if self.reuse_port {
+                socket.set_reuse_port(true)?;
+            }
+        }
+        if let Some(class) = self.traffic_class {
+            set_traffic_class(SockRef::from(&socket), addr.is_ipv6(), class)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.set_read_timeout(self.read_timeout)?;
+        socket.set_write_timeout(self.write_timeout)?;
+        Ok(socket.into())
+    }
+
+    /// Creates a UDP socket bound to the specified address which will have its
+    /// traffic routed through the specified proxy.
+    ///
+    /// The proxy's address is looked up with the resolver of the `socks`
+    /// builder.
+    pub fn bind<T, U>(&self, proxy: T, addr: U) -> io::Result<Socks5Datagram>
+        where T: ToTargetAddr,
+              U: ToSocketAddrs
+    {
+        let proxy = self.socks.proxy_addrs(proxy)?;
+        let mut last_err = None;
+        let mut socket = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_socket(addr) {
+                Ok(s) => {
+                    socket = Some(s);
+                    break;
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let socket = match socket {
+            Some(socket) => socket,
+            None => {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   "could not resolve to any addresses")
+                }))
+            }
+        };
+
+        let credentials = self.socks.credentials_for(&unspecified_addr());
+        let mut datagram = Socks5Datagram::associate(&*proxy,
+                                                     socket,
+                                                     &self.socks.auth(&credentials)?,
+                                                     self.relay_addr,
+                                                     &self.socks)?;
+        if self.reassemble {
+            datagram.reassembly = Some(Mutex::new(Reassembly::default()));
+        }
+        datagram.fragment_len = self.fragment_len;
+        Ok(datagram)
+    }
+}
+
+// The number of encoded UDP request headers cached by a `Socks5Datagram`.
+const HEADER_CACHE_LEN: usize = 8;
+
+// The encoded UDP request headers of recently used destinations, least
+// recently used first.
+#[derive(Debug, Default)]
+struct HeaderCache {
+    entries: Vec<(TargetAddr, Vec<u8>)>,
+}
+
+impl HeaderCache {
+    fn get(&mut self, addr: &TargetAddr) -> io::Result<&[u8]> {
+        match self.entries.iter().position(|e| e.0 == *addr) {
+            Some(i) => {
+                let entry = self.entries.remove(i);
+                self.entries.push(entry);
+            }
+            None => {
+                let header = encode_udp_header(addr)?;
+                if self.entries.len() == HEADER_CACHE_LEN {
+                    self.entries.remove(0);
+                }
+                self.entries.push((addr.clone(), header));
+            }
+        }
+        Ok(&self.entries.last().unwrap().1)
+    }
+}
+
+pub(crate) fn encode_udp_header(addr: &TargetAddr) -> io::Result<Vec<u8>> {
+    let mut header = [0; MAX_ADDR_LEN + 3];
+    // first two bytes are reserved at 0
+    // third byte is the fragment id at 0
+    let len = write_addr(&mut header[3..], addr)?;
+    Ok(header[..len + 3].to_vec())
+}
+
+// How long fragments of an incomplete message are held on to.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The largest fragment position; the high bit of the fragment field marks the
+// end of a message.
+const MAX_FRAGMENTS: usize = 0x7f;
+
+// The fragments of a message received so far.
+#[derive(Debug, Default)]
+struct Reassembly {
+    // the source, last position and arrival time of the latest fragment
+    current: Option<(TargetAddr, u8, Instant)>,
+    data: Vec<u8>,
+}
+
+impl Reassembly {
+    // Adds a fragment, returning the message it completes, if any.
+    fn push(&mut self, frag: u8, addr: TargetAddr, data: &[u8]) -> Option<(Vec<u8>, TargetAddr)> {
+        let position = frag & 0x7f;
+        let now = Instant::now();
+
+        let continues = match self.current {
+            Some((ref current, last, time)) => {
+                *current == addr && position == last + 1 && now - time < REASSEMBLY_TIMEOUT
+            }
+            None => false,
+        };
+        if !continues {
+            self.current = None;
+            self.data.clear();
+            // the rest of a message is useless without its start
+            if position != 1 {
+                return None;
+            }
+        }
+
+        // don't let a stream of fragments grow the buffer without bound
+        if self.data.len() + data.len() > MAX_DATAGRAM_LEN {
+            self.current = None;
+            self.data.clear();
+            return None;
+        }
+
+        self.data.extend_from_slice(data);
+        if frag & 0x80 != 0 {
+            self.current = None;
+            return Some((mem::take(&mut self.data), addr));
+        }
+        self.current = Some((addr, position, now));
+        None
+    }
+}
+
+// The outcome of passing a received datagram through reassembly.
+enum Reassembled {
+    // the datagram is a whole message
+    Datagram(TargetAddr),
+    // the datagram completed a fragmented message
+    Message(Vec<u8>, TargetAddr),
+    // the datagram is a fragment of an incomplete message
+    Pending,
+}
+
+/// A SOCKS5 UDP client.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    // keeps the session alive
+    stream: Socks5Stream,
+    headers: Mutex<HeaderCache>,
+    reassembly: Option<Mutex<Reassembly>>,
+    fragment_len: Option<usize>,
+}
+
+impl Socks5Datagram {
+    /// Creates a UDP socket bound to the specified address which will have its
+    /// traffic routed through the specified proxy.
+    pub fn bind<T, U>(proxy: T, addr: U) -> io::Result<Socks5Datagram>
+        where T: ToSocketAddrs,
+              U: ToSocketAddrs
+    {
+        Self::bind_internal(proxy, addr, &[Authentication::None])
+    }
+    /// Creates a UDP socket bound to the specified address which will have its
+    /// traffic routed through the specified proxy. The given username and password
+    /// is used to authenticate to the SOCKS proxy.
+    pub fn bind_with_password<T, U>(proxy: T, addr: U, username: &str, password: &str) -> io::Result<Socks5Datagram>
+        where T: ToSocketAddrs,
+              U: ToSocketAddrs
+    {
+        let auth = Authentication::Password { username, password };
+        Self::bind_internal(proxy, addr, &[auth, Authentication::None])
+    }
+
+    fn bind_internal<T, U>(proxy: T, addr: U, auth: &[Authentication]) -> io::Result<Socks5Datagram>
+        where T: ToSocketAddrs,
+              U: ToSocketAddrs
+    {
+        Self::associate(proxy, UdpSocket::bind(addr)?, auth, None, &Socks5Builder::new())
+    }
+
+    fn associate<T>(proxy: T,
+                    socket: UdpSocket,
+                    auth: &[Authentication],
+                    relay_addr: Option<SocketAddr>,
+                    config: &Socks5Builder)
+                    -> io::Result<Socks5Datagram>
+        where T: ToSocketAddrs
+    {
+        // we don't know what our IP is from the perspective of the proxy, so
+        // don't try to pass `addr` in here.
+        let stream = Socks5Stream::connect_sending(Command::UdpAssociate,
+                                                   proxy,
+                                                   unspecified_addr(),
+                                                   auth,
+                                                   &[],
+                                                   config)?;
+
+        match relay_addr {
+            Some(addr) => socket.connect(addr)?,
+            None => socket.connect(&stream.proxy_addr)?,
+        }
+
+        Ok(Socks5Datagram {
+            socket: socket,
+            stream: stream,
+            headers: Mutex::new(HeaderCache::default()),
+            reassembly: None,
+            fragment_len: None,
+        })
+    }
+
+    /// Like `UdpSocket::send_to`.
+    ///
+    /// # Note
+    ///
+    /// The SOCKS protocol inserts a header at the beginning of the message. The
+    /// header will be 10 bytes for an IPv4 address, 22 bytes for an IPv6
+    /// address, and 7 bytes plus the length of the domain for a domain address.
+    ///
+    /// The headers of the last few destinations are cached, so repeated sends
+    /// to the same destinations don't re-encode the address.
+    pub fn send_to<A>(&self, buf: &[u8], addr: A) -> io::Result<usize>
+        where A: ToTargetAddr
+    {
+        let addr = addr.to_target_addr()?;
+
+        if let Some(len) = self.fragment_len {
+            if buf.len() > len {
+                return self.send_fragments(buf, &addr, len);
+            }
+        }
+
+        // don't make concurrent senders wait on each other
+        match self.headers.try_lock() {
+            Ok(mut headers) => {
+                self.socket.writev(&[IoSlice::new(headers.get(&addr)?), IoSlice::new(buf)])
+            }
+            Err(_) => {
+                let header = encode_udp_header(&addr)?;
+                self.socket.writev(&[IoSlice::new(&header), IoSlice::new(buf)])
+            }
+        }
+    }
+
+    /// Sends a message with the specified value in the header's fragment
+    /// field.
+    ///
+    /// No fragmentation is performed: the message is sent as a single
+    /// datagram regardless of `Socks5DatagramBuilder::fragment_len`. The high
+    /// bit of `frag` marks the last fragment of a message, and 0 marks a
+    /// complete message. This is intended for interoperability testing and
+    /// relays which fragment messages themselves.
+    pub fn send_fragment<A>(&self, buf: &[u8], addr: A, frag: u8) -> io::Result<usize>
+        where A: ToTargetAddr
+    {
+        let mut header = encode_udp_header(&addr.to_target_addr()?)?;
+        header[2] = frag;
+        let len = self.socket.writev(&[IoSlice::new(&header), IoSlice::new(buf)])?;
+        Ok(len.saturating_sub(header.len()))
+    }
+
+    fn send_fragments(&self, buf: &[u8], addr: &TargetAddr, len: usize) -> io::Result<usize> {
+        let count = buf.len().div_ceil(len);
+        if count > MAX_FRAGMENTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "message has too many fragments"));
+        }
+
+        let mut header = encode_udp_header(addr)?;
+        for (i, chunk) in buf.chunks(len).enumerate() {
+            header[2] = i as u8 + 1;
+            if i + 1 == count {
+                header[2] |= 0x80;
+            }
+            self.socket.writev(&[IoSlice::new(&header), IoSlice::new(chunk)])?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Like `send_to`, but never blocks, regardless of whether the socket is
+    /// in nonblocking mode.
+    ///
+    /// A `WouldBlock` error is returned if the message can't be sent
+    /// immediately. Messages are not fragmented, so an `InvalidInput` error is
+    /// returned if one is longer than `Socks5DatagramBuilder::fragment_len`.
+    /// On Unix, this uses `MSG_DONTWAIT`. On Windows, the socket is polled
+    /// first, and its blocking mode is left alone.
+    pub fn try_send_to<A>(&self, buf: &[u8], addr: A) -> io::Result<usize>
+        where A: ToTargetAddr
+    {
+        if self.fragment_len.is_some_and(|len| buf.len() > len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "message must be fragmented"));
+        }
+
+        let header = encode_udp_header(&addr.to_target_addr()?)?;
+        let bufs = [IoSlice::new(&header), IoSlice::new(buf)];
+        #[cfg(unix)]
+        let len = SockRef::from(&self.socket).send_vectored_with_flags(&bufs, libc::MSG_DONTWAIT)?;
+        #[cfg(windows)]
+        let len = poll_now(&self.socket, winapi::um::winsock2::POLLWRNORM)
+            .and_then(|_| self.socket.writev(&bufs))?;
+        Ok(len.saturating_sub(header.len()))
+    }
+
+    /// Like `recv_from`, but never blocks, regardless of whether the socket
+    /// is in nonblocking mode.
+    ///
+    /// A `WouldBlock` error is returned if no complete message is queued.
+    /// Fragments received before then are kept for later calls. On Unix,
+    /// this uses `MSG_DONTWAIT`. On Windows, the socket is polled first, and
+    /// its blocking mode is left alone.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+        self.recv_message(buf, true)
+    }
+
+    /// Receives all of the messages currently queued on the socket without
+    /// blocking, appending each to `messages` along with its source address.
+    ///
+    /// This suits poll-based applications which empty the socket on each
+    /// wakeup. Messages are received into an internal buffer large enough
+    /// for any datagram, so none are truncated. Returns the number of
+    /// messages received. If an error other than `WouldBlock` occurs, it is
+    /// returned, and the messages received before it are left in `messages`.
+    /// See `try_recv_from` for details.
+    pub fn drain_into(&self, messages: &mut Vec<(Vec<u8>, TargetAddr)>) -> io::Result<usize> {
+        self.drain_into_limited(messages, usize::MAX)
+    }
+
+    /// Like `drain_into`, but receives at most `max` messages, leaving the
+    /// rest queued for later calls.
+    ///
+    /// This keeps a busy peer from starving an application's other work.
+    pub fn drain_into_limited(&self,
+                              messages: &mut Vec<(Vec<u8>, TargetAddr)>,
+                              max: usize)
+                              -> io::Result<usize> {
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        let mut count = 0;
+        while count < max {
+            match self.try_recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    messages.push((buf[..len].to_vec(), addr));
+                    count += 1;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Like `UdpSocket::recv_from`.
+    ///
+    /// If reassembly was enabled with `Socks5DatagramBuilder::reassemble`,
+    /// fragments are buffered until a complete message arrives.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+        self.recv_message(buf, false)
+    }
+
+    fn recv_message(&self, buf: &mut [u8], nonblocking: bool) -> io::Result<(usize, TargetAddr)> {
+        loop {
+            let (len, frag, addr) = self.recv_datagram(buf, nonblocking)?;
+            match self.reassemble(frag, addr, &buf[..len])? {
+                Reassembled::Datagram(addr) => return Ok((len, addr)),
+                Reassembled::Message(message, addr) => {
+                    let len = cmp::min(message.len(), buf.len());
+                    buf[..len].copy_from_slice(&message[..len]);
+                    return Ok((len, addr));
+                }
+                Reassembled::Pending => {}
+            }
+        }
+    }
+
+    // Passes a datagram with the specified fragment id through reassembly.
+    fn reassemble(&self, frag: u8, addr: TargetAddr, payload: &[u8]) -> io::Result<Reassembled> {
+        let reassembly = match self.reassembly {
+            Some(ref reassembly) => reassembly,
+            None if frag == 0 => return Ok(Reassembled::Datagram(addr)),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid fragment id")),
+        };
+
+        let mut reassembly = reassembly.lock().unwrap();
+        if frag == 0 {
+            // a complete message abandons any partial one
+            *reassembly = Reassembly::default();
+            return Ok(Reassembled::Datagram(addr));
+        }
+        match reassembly.push(frag, addr, payload) {
+            Some((message, addr)) => Ok(Reassembled::Message(message, addr)),
+            None => Ok(Reassembled::Pending),
+        }
+    }
+
+    /// Receives a message along with the value of its header's fragment
+    /// field, without reassembling fragments.
+    ///
+    /// Unlike `recv_from`, fragments are returned as they arrive rather than
+    /// being buffered or rejected, regardless of
+    /// `Socks5DatagramBuilder::reassemble`. Returns the length of the payload,
+    /// the fragment field and the source address.
+    pub fn recv_fragment(&self, buf: &mut [u8]) -> io::Result<(usize, u8, TargetAddr)> {
+        self.recv_datagram(buf, false)
+    }
+
+    fn recv_datagram(&self,
+                     buf: &mut [u8],
+                     nonblocking: bool)
+                     -> io::Result<(usize, u8, TargetAddr)> {
+        let mut header = [0; MAX_ADDR_LEN + 3];
+        let len = if nonblocking {
+            self.try_readv(&mut header, buf)?
+        } else {
+            self.socket.readv(&mut [IoSliceMut::new(&mut header), IoSliceMut::new(buf)])?
+        };
+
+        let overflow = len.saturating_sub(header.len());
+
+        let header_len = cmp::min(header.len(), len);
+        let (parsed, parsed_len) = parse_udp_header(&header[..header_len])?;
+        let header = &header[parsed_len..header_len];
+        let frag = parsed.fragment;
+        let addr = parsed.addr;
+
+        // the payload starts at the end of `header` and continues into `buf`,
+        // and is truncated if it doesn't fit, as with `UdpSocket::recv_from`
+        let len = cmp::min(header.len() + overflow, buf.len());
+        let head = cmp::min(header.len(), len);
+        buf.copy_within(..len - head, head);
+        buf[..head].copy_from_slice(&header[..head]);
+
+        Ok((len, frag, addr))
+    }
+
+    // Receives a datagram into `header` and then `buf` without blocking.
+    #[cfg(unix)]
+    fn try_readv(&self, header: &mut [u8], buf: &mut [u8]) -> io::Result<usize> {
+        let mut bufs = [MaybeUninitSlice::new(as_uninit(header)),
+                        MaybeUninitSlice::new(as_uninit(buf))];
+        SockRef::from(&self.socket)
+            .recv_vectored_with_flags(&mut bufs, libc::MSG_DONTWAIT)
+            .map(|(len, _)| len)
+    }
+
+    #[cfg(windows)]
+    fn try_readv(&self, header: &mut [u8], buf: &mut [u8]) -> io::Result<usize> {
+        poll_now(&self.socket, winapi::um::winsock2::POLLRDNORM)?;
+        self.socket.readv(&mut [IoSliceMut::new(header), IoSliceMut::new(buf)])
+    }
+
+    /// Receives a message without interpreting its header.
+    ///
+    /// The entire datagram, header included, is read into `buf`. The parsed
+    /// header is returned along with the range of `buf` holding the payload.
+    /// Fragments are returned as they arrive, regardless of
+    /// `Socks5DatagramBuilder::reassemble`.
+    ///
+    /// This is intended for protocol bridges which need to see exactly what
+    /// the proxy sent; most users should use `recv_from` instead.
+    pub fn recv_raw(&self, buf: &mut [u8]) -> io::Result<(UdpHeader, Range<usize>)> {
+        let len = self.socket.recv(buf)?;
+        let (header, header_len) = parse_udp_header(&buf[..len])?;
+        Ok((header, header_len..len))
+    }
+
+    /// Like `recv_from`, but receives into a growable buffer.
+    ///
+    /// The buffer's contents are replaced by the message received. Its
+    /// capacity is grown to fit any message, but it is not initialized first,
+    /// so a buffer reused across calls is only allocated once and each call
+    /// only writes the bytes received.
+    pub fn recv_from_buf(&self, buf: &mut Vec<u8>) -> io::Result<TargetAddr> {
+        let result = self.recv_into_vec(buf);
+        if result.is_err() {
+            buf.clear();
+        }
+        result
+    }
+
+    fn recv_into_vec(&self, buf: &mut Vec<u8>) -> io::Result<TargetAddr> {
+        loop {
+            buf.clear();
+            buf.reserve(MAX_DATAGRAM_LEN);
+            let len = SockRef::from(&self.socket).recv(buf.spare_capacity_mut())?;
+            // `recv` initialized the first `len` bytes of the spare capacity
+            unsafe { buf.set_len(len) };
+            let (header, header_len) = parse_udp_header(buf)?;
+            buf.drain(..header_len);
+
+            match self.reassemble(header.fragment, header.addr, buf)? {
+                Reassembled::Datagram(addr) => return Ok(addr),
+                Reassembled::Message(message, addr) => {
+                    buf.clear();
+                    buf.extend_from_slice(&message);
+                    return Ok(addr);
+                }
+                Reassembled::Pending => {}
+            }
+        }
+    }
+
+    /// Receives a message into a fixed-size buffer of `N` bytes stored
+    /// inline in the returned value.
+    ///
+    /// This suits workloads with small messages, such as DNS, since the
+    /// receive buffer isn't allocated on the heap. Messages longer than `N`
+    /// bytes are truncated, as with `recv_from`. Note that a domain name
+    /// source address is still allocated.
+    pub fn recv_msg<const N: usize>(&self) -> io::Result<InlineMessage<N>> {
+        let mut buf = [0; N];
+        let (len, addr) = self.recv_from(&mut buf)?;
+        Ok(InlineMessage { buf, len, addr })
+    }
+
+    /// Returns an iterator over the messages received by the socket.
+    ///
+    /// Each message is copied out of a receive buffer owned by the iterator,
+    /// which is reused across calls. The iterator never ends; iteration
+    /// blocks until a message arrives.
+    pub fn messages(&self) -> DatagramMessages<'_> {
+        DatagramMessages {
+            socket: self,
+            buf: vec![],
+        }
+    }
+
+    /// Returns the address of the proxy-side UDP socket through which all
+    /// messages will be routed.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.stream.proxy_addr
+    }
+
+    /// Returns a shared reference to the inner socket.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the inner socket.
+    pub fn get_mut(&mut self) -> &mut UdpSocket {
+        &mut self.socket
+    }
+}
+
+// Parses the header at the start of a UDP message, returning it along with its
+// length.
+pub(crate) fn parse_udp_header(buf: &[u8]) -> io::Result<(UdpHeader, usize)> {
+    let mut header = buf;
+    if header.read_u16::<BigEndian>()? != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid reserved bytes"));
+    }
+    let fragment = header.read_u8()?;
+    let addr = read_addr(&mut header)?;
+
+    let header_len = buf.len() - header.len();
+    let header = UdpHeader { fragment, addr };
+    Ok((header, header_len))
+}
+
+/// The header of a message received by a `Socks5Datagram`.
+///
+/// Returned by `Socks5Datagram::recv_raw`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    fragment: u8,
+    addr: TargetAddr,
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for UdpHeader {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<UdpHeader> {
+        Ok(UdpHeader {
+            fragment: u.arbitrary()?,
+            addr: TargetAddr::arbitrary(u)?,
+        })
+    }
+}
+
+impl UdpHeader {
+    /// Returns the fragment number, whose high bit marks the last fragment of
+    /// a message.
+    ///
+    /// This is 0 for a message which is not fragmented.
+    pub fn fragment(&self) -> u8 {
+        self.fragment
+    }
+
+    /// Returns the type of the address in the header.
+    pub fn address_type(&self) -> AddressType {
+        self.addr.address_type()
+    }
+
+    /// Returns the address of the peer which sent the message.
+    pub fn addr(&self) -> &TargetAddr {
+        &self.addr
+    }
+}
+
+// The largest possible UDP payload.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// A message received into a buffer of `N` bytes stored inline.
+///
+/// Returned by `Socks5Datagram::recv_msg`.
+#[derive(Debug, Clone)]
+pub struct InlineMessage<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    addr: TargetAddr,
+}
+
+impl<const N: usize> InlineMessage<N> {
+    /// Returns the message's payload.
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Returns the address the message was sent from.
+    pub fn addr(&self) -> &TargetAddr {
+        &self.addr
+    }
+}
+
+/// An iterator over the messages received by a `Socks5Datagram`.
+///
+/// Returned by `Socks5Datagram::messages`.
+#[derive(Debug)]
+pub struct DatagramMessages<'a> {
+    socket: &'a Socks5Datagram,
+    buf: Vec<u8>,
+}
+
+impl<'a> Iterator for DatagramMessages<'a> {
+    type Item = io::Result<(Vec<u8>, TargetAddr)>;
+
+    fn next(&mut self) -> Option<io::Result<(Vec<u8>, TargetAddr)>> {
+        let message = self.socket
+            .recv_from_buf(&mut self.buf)
+            .map(|addr| (self.buf.clone(), addr));
+        Some(message)
+    }
+}
+
+// The number of messages buffered for a `DatagramHandle` which isn't
+// receiving; older messages are dropped beyond this.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+struct SharedState {
+    queues: HashMap<TargetAddr, VecDeque<Vec<u8>>>,
+    reading: bool,
+    // reused by whichever handle is reading
+    buf: Vec<u8>,
+}
+
+struct SharedAssociation {
+    socket: Socks5Datagram,
+    state: Mutex<SharedState>,
+    cond: Condvar,
+}
+
+/// A UDP association shared between several `DatagramHandle`s.
+///
+/// Each handle exchanges messages with a single peer over the same control
+/// connection and local UDP socket, avoiding a separate association per
+/// peer. Incoming messages are matched against handles by the source address
+/// reported by the proxy, so peers should be identified by IP address, and
+/// messages from peers without a handle are discarded.
+#[derive(Clone)]
+pub struct SharedDatagram {
+    shared: Arc<SharedAssociation>,
+}
+
+impl fmt::Debug for SharedDatagram {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SharedDatagram")
+            .field("socket", &self.shared.socket)
+            .finish()
+    }
+}
+
+impl SharedDatagram {
+    /// Shares an association between handles.
+    pub fn new(socket: Socks5Datagram) -> SharedDatagram {
+        SharedDatagram {
+            shared: Arc::new(SharedAssociation {
+                socket,
+                state: Mutex::new(SharedState {
+                    queues: HashMap::new(),
+                    reading: false,
+                    buf: vec![],
+                }),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Creates a handle which exchanges messages with `peer`.
+    ///
+    /// Returns an error of kind `AddrInUse` if a handle for `peer` already
+    /// exists.
+    pub fn handle<A: ToTargetAddr>(&self, peer: A) -> io::Result<DatagramHandle> {
+        let peer = peer.to_target_addr()?;
+        let mut state = self.shared.state.lock().unwrap();
+        if state.queues.contains_key(&peer) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                      "a handle for the peer already exists"));
+        }
+        state.queues.insert(peer.clone(), VecDeque::new());
+
+        Ok(DatagramHandle {
+            shared: self.shared.clone(),
+            peer,
+        })
+    }
+
+    /// Returns a shared reference to the underlying association.
+    pub fn get_ref(&self) -> &Socks5Datagram {
+        &self.shared.socket
+    }
+}
+
+/// A handle for exchanging messages with a single peer over a
+/// `SharedDatagram`.
+pub struct DatagramHandle {
+    shared: Arc<SharedAssociation>,
+    peer: TargetAddr,
+}
+
+impl fmt::Debug for DatagramHandle {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DatagramHandle")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl DatagramHandle {
+    /// Sends a message to the peer.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.shared.socket.send_to(buf, self.peer.clone())
+    }
+
+    /// Receives a message from the peer.
+    ///
+    /// Like `UdpSocket::recv`, the message is truncated if it doesn't fit in
+    /// `buf`. Messages for other handles which are received in the meantime
+    /// are queued for them.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some(message) = state.queues.get_mut(&self.peer).and_then(VecDeque::pop_front) {
+                let len = cmp::min(buf.len(), message.len());
+                buf[..len].copy_from_slice(&message[..len]);
+                return Ok(len);
+            }
+
+            // only one handle reads from the socket at a time
+            if state.reading {
+                state = shared.cond.wait(state).unwrap();
+                continue;
+            }
+            state.reading = true;
+            let mut message = mem::take(&mut state.buf);
+            drop(state);
+
+            let result = shared.socket.recv_from_buf(&mut message);
+
+            state = shared.state.lock().unwrap();
+            state.reading = false;
+            shared.cond.notify_all();
+            let result = result.map(|addr| {
+                if addr == self.peer {
+                    // our queue was empty, so the message can be returned
+                    // directly
+                    let len = cmp::min(buf.len(), message.len());
+                    buf[..len].copy_from_slice(&message[..len]);
+                    return Some(len);
+                }
+                if let Some(queue) = state.queues.get_mut(&addr) {
+                    if queue.len() == MAX_QUEUED_DATAGRAMS {
+                        queue.pop_front();
+                    }
+                    queue.push_back(message.to_vec());
+                }
+                None
+            });
+            state.buf = message;
+            if let Some(len) = result? {
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> &TargetAddr {
+        &self.peer
+    }
+}
+
+impl Drop for DatagramHandle {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().queues.remove(&self.peer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, ToSocketAddrs, TcpListener, TcpStream, UdpSocket};
+    use std::thread;
+
+    use super::*;
+    use ConnectErrorKind;
+    use test_util::{failing_proxy, fake_proxy, fake_proxy_replying, fake_proxy_serving};
+    use CachingResolver;
+
+    const SOCKS_PROXY_NO_AUTH_ONLY: &str = "127.0.0.1:1080";
+    const SOCKS_PROXY_PASSWD_ONLY: &str = "127.0.0.1:1081";
+
+    #[test]
+    fn google_no_auth() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+        let socket = Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, addr).unwrap();
+        google(socket);
+    }
+
+    #[test]
+    fn google_with_password() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+        let socket = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            "testuser",
+            "testpass"
+        ).unwrap();
+        google(socket);
+    }
+
+    fn google(mut socket: Socks5Stream) {
+        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+
+        println!("{}", String::from_utf8_lossy(&result));
+        assert!(result.starts_with(b"HTTP/1.0"));
+        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+    }
+
+    #[test]
+    fn google_dns() {
+        let mut socket = Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, "google.com:80").unwrap();
+
+        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut result = vec![];
+        socket.read_to_end(&mut result).unwrap();
+
+        println!("{}", String::from_utf8_lossy(&result));
+        assert!(result.starts_with(b"HTTP/1.0"));
+        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+    }
+
+    #[test]
+    fn bind_no_auth() {
+        let addr = find_address();
+        let listener = Socks5Listener::bind(SOCKS_PROXY_NO_AUTH_ONLY, addr).unwrap();
+        bind(listener);
+    }
+
+    #[test]
+    fn bind_with_password_supported_but_no_auth_used() {
+        let addr = find_address();
+        let listener = Socks5Listener::bind_with_password(
+            SOCKS_PROXY_NO_AUTH_ONLY,
+            addr,
+            "unused_and_invalid_username",
+            "unused_and_invalid_password"
+        ).unwrap();
+        bind(listener);
+    }
+
+    #[test]
+    fn bind_with_password() {
+        let addr = find_address();
+        let listener = Socks5Listener::bind_with_password(
+            "127.0.0.1:1081",
+            addr,
+            "testuser",
+            "testpass"
+        ).unwrap();
+        bind(listener);
+    }
+
+    fn bind(listener: Socks5Listener) {
+        let addr = listener.proxy_addr().clone();
+        let mut end = TcpStream::connect(addr).unwrap();
+        let mut conn = listener.accept().unwrap();
+        conn.write_all(b"hello world").unwrap();
+        drop(conn);
+        let mut result = vec![];
+        end.read_to_end(&mut result).unwrap();
+        assert_eq!(result, b"hello world");
+    }
+
+    // First figure out our local address that we'll be connecting from
+    fn find_address() -> TargetAddr {
+        let socket = Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, "google.com:80").unwrap();
+        socket.proxy_addr().to_owned()
+    }
+
+    #[test]
+    fn associate_no_auth() {
+        let socks = Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15410").unwrap();
+        associate(socks, "127.0.0.1:15411");
+    }
+
+    #[test]
+    fn associate_with_password() {
+        let socks = Socks5Datagram::bind_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            "127.0.0.1:15414",
+            "testuser",
+            "testpass"
+        ).unwrap();
+        associate(socks, "127.0.0.1:15415");
+    }
+
+    fn associate(socks: Socks5Datagram, socket_addr: &str) {
+        let socket = UdpSocket::bind(socket_addr).unwrap();
+
+        socks.send_to(b"hello world!", socket_addr).unwrap();
+        let mut buf = [0; 13];
+        let (len, addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(&buf[..12], b"hello world!");
+
+        socket.send_to(b"hello world!", addr).unwrap();
+
+        let len = socks.recv_from(&mut buf).unwrap().0;
+        assert_eq!(len, 12);
+        assert_eq!(&buf[..12], b"hello world!");
+    }
+
+    #[test]
+    fn associate_long() {
+        let socks = Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15412").unwrap();
+        let socket_addr = "127.0.0.1:15413";
+        let socket = UdpSocket::bind(socket_addr).unwrap();
+
+        let mut msg = vec![];
+        for i in 0..(MAX_ADDR_LEN + 100) {
+            msg.push(i as u8);
+        }
+
+        socks.send_to(&msg, socket_addr).unwrap();
+        let mut buf = vec![0; msg.len() + 1];
+        let (len, addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(len, msg.len());
+        assert_eq!(msg, &buf[..msg.len()]);
+
+        socket.send_to(&msg, addr).unwrap();
+
+        let mut buf = vec![0; msg.len() + 1];
+        let len = socks.recv_from(&mut buf).unwrap().0;
+        assert_eq!(len, msg.len());
+        assert_eq!(msg, &buf[..msg.len()]);
+    }
+
+    #[test]
+    fn incorrect_password() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            "testuser",
+            "invalid"
+        ).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(err.description(), "password authentication failed");
+    }
+
+    #[test]
+    fn auth_method_not_supported() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+        let err = Socks5Stream::connect(SOCKS_PROXY_PASSWD_ONLY, addr).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.description(), "no acceptable auth methods");
+    }
+
+    #[test]
+    fn username_and_password_length() {
+        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            &string_of_size(1),
+            &string_of_size(1)
+        ).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(err.description(), "password authentication failed");
+
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            &string_of_size(255),
+            &string_of_size(255)
+        ).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(err.description(), "password authentication failed");
+
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            &string_of_size(0),
+            &string_of_size(255)
+        ).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.description(), "invalid username");
+
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            &string_of_size(256),
+            &string_of_size(255)
+        ).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.description(), "invalid username");
+
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            &string_of_size(255),
+            &string_of_size(0)
+        ).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.description(), "invalid password");
+
+        let err = Socks5Stream::connect_with_password(
+            SOCKS_PROXY_PASSWD_ONLY,
+            addr,
+            &string_of_size(255),
+            &string_of_size(256)
+        ).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.description(), "invalid password");
+    }
+
+    #[test]
+    fn auth_method_preference() {
+        let ids = |builder: &Socks5Builder| {
+            builder.auth(&builder.credentials)
+                .map(|auth| auth.iter().map(Authentication::id).collect::<Vec<_>>())
+        };
+
+        let mut builder = Socks5Builder::new();
+        assert_eq!(ids(&builder).unwrap(), [0]);
+
+        builder.password("user", "pass");
+        assert_eq!(ids(&builder).unwrap(), [2, 0]);
+
+        builder.methods(&[AuthMethod::Password]);
+        assert_eq!(ids(&builder).unwrap(), [2]);
+
+        builder.methods(&[AuthMethod::None, AuthMethod::Password]);
+        assert_eq!(ids(&builder).unwrap(), [0, 2]);
+
+        builder.methods(&[AuthMethod::Gssapi, AuthMethod::Password]);
+        assert_eq!(ids(&builder).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        builder.methods(&[AuthMethod::Password, AuthMethod::None, AuthMethod::Password]);
+        assert_eq!(ids(&builder).unwrap(), [2, 0]);
+
+        builder.methods(&[]);
+        assert_eq!(ids(&builder).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn fingerprint() {
+        let greeting = |builder: &Socks5Builder| {
+            greeting(&builder.auth(&builder.credentials).unwrap())
+        };
+
+        let mut builder = Socks5Builder::new();
+        builder.fingerprint(Fingerprint::Curl);
+        assert_eq!(greeting(&builder), [5, 1, 0]);
+        builder.password("user", "pass");
+        assert_eq!(greeting(&builder), [5, 2, 0, 2]);
+
+        builder.fingerprint(Fingerprint::Firefox);
+        assert_eq!(greeting(&builder), [5, 1, 2]);
+
+        builder.methods(&[AuthMethod::Password, AuthMethod::None]);
+        assert_eq!(greeting(&builder), [5, 2, 2, 0]);
+    }
+
+    #[test]
+    fn require_auth() {
+        let ids = |builder: &Socks5Builder| {
+            builder.auth(&builder.credentials)
+                .map(|auth| auth.iter().map(Authentication::id).collect::<Vec<_>>())
+        };
+
+        let mut builder = Socks5Builder::new();
+        builder.require_auth(true);
+        assert_eq!(ids(&builder).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        builder.password("user", "pass");
+        assert_eq!(ids(&builder).unwrap(), [2]);
+
+        builder.methods(&[AuthMethod::Password, AuthMethod::None]);
+        assert_eq!(ids(&builder).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn downgrade_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        // a proxy which always selects "no authentication"
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                let mut greeting = [0; 4];
+                socket.read_exact(&mut greeting).unwrap();
+                assert_eq!(greeting, [5, 2, 2, 0]);
+                socket.write_all(&[5, 0]).unwrap();
+                let mut request = [0; 10];
+                if socket.read_exact(&mut request).is_ok() {
+                    socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+                }
+            }
+        });
+
+        let mut builder = Socks5Builder::new();
+        builder.password("user", "pass");
+        builder.connect(proxy, "10.0.0.1:80").unwrap();
+
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let warned = warnings.clone();
+        builder.downgrade_policy(DowngradePolicy::Warn(Arc::new(move |target: &TargetAddr| {
+            warned.lock().unwrap().push(target.clone());
+        })));
+        builder.connect(proxy, "10.0.0.1:80").unwrap();
+        assert_eq!(*warnings.lock().unwrap(), ["10.0.0.1:80".to_target_addr().unwrap()]);
+
+        builder.downgrade_policy(DowngradePolicy::Fail);
+        let err = builder.connect(proxy, "10.0.0.1:80").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = err.into_inner().unwrap().downcast::<ConnectError>().unwrap();
+        assert_eq!(err.kind(), ConnectErrorKind::Auth);
+        match err.get_ref().get_ref().and_then(|e| e.downcast_ref::<::Error>()) {
+            Some(::Error::AuthDowngrade { advertised }) => {
+                assert_eq!(*advertised, [AuthMethod::Password, AuthMethod::None]);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn connect_many() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        // a proxy which refuses connections to port 1
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                let mut request = [0; 13];
+                socket.read_exact(&mut request[..3]).unwrap();
+                socket.write_all(&[5, 0]).unwrap();
+                socket.read_exact(&mut request[3..]).unwrap();
+                let code = if request[12] == 1 { 5 } else { 0 };
+                socket.write_all(&[5, code, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+            }
+        });
+
+        let targets = ["10.0.0.1:80", "10.0.0.2:1", "10.0.0.3:80", "10.0.0.4:80"];
+        let results = Socks5Builder::new().connect_many(proxy, &targets, 2).unwrap();
+        assert_eq!(results.len(), 4);
+        for (result, target) in results.iter().zip(&targets) {
+            match *result {
+                Ok(ref stream) => {
+                    assert_eq!(*stream.target_addr(), target.to_target_addr().unwrap())
+                }
+                Err(_) => assert_eq!(*target, "10.0.0.2:1"),
+            }
+        }
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn connect_interruptible() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        // a proxy which never responds
+        let server = thread::spawn(move || listener.accept().unwrap().0);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            flag.store(true, Ordering::SeqCst);
+        });
+        let err = Socks5Builder::new()
+            .connect_interruptible(proxy, "10.0.0.1:80", &stop)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+
+        // the connection to the proxy is shut down
+        let mut socket = server.join().unwrap();
+        let mut greeting = [0; 3];
+        socket.read_exact(&mut greeting).unwrap();
+        assert_eq!(socket.read(&mut [0]).unwrap(), 0);
+
+        // a connection opened after the flag is set is shut down unused
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || listener.accept().unwrap().0);
+        let stop = AtomicBool::new(true);
+        let err = Socks5Builder::new()
+            .before_connect(|_, _| {
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            })
+            .connect_interruptible(proxy, "10.0.0.1:80", &stop)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        let mut socket = server.join().unwrap();
+        assert_eq!(socket.read(&mut [0]).unwrap(), 0);
+    }
+
+    #[test]
+    fn max_domain_len() {
+        let too_long = |err: io::Error| {
+            match err.get_ref().and_then(|e| e.downcast_ref::<::Error>()) {
+                Some(&::Error::DomainTooLong { len, max, .. }) => (len, max),
+                e => panic!("unexpected error {:?}", e),
+            }
+        };
+
+        let long = TargetAddr::Domain(string_of_size(256), 80);
+        let err = Socks5Builder::new().connect("127.0.0.1:1", long.clone()).unwrap_err();
+        assert_eq!(too_long(err), (256, 255));
+
+        let mut builder = Socks5Builder::new();
+        builder.max_domain_len(10);
+        let err = builder.connect("127.0.0.1:1", ("example.com", 80)).unwrap_err();
+        assert_eq!(too_long(err), (11, 10));
+
+        builder.max_domain_len(1000);
+        let err = builder.connect("127.0.0.1:1", long).unwrap_err();
+        assert_eq!(too_long(err), (256, 255));
+    }
+
+    #[test]
+    fn credential_lengths() {
+        let err = Socks5Stream::connect_with_password("127.0.0.1:1",
+                                                      "10.0.0.1:80",
+                                                      &string_of_size(256),
+                                                      "pass")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match err.get_ref().and_then(|e| e.downcast_ref::<::Error>()) {
+            Some(&::Error::InvalidUsername { len: 256 }) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn auth_method_codes() {
+        for id in 0..=255u8 {
+            assert_eq!(u8::from(AuthMethod::from(id)), id);
+        }
+    }
+
+    #[test]
+    fn protocol_codes() {
+        for id in 0..=255u8 {
+            assert_eq!(u8::from(Command::from(id)), id);
+            assert_eq!(u8::from(AddressType::from(id)), id);
+            assert_eq!(u8::from(ReplyCode::from(id)), id);
+        }
+    }
+
+    #[test]
+    fn credential_provider() {
+        let mut builder = Socks5Builder::new();
+        builder.password("static", "pass")
+            .credential_provider(|target| {
+                Credentials::Password {
+                    username: target.host().into_owned(),
+                    password: "pass".to_owned(),
+                }
+            });
+
+        let target = TargetAddr::Domain("example.com".to_owned(), 80);
+        let credentials = builder.credentials_for(&target);
+        assert_eq!(credentials.len(), 1);
+        match credentials[0] {
+            Credentials::Password { ref username, .. } => assert_eq!(username, "example.com"),
+            #[cfg(feature = "chap")]
+            _ => panic!("unexpected credentials"),
+        }
+        assert!(!format!("{:?}", builder).contains("pass"));
+    }
+
+    #[test]
+    fn before_connect() {
+        let (proxy, server) = fake_proxy();
+        let mut builder = Socks5Builder::new();
+        builder.before_connect(move |socket, addr| {
+            assert_eq!(*addr, proxy);
+            SockRef::from(&socket).set_tcp_nodelay(true)
+        });
+        let stream = builder.connect(proxy, "127.0.0.1:80").unwrap();
+        assert!(stream.get_ref().nodelay().unwrap());
+        server.join().unwrap();
+
+        let mut builder = Socks5Builder::new();
+        builder.before_connect(|_, _| Err(io::Error::other("refused")));
+        let err = builder.connect(proxy, "127.0.0.1:80").unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(err.kind(), ConnectErrorKind::ProxyUnreachable);
+    }
+
+    #[test]
+    fn traffic_class() {
+        let (proxy, server) = fake_proxy();
+        let mut builder = Socks5Builder::new();
+        builder.traffic_class(Some(0x28 << 2));
+        let stream = builder.connect(proxy, "127.0.0.1:80").unwrap();
+        assert_eq!(SockRef::from(stream.get_ref()).tos_v4().unwrap(), 0x28 << 2);
+        server.join().unwrap();
+
+        let mut builder = Socks5DatagramBuilder::new();
+        builder.traffic_class(Some(0x2e << 2));
+        let socket = builder.bind_socket("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert_eq!(SockRef::from(&socket).tos_v4().unwrap(), 0x2e << 2);
+    }
+
+    #[test]
+    fn connect_error_context() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || drop(listener.accept().unwrap()));
+
+        let err = Socks5Stream::connect(proxy, "example.com:80").unwrap_err();
+        server.join().unwrap();
+        let inner = err.get_ref().unwrap().downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(err.kind(), inner.get_ref().kind());
+        assert_eq!(err.to_string(),
+                   format!("error connecting to example.com:80 through proxy {}: {}",
+                           proxy,
+                           inner.get_ref()));
+        assert_eq!(inner.proxy_addr(), Some(proxy));
+        assert_eq!(*inner.target_addr(), TargetAddr::Domain("example.com".to_owned(), 80));
+    }
+
+    #[test]
+    fn connect_each() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for &code in &[4, 0] {
+                let mut socket = listener.accept().unwrap().0;
+                let mut greeting = [0; 3];
+                socket.read_exact(&mut greeting).unwrap();
+                socket.write_all(&[5, 0]).unwrap();
+                let mut request = [0; 10];
+                socket.read_exact(&mut request).unwrap();
+                socket.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let targets = ["10.0.0.1:80".parse().unwrap(), "10.0.0.2:80".parse().unwrap()];
+        let stream = Socks5Stream::connect_each(proxy, &targets[..]).unwrap();
+        assert_eq!(*stream.target_addr(), TargetAddr::Ip(targets[1]));
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], [5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+        assert_eq!(requests[1], [5, 1, 0, 1, 10, 0, 0, 2, 0, 80]);
+
+        let empty: &[SocketAddr] = &[];
+        let err = Socks5Stream::connect_each(proxy, empty).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // a refusal by the proxy's ruleset isn't retried; another attempt
+        // would find nothing listening
+        let (proxy, server) = failing_proxy(2);
+        let err = Socks5Stream::connect_each(proxy, &targets[..]).unwrap_err();
+        server.join().unwrap();
+        let err = err.get_ref().unwrap().downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(err.kind(), ConnectErrorKind::NotAllowed);
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addrs = ["[2001:db8::1]:80", "[2001:db8::2]:80", "10.0.0.1:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect::<Vec<SocketAddr>>();
+        let expected = [addrs[0], addrs[2], addrs[1]];
+        assert_eq!(interleave_families(addrs), expected);
+    }
+
+    #[test]
+    fn attempt_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut greeting = [0; 3];
+                    socket.read_exact(&mut greeting).unwrap();
+                    socket.write_all(&[5, 0]).unwrap();
+                    let mut header = [0; 4];
+                    socket.read_exact(&mut header).unwrap();
+                    if header[3] == 4 {
+                        // the IPv6 route is a black hole
+                        let mut rest = [0; 18];
+                        socket.read_exact(&mut rest).unwrap();
+                        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+                        let _ = tx.send(socket.read(&mut [0]).map_err(|e| e.kind()));
+                        return;
+                    }
+                    let mut rest = [0; 6];
+                    socket.read_exact(&mut rest).unwrap();
+                    socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+                });
+            }
+        });
+
+        let targets = ["[2001:db8::1]:80".parse().unwrap(), "10.0.0.1:80".parse().unwrap()];
+        let mut builder = Socks5Builder::new();
+        builder.attempt_delay(Some(Duration::from_millis(50)))
+            .resolver(move |host: &str, port| {
+                match host {
+                    "proxy.test" => Ok(vec![SocketAddr::new(proxy.ip(), port)]),
+                    _ => Ok(targets.to_vec()),
+                }
+            });
+        let start = Instant::now();
+        let stream = builder.connect_each(("proxy.test", proxy.port()), "example.com:80").unwrap();
+        assert_eq!(*stream.target_addr(), TargetAddr::Ip(targets[1]));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // the losing attempt's connection is shut down
+        assert_eq!(rx.recv().unwrap(), Ok(0));
+    }
+
+    #[test]
+    fn racing_waits_for_pending() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                thread::spawn(move || {
+                    let mut greeting = [0; 3];
+                    socket.read_exact(&mut greeting).unwrap();
+                    socket.write_all(&[5, 0]).unwrap();
+                    let mut header = [0; 4];
+                    socket.read_exact(&mut header).unwrap();
+                    if header[3] == 4 {
+                        // IPv6 targets are forbidden by the ruleset
+                        socket.write_all(&[5, 2, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+                        return;
+                    }
+                    let mut rest = [0; 6];
+                    socket.read_exact(&mut rest).unwrap();
+                    thread::sleep(Duration::from_millis(150));
+                    socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+                });
+            }
+        });
+
+        let targets = ["10.0.0.1:80".parse().unwrap(), "[2001:db8::1]:80".parse().unwrap()];
+        let mut builder = Socks5Builder::new();
+        builder.attempt_delay(Some(Duration::from_millis(50)))
+            .resolver(move |_: &str, _| Ok(targets.to_vec()));
+        let stream = builder.connect_each(proxy, "example.com:80").unwrap();
+        assert_eq!(*stream.target_addr(), TargetAddr::Ip(targets[0]));
+    }
+
+    #[test]
+    fn cached_resolution() {
+        let (proxy, server) = fake_proxy_serving(2, [5, 0, 0, 1, 127, 0, 0, 1, 0, 80]);
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+        let resolver = CachingResolver::new(move |_: &str, port| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::from(([10, 0, 0, 1], port))])
+        }, Duration::from_secs(60));
+        let mut builder = Socks5Builder::new();
+        builder.resolver(resolver);
+
+        // clones of the builder share the cache
+        builder.connect_each(proxy, "example.com:80").unwrap();
+        builder.clone().connect_each(proxy, "example.com:443").unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let retryable = |code| {
+            let (proxy, server) = failing_proxy(code);
+            let err = Socks5Stream::connect(proxy, "10.0.0.1:80").unwrap_err();
+            server.join().unwrap();
+            let err = err.into_inner().unwrap().downcast::<ConnectError>().unwrap();
+            (err.kind(), err.is_retryable())
+        };
+        assert_eq!(retryable(3), (ConnectErrorKind::TargetUnreachable, true));
+        assert_eq!(retryable(6), (ConnectErrorKind::TargetUnreachable, true));
+        assert_eq!(retryable(2), (ConnectErrorKind::NotAllowed, false));
+        assert_eq!(retryable(7), (ConnectErrorKind::Unsupported, false));
+
+        let (proxy, server) = failing_proxy(4);
+        let err = Socks5Stream::connect(proxy, "10.0.0.1:80").unwrap_err();
+        server.join().unwrap();
+        assert!(err.to_string().ends_with(": host unreachable"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        drop(listener);
+        let err = Socks5Stream::connect(proxy, "10.0.0.1:80").unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(err.kind(), ConnectErrorKind::ProxyUnreachable);
+        assert!(err.is_retryable());
+    }
+
+    #[cfg(feature = "transcript")]
+    #[test]
+    fn transcript() {
+        let (proxy, server) = failing_proxy(1);
+        let err = Socks5Stream::connect(proxy, "10.0.0.1:80").unwrap_err();
+        server.join().unwrap();
+
+        let err = err.get_ref().unwrap().downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(err.transcript().unwrap().to_string(),
+                   "> 05 01 00\n\
+                    < 05 00\n\
+                    > 05 01 00 01 0a 00 00 01 00 50\n\
+                    < 05 01\n");
+    }
+
+    #[test]
+    fn connect_via() {
+        let (proxy, server) = fake_proxy();
+        let first = Socks5Stream::connect(proxy, "127.0.0.1:1080").unwrap();
+        let mut server = server.join().unwrap();
+
+        // the first proxy's end of the connection acts as the second proxy
+        let second = thread::spawn(move || {
+            let mut greeting = [0; 3];
+            server.read_exact(&mut greeting).unwrap();
+            server.write_all(&[5, 0]).unwrap();
+            let mut request = [0; 10];
+            server.read_exact(&mut request).unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+            server.write_all(&[5, 0, 0, 1, 10, 0, 0, 2, 0, 80]).unwrap();
+            server.write_all(b"x").unwrap();
+        });
+
+        let mut stream = Socks5Stream::connect_via(first, "10.0.0.1:80").unwrap();
+        second.join().unwrap();
+        assert_eq!(*stream.proxy_addr(), TargetAddr::Ip("10.0.0.2:80".parse().unwrap()));
+        assert_eq!(*stream.get_ref().target_addr(),
+                   TargetAddr::Ip("127.0.0.1:1080".parse().unwrap()));
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x");
+    }
+
+    #[test]
+    fn connect_and_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut socket = listener.accept().unwrap().0;
+            // everything arrives before the proxy has said anything
+            let mut packet = [0; 16];
+            socket.read_exact(&mut packet).unwrap();
+            assert_eq!(packet, *b"\x05\x01\x00\x05\x01\x00\x01\x0a\x00\x00\x01\x00\x50GET");
+            socket.write_all(&[5, 0, 5, 0, 0, 1, 10, 0, 0, 2, 0, 80]).unwrap();
+            socket
+        });
+
+        let stream = Socks5Stream::connect_and_send(proxy, "10.0.0.1:80", b"GET").unwrap();
+        server.join().unwrap();
+        assert_eq!(*stream.proxy_addr(), TargetAddr::Ip("10.0.0.2:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn strict_validation() {
+        let reply = [5, 0, 0, 1, 10, 0, 0, 2, 0, 80];
+        let addr = TargetAddr::Ip("10.0.0.2:80".parse().unwrap());
+        assert_eq!(read_reply(&mut &reply[..], Validation::Strict).unwrap(), addr);
+
+        let mut junk = reply.to_vec();
+        junk.push(0);
+        assert_eq!(read_reply(&mut &junk[..], Validation::Standard).unwrap(), addr);
+        let err = read_reply(&mut &junk[..], Validation::Strict).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-        let mut header = [0; MAX_ADDR_LEN + 3];
-        // first two bytes are reserved at 0
-        // third byte is the fragment id at 0
-        let len = write_addr(&mut header[3..], &addr)?;
+        let err = read_reply(&mut &reply[..9], Validation::Strict).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
 
-        self.socket.writev([&header[..len + 3], buf])
-    }
+        let reply = [5, 0, 1, 1, 10, 0, 0, 2, 0, 80];
+        let err = read_reply(&mut &reply[..], Validation::Strict).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-    /// Like `UdpSocket::recv_from`.
-    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
-        let mut header = [0; MAX_ADDR_LEN + 3];
-        let len = self.socket.readv([&mut header, buf])?;
+        let reply = [5, 0, 0, 5, 10, 0, 0, 2, 0, 80];
+        let err = read_reply(&mut &reply[..], Validation::Strict).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-        let overflow = len.saturating_sub(header.len());
+        let reply = [5, 0, 0, 3, 0, 0, 80];
+        let err = read_reply(&mut &reply[..], Validation::Strict).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-        let header_len = cmp::min(header.len(), len);
-        let mut header = &mut &header[..header_len];
+        // failures are reported as such even if truncated
+        let err = read_reply(&mut &[5, 2][..], Validation::Strict).unwrap_err();
+        assert!(matches!(err.get_ref().unwrap().downcast_ref::<::Error>(),
+                         Some(&::Error::Socks5Reply { code: ReplyCode::NotAllowed })));
+    }
 
-        if header.read_u16::<BigEndian>()? != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid reserved bytes"));
-        }
-        if header.read_u8()? != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid fragment id"));
-        }
-        let addr = read_addr(&mut header)?;
+    #[test]
+    fn lenient_validation() {
+        let reply = [5, 0, 1, 1, 10, 0, 0, 2, 0, 80];
+        let addr = TargetAddr::Ip("10.0.0.2:80".parse().unwrap());
+        assert_eq!(read_reply(&mut &reply[..], Validation::Lenient).unwrap(), addr);
+        let err = read_reply(&mut &reply[..], Validation::Standard).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-        unsafe {
-            ptr::copy(buf.as_ptr(), buf.as_mut_ptr().offset(header.len() as isize), overflow);
-        }
-        buf[..header.len()].copy_from_slice(header);
+        let (proxy, server) = fake_proxy_replying([0, 0, 0, 0, 0, 80]);
+        let stream = Socks5Builder::new()
+            .validation(Validation::Lenient)
+            .connect(proxy, "10.0.0.1:80")
+            .unwrap();
+        let _server = server.join().unwrap();
+        assert_eq!(*stream.proxy_addr(), TargetAddr::Ip("127.0.0.1:80".parse().unwrap()));
 
-        Ok((header.len() + overflow, addr))
+        // the second reply to a bind request
+        let (proxy, server) = fake_proxy_replying([10, 0, 0, 1, 0x10, 0x00]);
+        let mut listener = Socks5Builder::new()
+            .validation(Validation::Lenient)
+            .bind(proxy, "10.0.0.2:0")
+            .unwrap();
+        let mut server = server.join().unwrap();
+        server.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0x04, 0x38]).unwrap();
+        let peer = listener.accept_reply().unwrap();
+        assert_eq!(peer, TargetAddr::Ip("127.0.0.1:1080".parse().unwrap()));
     }
 
-    /// Returns the address of the proxy-side UDP socket through which all
-    /// messages will be routed.
-    pub fn proxy_addr(&self) -> &TargetAddr {
-        &self.stream.proxy_addr
-    }
+    #[test]
+    fn listener_ftp_arguments() {
+        let (proxy, server) = fake_proxy_replying([0, 0, 0, 0, 0x12, 0x34]);
+        let listener = Socks5Listener::bind(proxy, "10.0.0.1:21").unwrap();
+        let _server = server.join().unwrap();
 
-    /// Returns a shared reference to the inner socket.
-    pub fn get_ref(&self) -> &UdpSocket {
-        &self.socket
+        assert_eq!(listener.port_argument().unwrap(), "127,0,0,1,18,52");
+        assert_eq!(listener.eprt_argument().unwrap(), "|1|127.0.0.1|4660|");
     }
 
-    /// Returns a mutable reference to the inner socket.
-    pub fn get_mut(&mut self) -> &mut UdpSocket {
-        &mut self.socket
+    #[test]
+    fn listener_keeps_control() {
+        let (proxy, server) = fake_proxy_replying([10, 0, 0, 1, 0x10, 0x00]);
+        let mut listener = Socks5Listener::bind(proxy, "10.0.0.2:0").unwrap();
+        let mut server = server.join().unwrap();
+
+        server.write_all(&[5, 0, 0, 1, 10, 0, 0, 2, 0x04, 0x38]).unwrap();
+        server.write_all(&[5, 0, 0, 1, 10, 0, 0, 2, 0x04, 0x39]).unwrap();
+        let peer = listener.accept_reply().unwrap();
+        assert_eq!(peer, TargetAddr::Ip("10.0.0.2:1080".parse().unwrap()));
+        let peer = listener.accept_reply().unwrap();
+        assert_eq!(peer, TargetAddr::Ip("10.0.0.2:1081".parse().unwrap()));
+
+        let control = listener.into_control();
+        assert_eq!(*control.proxy_addr(), TargetAddr::Ip("10.0.0.1:4096".parse().unwrap()));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::error::Error;
-    use std::io::{Read, Write};
-    use std::net::{ToSocketAddrs, TcpStream, UdpSocket};
+    #[test]
+    fn listener_accept_timeout() {
+        let (proxy, server) = fake_proxy_replying([10, 0, 0, 1, 0x10, 0x00]);
+        let mut builder = Socks5Builder::new();
+        builder.accept_timeout(Some(Duration::from_millis(50)));
+        let listener = builder.bind(proxy, "10.0.0.2:0").unwrap();
+        let mut server = server.join().unwrap();
 
-    use super::*;
+        // a reply trickling in doesn't extend the timeout
+        let writer = thread::spawn(move || {
+            for &b in &[5, 0, 0, 1, 10, 0, 0] {
+                thread::sleep(Duration::from_millis(20));
+                server.write_all(&[b]).unwrap();
+            }
+            server
+        });
+        let mut listener = listener;
+        let start = Instant::now();
+        let err = listener.accept_reply().unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(130));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
 
-    const SOCKS_PROXY_NO_AUTH_ONLY: &str = "127.0.0.1:1080";
-    const SOCKS_PROXY_PASSWD_ONLY: &str = "127.0.0.1:1081";
+        // the rest of the reply is read by the next call, and no more
+        let mut server = writer.join().unwrap();
+        server.write_all(&[2, 0x04, 0x38, b'x']).unwrap();
+        let peer = listener.accept_reply().unwrap();
+        assert_eq!(peer, TargetAddr::Ip("10.0.0.2:1080".parse().unwrap()));
+        let mut byte = [0];
+        listener.control().read_exact(&mut byte).unwrap();
+        assert_eq!(byte, *b"x");
+
+        let start = Instant::now();
+        let err = listener.accept().unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        match err.get_ref().and_then(|e| e.downcast_ref::<::Error>()) {
+            Some(&::Error::AcceptTimedOut { timeout }) => {
+                assert_eq!(timeout, Duration::from_millis(50))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
 
     #[test]
-    fn google_no_auth() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
-        let socket = Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, addr).unwrap();
-        google(socket);
+    fn timing() {
+        let (proxy, server) = fake_proxy();
+        let stream = Socks5Stream::connect(proxy, "127.0.0.1:80").unwrap();
+        let timing = stream.timing();
+        assert_eq!(timing.total(), timing.tcp_connect() + timing.auth() + timing.request());
+        server.join().unwrap();
     }
 
     #[test]
-    fn google_with_password() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
-        let socket = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            "testuser",
-            "testpass"
-        ).unwrap();
-        google(socket);
+    fn socket_addrs() {
+        let (proxy, server) = fake_proxy();
+        let stream = Socks5Stream::connect(proxy, "127.0.0.1:80").unwrap();
+        let server = server.join().unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap(), proxy);
+        assert_eq!(stream.local_addr().unwrap(), server.peer_addr().unwrap());
+        assert_eq!(*stream.proxy_addr(), TargetAddr::Ip("127.0.0.1:80".parse().unwrap()));
     }
 
-    fn google(mut socket: Socks5Stream) {
-        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
-        let mut result = vec![];
-        socket.read_to_end(&mut result).unwrap();
+    #[test]
+    fn target_addr() {
+        let (proxy, server) = fake_proxy();
+        let stream = Socks5Stream::connect(proxy, "10.0.0.1:80").unwrap();
+        server.join().unwrap();
 
-        println!("{}", String::from_utf8_lossy(&result));
-        assert!(result.starts_with(b"HTTP/1.0"));
-        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+        assert_eq!(*stream.target_addr(), TargetAddr::Ip("10.0.0.1:80".parse().unwrap()));
     }
 
     #[test]
-    fn google_dns() {
-        let mut socket = Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, "google.com:80").unwrap();
+    fn nonblocking() {
+        let (proxy, server) = fake_proxy();
+        let mut stream = Socks5Stream::connect(proxy, "127.0.0.1:80").unwrap();
+        let mut server = server.join().unwrap();
 
-        socket.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
-        let mut result = vec![];
-        socket.read_to_end(&mut result).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let err = stream.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
 
-        println!("{}", String::from_utf8_lossy(&result));
-        assert!(result.starts_with(b"HTTP/1.0"));
-        assert!(result.ends_with(b"</HTML>\r\n") || result.ends_with(b"</html>"));
+        server.write_all(b"x").unwrap();
+        stream.set_nonblocking(false).unwrap();
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x");
     }
 
     #[test]
-    fn bind_no_auth() {
-        let addr = find_address();
-        let listener = Socks5Listener::bind(SOCKS_PROXY_NO_AUTH_ONLY, addr).unwrap();
-        bind(listener);
+    fn is_alive() {
+        let (proxy, server) = fake_proxy();
+        let mut stream = Socks5Stream::connect(proxy, "127.0.0.1:80").unwrap();
+        let mut server = server.join().unwrap();
+        assert!(stream.is_alive());
+
+        server.write_all(b"x").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(stream.is_alive());
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x");
+
+        stream.set_keepalive(Some(Duration::from_secs(60))).unwrap();
+        assert!(SockRef::from(stream.get_ref()).keepalive().unwrap());
+        stream.set_keepalive(None).unwrap();
+        assert!(!SockRef::from(stream.get_ref()).keepalive().unwrap());
+
+        drop(server);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!stream.is_alive());
+    }
+
+    // Sets up a UDP association with a fake proxy, returning the client's
+    // socket, the proxy's relay socket, and the proxy's end of the control
+    // connection.
+    fn fake_association() -> (Socks5Datagram, UdpSocket, TcpStream) {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let (proxy, server) = fake_proxy_replying([127, 0, 0, 1, (port >> 8) as u8, port as u8]);
+        let socket = Socks5Datagram::bind(proxy, "127.0.0.1:0").unwrap();
+        (socket, relay, server.join().unwrap())
     }
 
     #[test]
-    fn bind_with_password_supported_but_no_auth_used() {
-        let addr = find_address();
-        let listener = Socks5Listener::bind_with_password(
-            SOCKS_PROXY_NO_AUTH_ONLY,
-            addr,
-            "unused_and_invalid_username",
-            "unused_and_invalid_password"
-        ).unwrap();
-        bind(listener);
+    fn datagram_messages() {
+        let (socket, relay, _control) = fake_association();
+
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'a'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 2, 0, 53, b'b', b'c'], local).unwrap();
+
+        let mut messages = socket.messages();
+        let (data, addr) = messages.next().unwrap().unwrap();
+        assert_eq!(data, b"a");
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
+        let (data, addr) = messages.next().unwrap().unwrap();
+        assert_eq!(data, b"bc");
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.2:53".parse().unwrap()));
     }
 
     #[test]
-    fn bind_with_password() {
-        let addr = find_address();
-        let listener = Socks5Listener::bind_with_password(
-            "127.0.0.1:1081",
-            addr,
-            "testuser",
-            "testpass"
-        ).unwrap();
-        bind(listener);
+    fn shared_datagram() {
+        let (socket, relay, _control) = fake_association();
+        let local = socket.get_ref().local_addr().unwrap();
+        let shared = SharedDatagram::new(socket);
+        let first = shared.handle("10.0.0.1:53").unwrap();
+        let second = shared.handle("10.0.0.2:53").unwrap();
+        assert_eq!(shared.handle("10.0.0.2:53").unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        second.send(b"x").unwrap();
+        let mut buf = [0; 16];
+        let len = relay.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], [0, 0, 0, 1, 10, 0, 0, 2, 0, 53, b'x']);
+
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 3, 0, 53, b'a'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 2, 0, 53, b'b'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'c'], local).unwrap();
+
+        let len = first.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"c");
+        let len = second.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"b");
     }
 
-    fn bind(listener: Socks5Listener) {
-        let addr = listener.proxy_addr().clone();
-        let mut end = TcpStream::connect(addr).unwrap();
-        let mut conn = listener.accept().unwrap();
-        conn.write_all(b"hello world").unwrap();
-        drop(conn);
-        let mut result = vec![];
-        end.read_to_end(&mut result).unwrap();
-        assert_eq!(result, b"hello world");
+    #[test]
+    fn recv_raw() {
+        let (socket, relay, _control) = fake_association();
+
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 0x81, 3, 1, b'a', 0, 53, b'x', b'y'], local).unwrap();
+
+        let mut buf = [0; 16];
+        let (header, range) = socket.recv_raw(&mut buf).unwrap();
+        assert_eq!(header.fragment(), 0x81);
+        assert_eq!(header.address_type(), AddressType::Domain);
+        assert_eq!(*header.addr(), TargetAddr::Domain("a".to_owned(), 53));
+        assert_eq!(&buf[range], b"xy");
     }
 
-    // First figure out our local address that we'll be connecting from
-    fn find_address() -> TargetAddr {
-        let socket = Socks5Stream::connect(SOCKS_PROXY_NO_AUTH_ONLY, "google.com:80").unwrap();
-        socket.proxy_addr().to_owned()
+    #[test]
+    fn recv_from_truncated() {
+        let (socket, relay, _control) = fake_association();
+
+        let local = socket.get_ref().local_addr().unwrap();
+        let mut message = vec![0, 0, 0, 1, 10, 0, 0, 1, 0, 53];
+        message.extend((0..600).map(|i| i as u8));
+        relay.send_to(&message, local).unwrap();
+
+        let mut buf = [0; 300];
+        let (len, addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(len, 300);
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
+        assert_eq!(buf[..], message[10..310]);
     }
 
     #[test]
-    fn associate_no_auth() {
-        let socks = Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15410").unwrap();
-        associate(socks, "127.0.0.1:15411");
+    fn recv_msg() {
+        let (socket, relay, _control) = fake_association();
+
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'a', b'b'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'c', b'd', b'e'], local).unwrap();
+
+        let message = socket.recv_msg::<4>().unwrap();
+        assert_eq!(message.data(), b"ab");
+        assert_eq!(*message.addr(), TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
+        let message = socket.recv_msg::<2>().unwrap();
+        assert_eq!(message.data(), b"cd");
     }
 
     #[test]
-    fn associate_with_password() {
-        let socks = Socks5Datagram::bind_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            "127.0.0.1:15414",
-            "testuser",
-            "testpass"
-        ).unwrap();
-        associate(socks, "127.0.0.1:15415");
+    fn explicit_fragments() {
+        let (socket, relay, _control) = fake_association();
+
+        let local = socket.get_ref().local_addr().unwrap();
+        assert_eq!(socket.send_fragment(b"ab", "10.0.0.1:53", 0x81).unwrap(), 2);
+        let mut buf = [0; 16];
+        let (len, _) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[..len], [0, 0, 0x81, 1, 10, 0, 0, 1, 0, 53, b'a', b'b']);
+
+        relay.send_to(&[0, 0, 1, 1, 10, 0, 0, 1, 0, 53, b'c'], local).unwrap();
+        let (len, frag, addr) = socket.recv_fragment(&mut buf).unwrap();
+        assert_eq!((&buf[..len], frag), (&b"c"[..], 1));
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
     }
 
-    fn associate(socks: Socks5Datagram, socket_addr: &str) {
-        let socket = UdpSocket::bind(socket_addr).unwrap();
+    #[test]
+    fn try_send_recv() {
+        let (socket, relay, _control) = fake_association();
+        let mut buf = [0; 16];
+        let err = socket.try_recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
 
-        socks.send_to(b"hello world!", socket_addr).unwrap();
-        let mut buf = [0; 13];
-        let (len, addr) = socket.recv_from(&mut buf).unwrap();
-        assert_eq!(len, 12);
-        assert_eq!(&buf[..12], b"hello world!");
+        assert_eq!(socket.try_send_to(b"ab", "10.0.0.1:53").unwrap(), 2);
+        let (len, _) = relay.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[..len], [0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'a', b'b']);
 
-        socket.send_to(b"hello world!", addr).unwrap();
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'c'], local).unwrap();
+        let start = Instant::now();
+        let (len, addr) = loop {
+            match socket.try_recv_from(&mut buf) {
+                Ok(r) => break r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(start.elapsed() < Duration::from_secs(5));
+                    thread::yield_now();
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(&buf[..len], b"c");
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
+    }
 
-        let len = socks.recv_from(&mut buf).unwrap().0;
-        assert_eq!(len, 12);
-        assert_eq!(&buf[..12], b"hello world!");
+    #[test]
+    fn drain_into() {
+        let (socket, relay, _control) = fake_association();
+        let mut messages = vec![];
+        assert_eq!(socket.drain_into(&mut messages).unwrap(), 0);
+
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'a', b'b'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 2, 0, 53, b'c'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 3, 0, 53, b'd'], local).unwrap();
+        // give the messages time to be queued
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(socket.drain_into_limited(&mut messages, 2).unwrap(), 2);
+        assert_eq!(messages,
+                   [(b"ab".to_vec(), TargetAddr::Ip("10.0.0.1:53".parse().unwrap())),
+                    (b"c".to_vec(), TargetAddr::Ip("10.0.0.2:53".parse().unwrap()))]);
+
+        // the rest are left for the next call
+        assert_eq!(socket.drain_into(&mut messages).unwrap(), 1);
+        assert_eq!(messages[2], (b"d".to_vec(), TargetAddr::Ip("10.0.0.3:53".parse().unwrap())));
+
+        // messages of any length are received whole
+        let mut datagram = vec![0, 0, 0, 1, 10, 0, 0, 4, 0, 53];
+        datagram.extend_from_slice(&[1; 4096]);
+        relay.send_to(&datagram, local).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(socket.drain_into(&mut messages).unwrap(), 1);
+        assert_eq!(messages[3].0, [1; 4096]);
     }
 
     #[test]
-    fn associate_long() {
-        let socks = Socks5Datagram::bind(SOCKS_PROXY_NO_AUTH_ONLY, "127.0.0.1:15412").unwrap();
-        let socket_addr = "127.0.0.1:15413";
-        let socket = UdpSocket::bind(socket_addr).unwrap();
+    fn recv_from_buf() {
+        let (socket, relay, _control) = fake_association();
 
-        let mut msg = vec![];
-        for i in 0..(MAX_ADDR_LEN + 100) {
-            msg.push(i as u8);
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'a', b'b'], local).unwrap();
+        relay.send_to(&[0, 0, 0, 1, 10, 0, 0, 1, 0, 53, b'c'], local).unwrap();
+
+        let mut buf = vec![];
+        socket.recv_from_buf(&mut buf).unwrap();
+        assert_eq!(buf, b"ab");
+        let capacity = buf.capacity();
+        let addr = socket.recv_from_buf(&mut buf).unwrap();
+        assert_eq!(buf, b"c");
+        assert_eq!(buf.capacity(), capacity);
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
+    }
+
+    #[test]
+    fn header_cache() {
+        let mut cache = HeaderCache::default();
+        for i in 0..HEADER_CACHE_LEN as u16 + 1 {
+            let addr = TargetAddr::Domain("example.com".to_owned(), i);
+            let header = cache.get(&addr).unwrap().to_vec();
+            assert_eq!(header, encode_udp_header(&addr).unwrap());
+            // keep the first destination in use
+            cache.get(&TargetAddr::Domain("example.com".to_owned(), 0)).unwrap();
         }
 
-        socks.send_to(&msg, socket_addr).unwrap();
-        let mut buf = vec![0; msg.len() + 1];
-        let (len, addr) = socket.recv_from(&mut buf).unwrap();
-        assert_eq!(len, msg.len());
-        assert_eq!(msg, &buf[..msg.len()]);
+        assert_eq!(cache.entries.len(), HEADER_CACHE_LEN);
+        assert_eq!(cache.entries.last().unwrap().0, TargetAddr::Domain("example.com".to_owned(), 0));
+        assert!(cache.entries.iter().all(|e| e.0.port() != 1));
+    }
 
-        socket.send_to(&msg, addr).unwrap();
+    #[test]
+    fn send_to() {
+        let (socket, relay, _control) = fake_association();
 
-        let mut buf = vec![0; msg.len() + 1];
-        let len = socks.recv_from(&mut buf).unwrap().0;
-        assert_eq!(len, msg.len());
-        assert_eq!(msg, &buf[..msg.len()]);
+        socket.send_to(b"a", "10.0.0.1:53").unwrap();
+        socket.send_to(b"b", "10.0.0.1:53").unwrap();
+
+        let mut buf = [0; 16];
+        for &data in &[b"a", b"b"] {
+            let len = relay.recv(&mut buf).unwrap();
+            assert_eq!(buf[..len - 1], [0, 0, 0, 1, 10, 0, 0, 1, 0, 53]);
+            assert_eq!(buf[len - 1], data[0]);
+        }
     }
 
     #[test]
-    fn incorrect_password() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            "testuser",
-            "invalid"
-        ).unwrap_err();
+    fn reuse_address() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let reply = [127, 0, 0, 1, (port >> 8) as u8, port as u8];
 
-        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
-        assert_eq!(err.description(), "password authentication failed");
+        let mut builder = Socks5DatagramBuilder::new();
+        builder.reuse_address(true);
+        #[cfg(unix)]
+        builder.reuse_port(true);
+
+        let (proxy, server) = fake_proxy_replying(reply);
+        let a = builder.bind(proxy, "127.0.0.1:0").unwrap();
+        let _a_control = server.join().unwrap();
+        let addr = a.get_ref().local_addr().unwrap();
+
+        let (proxy, server) = fake_proxy_replying(reply);
+        let b = builder.bind(proxy, addr).unwrap();
+        let _b_control = server.join().unwrap();
+        assert_eq!(b.get_ref().local_addr().unwrap(), addr);
     }
 
     #[test]
-    fn auth_method_not_supported() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
-        let err = Socks5Stream::connect(SOCKS_PROXY_PASSWD_ONLY, addr).unwrap_err();
+    fn relay_addr() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // an unreachable relay address, as reported by some proxies
+        let (proxy, server) = fake_proxy_replying([0, 0, 0, 0, 0, 9]);
 
-        assert_eq!(err.kind(), io::ErrorKind::Other);
-        assert_eq!(err.description(), "no acceptable auth methods");
+        let mut builder = Socks5DatagramBuilder::new();
+        builder.relay_addr(relay.local_addr().unwrap())
+            .read_timeout(Some(Duration::from_secs(5)));
+        let socket = builder.bind(proxy, "127.0.0.1:0").unwrap();
+        let _control = server.join().unwrap();
+
+        assert_eq!(*socket.proxy_addr(), TargetAddr::Ip("0.0.0.0:9".parse().unwrap()));
+        assert_eq!(socket.get_ref().read_timeout().unwrap(), Some(Duration::from_secs(5)));
+        socket.send_to(b"a", "10.0.0.1:53").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(relay.recv(&mut buf).unwrap(), 11);
     }
 
     #[test]
-    fn username_and_password_length() {
-        let addr = "google.com:80".to_socket_addrs().unwrap().next().unwrap();
+    fn fragments() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let (proxy, server) = fake_proxy_replying([127, 0, 0, 1, (port >> 8) as u8, port as u8]);
 
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            &string_of_size(1),
-            &string_of_size(1)
-        ).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
-        assert_eq!(err.description(), "password authentication failed");
+        let mut builder = Socks5DatagramBuilder::new();
+        builder.reassemble(true).fragment_len(Some(2)).unwrap();
+        let socket = builder.bind(proxy, "127.0.0.1:0").unwrap();
+        let _control = server.join().unwrap();
 
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            &string_of_size(255),
-            &string_of_size(255)
-        ).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
-        assert_eq!(err.description(), "password authentication failed");
+        socket.send_to(b"abcde", "10.0.0.1:53").unwrap();
+        let mut buf = [0; 16];
+        for &(frag, data) in &[(1, &b"ab"[..]), (2, b"cd"), (0x83, b"e")] {
+            let len = relay.recv(&mut buf).unwrap();
+            assert_eq!(buf[..10], [0, 0, frag, 1, 10, 0, 0, 1, 0, 53]);
+            assert_eq!(&buf[10..len], data);
+        }
 
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            &string_of_size(0),
-            &string_of_size(255)
-        ).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
-        assert_eq!(err.description(), "invalid username");
+        let local = socket.get_ref().local_addr().unwrap();
+        // a message missing its start is dropped
+        relay.send_to(&[0, 0, 2, 1, 10, 0, 0, 1, 0, 53, b'x'], local).unwrap();
+        relay.send_to(&[0, 0, 1, 1, 10, 0, 0, 1, 0, 53, b'a', b'b'], local).unwrap();
+        relay.send_to(&[0, 0, 0x82, 1, 10, 0, 0, 1, 0, 53, b'c'], local).unwrap();
+        let (len, addr) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"abc");
+        assert_eq!(addr, TargetAddr::Ip("10.0.0.1:53".parse().unwrap()));
 
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            &string_of_size(256),
-            &string_of_size(255)
-        ).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
-        assert_eq!(err.description(), "invalid username");
+        relay.send_to(&[0, 0, 1, 1, 10, 0, 0, 1, 0, 53, b'd', b'e'], local).unwrap();
+        relay.send_to(&[0, 0, 0x82, 1, 10, 0, 0, 1, 0, 53, b'f'], local).unwrap();
+        let mut buf = vec![];
+        socket.recv_from_buf(&mut buf).unwrap();
+        assert_eq!(buf, b"def");
 
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            &string_of_size(255),
-            &string_of_size(0)
-        ).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
-        assert_eq!(err.description(), "invalid password");
+        assert!(builder.fragment_len(Some(0)).is_err());
+    }
 
-        let err = Socks5Stream::connect_with_password(
-            SOCKS_PROXY_PASSWD_ONLY,
-            addr,
-            &string_of_size(255),
-            &string_of_size(256)
-        ).unwrap_err();
-        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
-        assert_eq!(err.description(), "invalid password");
+    #[test]
+    fn unexpected_fragment() {
+        let (socket, relay, _control) = fake_association();
+
+        let local = socket.get_ref().local_addr().unwrap();
+        relay.send_to(&[0, 0, 1, 1, 10, 0, 0, 1, 0, 53, b'a'], local).unwrap();
+        let err = socket.recv_from(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 
     fn string_of_size(size: usize) -> String {
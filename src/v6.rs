@@ -0,0 +1,475 @@
+//! An experimental client for the SOCKS 6 protocol, as described in
+//! draft-olteanu-intarea-socks-6-11.
+//!
+//! The protocol is still a draft and may change incompatibly; so may this
+//! module.
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs, SocketAddrV4, SocketAddrV6, TcpStream, Ipv4Addr,
+               Ipv6Addr};
+
+use {AuthMethod, ToTargetAddr, TargetAddr, domain_too_long, encode_domain};
+use v5::check_password;
+
+const VERSION: u8 = 6;
+
+const CMD_CONNECT: u8 = 1;
+
+const OPT_AUTH_METHOD_ADVERTISEMENT: u16 = 2;
+const OPT_AUTH_DATA: u16 = 4;
+
+// The length of an option's kind and length fields.
+const OPT_HEADER_LEN: usize = 4;
+
+// Returns the number of zero bytes needed to pad `len` to a multiple of 4.
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// A SOCKS 6 option.
+///
+/// Options are carried in requests and replies, and are used to negotiate
+/// authentication as well as extensions to the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks6Option {
+    kind: u16,
+    data: Vec<u8>,
+}
+
+impl Socks6Option {
+    /// Creates a new option.
+    ///
+    /// The data will be padded with zeros to a multiple of 4 bytes when the
+    /// option is sent.
+    pub fn new(kind: u16, data: &[u8]) -> Socks6Option {
+        Socks6Option {
+            kind,
+            data: data.to_owned(),
+        }
+    }
+
+    /// Returns the option's kind.
+    pub fn kind(&self) -> u16 {
+        self.kind
+    }
+
+    /// Returns the option's data, including any padding.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn write_to(&self, packet: &mut Vec<u8>) -> io::Result<()> {
+        let len = OPT_HEADER_LEN + self.data.len();
+        let padded_len = len + padding(len);
+        if padded_len > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "option too long"));
+        }
+
+        packet.write_u16::<BigEndian>(self.kind).unwrap();
+        packet.write_u16::<BigEndian>(padded_len as u16).unwrap();
+        packet.extend_from_slice(&self.data);
+        packet.extend((len..padded_len).map(|_| 0));
+        Ok(())
+    }
+}
+
+fn encode_options(options: &[Socks6Option]) -> io::Result<Vec<u8>> {
+    let mut packet = vec![];
+    for option in options {
+        option.write_to(&mut packet)?;
+    }
+
+    if packet.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "options too long"));
+    }
+    Ok(packet)
+}
+
+fn read_options<R: Read>(socket: &mut R, mut len: usize) -> io::Result<Vec<Socks6Option>> {
+    let mut options = vec![];
+    while len > 0 {
+        if len < OPT_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid option length"));
+        }
+        let kind = socket.read_u16::<BigEndian>()?;
+        let option_len = socket.read_u16::<BigEndian>()? as usize;
+        if option_len < OPT_HEADER_LEN || option_len > len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid option length"));
+        }
+
+        let mut data = vec![0; option_len - OPT_HEADER_LEN];
+        socket.read_exact(&mut data)?;
+        options.push(Socks6Option { kind, data });
+        len -= option_len;
+    }
+
+    Ok(options)
+}
+
+// Writes the port, padding, address type and address fields shared by
+// requests and operation replies.
+fn write_addr(packet: &mut Vec<u8>, target: &TargetAddr) -> io::Result<()> {
+    match *target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            packet.write_u16::<BigEndian>(addr.port()).unwrap();
+            packet.write_u8(0).unwrap(); // padding
+            packet.write_u8(1).unwrap();
+            packet.extend_from_slice(&addr.ip().octets());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            packet.write_u16::<BigEndian>(addr.port()).unwrap();
+            packet.write_u8(0).unwrap(); // padding
+            packet.write_u8(3).unwrap();
+            packet.extend_from_slice(&addr.ip().octets());
+        }
+        TargetAddr::Domain(ref domain, port) => {
+            let ascii = encode_domain(domain)?;
+            if ascii.len() > u8::MAX as usize {
+                return Err(domain_too_long(domain, ascii.len(), u8::MAX as usize));
+            }
+            packet.write_u16::<BigEndian>(port).unwrap();
+            packet.write_u8(0).unwrap(); // padding
+            packet.write_u8(2).unwrap();
+            packet.write_u8(ascii.len() as u8).unwrap();
+            packet.extend_from_slice(ascii.as_bytes());
+            packet.extend((0..padding(1 + ascii.len())).map(|_| 0));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_addr<R: Read>(socket: &mut R) -> io::Result<TargetAddr> {
+    let port = socket.read_u16::<BigEndian>()?;
+    socket.read_u8()?; // padding
+    match socket.read_u8()? {
+        1 => {
+            let ip = Ipv4Addr::from(socket.read_u32::<BigEndian>()?);
+            Ok(TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port))))
+        }
+        2 => {
+            let len = socket.read_u8()? as usize;
+            let mut domain = vec![0; len + padding(1 + len)];
+            socket.read_exact(&mut domain)?;
+            domain.truncate(len);
+            let domain = String::from_utf8(domain)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(TargetAddr::Domain(domain, port))
+        }
+        3 => {
+            let mut ip = [0; 16];
+            socket.read_exact(&mut ip)?;
+            let ip = Ipv6Addr::from(ip);
+            Ok(TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported address type")),
+    }
+}
+
+fn password_auth_data(username: &str, password: &str) -> io::Result<Vec<u8>> {
+    check_password(username, password)?;
+
+    let mut data = vec![AuthMethod::Password.into(), 1];
+    data.push(username.len() as u8);
+    data.extend_from_slice(username.as_bytes());
+    data.push(password.len() as u8);
+    data.extend_from_slice(password.as_bytes());
+    Ok(data)
+}
+
+/// A SOCKS 6 client configuration.
+///
+/// Requires the `unstable-socks6` Cargo feature.
+#[derive(Clone, Default)]
+pub struct Socks6Builder {
+    password: Option<(String, String)>,
+    options: Vec<Socks6Option>,
+    initial_data: Vec<u8>,
+}
+
+impl fmt::Debug for Socks6Builder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // don't leak credentials
+        fmt.debug_struct("Socks6Builder")
+            .field("username", &self.password.as_ref().map(|p| &p.0))
+            .field("options", &self.options)
+            .field("initial_data", &self.initial_data.len())
+            .finish()
+    }
+}
+
+impl Socks6Builder {
+    /// Creates a new builder which does not authenticate to the proxy.
+    pub fn new() -> Socks6Builder {
+        Socks6Builder::default()
+    }
+
+    /// Authenticates to the proxy with the given username and password.
+    ///
+    /// The credentials are sent along with the request, without waiting for
+    /// the proxy to select a method.
+    pub fn password(&mut self, username: &str, password: &str) -> &mut Socks6Builder {
+        self.password = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Adds an option to the request.
+    pub fn option(&mut self, option: Socks6Option) -> &mut Socks6Builder {
+        self.options.push(option);
+        self
+    }
+
+    /// Sets data to be sent to the target along with the request.
+    ///
+    /// The proxy forwards the data to the target as soon as the connection
+    /// is established, saving a round trip.
+    pub fn initial_data(&mut self, data: &[u8]) -> &mut Socks6Builder {
+        self.initial_data = data.to_owned();
+        self
+    }
+
+    fn request(&self, command: u8, target: &TargetAddr) -> io::Result<Vec<u8>> {
+        if self.initial_data.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "initial data too long"));
+        }
+
+        let mut options = vec![];
+        let mut advertisement = vec![];
+        advertisement.write_u16::<BigEndian>(self.initial_data.len() as u16).unwrap();
+        if let Some((ref username, ref password)) = self.password {
+            advertisement.push(AuthMethod::Password.into());
+            options.push(Socks6Option::new(OPT_AUTH_DATA, &password_auth_data(username, password)?));
+        }
+        if advertisement.len() > 2 || !self.initial_data.is_empty() {
+            options.insert(0, Socks6Option::new(OPT_AUTH_METHOD_ADVERTISEMENT, &advertisement));
+        }
+        options.extend(self.options.iter().cloned());
+
+        let options = encode_options(&options)?;
+
+        let mut packet = vec![VERSION, command];
+        packet.write_u16::<BigEndian>(options.len() as u16).unwrap();
+        write_addr(&mut packet, target)?;
+        packet.extend_from_slice(&options);
+        packet.extend_from_slice(&self.initial_data);
+        Ok(packet)
+    }
+
+    // Returns the proxy-side address and the options of the operation reply.
+    fn handshake<S>(&self,
+                    socket: &mut S,
+                    target: &TargetAddr)
+                    -> io::Result<(TargetAddr, Vec<Socks6Option>)>
+        where S: Read + Write
+    {
+        socket.write_all(&self.request(CMD_CONNECT, target)?)?;
+
+        // authentication reply
+        if socket.read_u8()? != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
+        }
+        let status = socket.read_u8()?;
+        let len = socket.read_u16::<BigEndian>()?;
+        read_options(socket, len as usize)?;
+        match status {
+            0 => {}
+            1 => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                          "authentication failed"))
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid authentication reply")),
+        }
+
+        // operation reply
+        if socket.read_u8()? != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version"));
+        }
+        let code = socket.read_u8()?;
+        let msg = match code {
+            0 => None,
+            1 => Some("general SOCKS server failure"),
+            2 => Some("connection not allowed by ruleset"),
+            3 => Some("network unreachable"),
+            4 => Some("host unreachable"),
+            5 => Some("connection refused"),
+            6 => Some("TTL expired"),
+            7 => Some("command not supported"),
+            8 => Some("address kind not supported"),
+            9 => Some("connection attempt timed out"),
+            _ => Some("unknown error"),
+        };
+        if let Some(msg) = msg {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, msg));
+        }
+        let len = socket.read_u16::<BigEndian>()?;
+        let proxy_addr = read_addr(socket)?;
+        let options = read_options(socket, len as usize)?;
+
+        Ok((proxy_addr, options))
+    }
+
+    /// Connects to a target server through a SOCKS 6 proxy.
+    pub fn connect<T, U>(&self, proxy: T, target: U) -> io::Result<Socks6Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        let mut socket = TcpStream::connect(proxy)?;
+        let target = target.to_target_addr()?;
+        let (proxy_addr, options) = self.handshake(&mut socket, &target)?;
+        Ok(Socks6Stream {
+            socket,
+            proxy_addr,
+            options,
+        })
+    }
+}
+
+/// A SOCKS 6 client.
+///
+/// Requires the `unstable-socks6` Cargo feature.
+#[derive(Debug)]
+pub struct Socks6Stream {
+    socket: TcpStream,
+    proxy_addr: TargetAddr,
+    options: Vec<Socks6Option>,
+}
+
+impl Socks6Stream {
+    /// Connects to a target server through a SOCKS 6 proxy.
+    pub fn connect<T, U>(proxy: T, target: U) -> io::Result<Socks6Stream>
+        where T: ToSocketAddrs,
+              U: ToTargetAddr
+    {
+        Socks6Builder::new().connect(proxy, target)
+    }
+
+    /// Returns the proxy-side address of the connection between the proxy and
+    /// target server.
+    ///
+    /// Use `peer_addr` for the address of the proxy server itself.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.proxy_addr
+    }
+
+    /// Returns the options included in the proxy's reply.
+    pub fn options(&self) -> &[Socks6Option] {
+        &self.options
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    ///
+    /// This is distinct from `proxy_addr`, which is the address the proxy
+    /// uses for its connection to the target server.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    /// Returns the local address of the connection to the proxy server.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Moves the inner `TcpStream` into or out of nonblocking mode.
+    ///
+    /// The handshake with the proxy is always performed in blocking mode;
+    /// once the stream has been established, reads and writes pass
+    /// `WouldBlock` errors through unchanged.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
+    /// Returns a shared reference to the inner `TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the inner `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /// Consumes the `Socks6Stream`, returning the inner `TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        self.socket
+    }
+}
+
+impl Read for Socks6Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+
+impl Read for &Socks6Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.socket).read(buf)
+    }
+}
+
+impl Write for Socks6Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Write for &Socks6Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.socket).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.socket).flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_util::Mock;
+
+    #[test]
+    fn request() {
+        let target = TargetAddr::Domain("example.com".to_owned(), 80);
+        let mut builder = Socks6Builder::new();
+        builder.password("u", "p").initial_data(b"GET");
+
+        let mut expected = vec![6, 1, 0, 20, 0, 80, 0, 2, 11];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[0, 2, 0, 8, 0, 3, 2, 0]);
+        expected.extend_from_slice(&[0, 4, 0, 12, 2, 1, 1, b'u', 1, b'p', 0, 0]);
+        expected.extend_from_slice(b"GET");
+        assert_eq!(builder.request(CMD_CONNECT, &target).unwrap(), expected);
+
+        let target = TargetAddr::Ip("10.0.0.1:443".parse().unwrap());
+        assert_eq!(Socks6Builder::new().request(CMD_CONNECT, &target).unwrap(),
+                   [6, 1, 0, 0, 1, 187, 0, 1, 10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn handshake() {
+        let mut input = vec![6, 0, 0, 0];
+        input.extend_from_slice(&[6, 0, 0, 8, 4, 56, 0, 1, 10, 0, 0, 2]);
+        input.extend_from_slice(&[0x10, 0, 0, 8, 1, 2, 3, 4]);
+        let mut mock = Mock::new(input);
+
+        let target = TargetAddr::Ip("10.0.0.1:443".parse().unwrap());
+        let (proxy_addr, options) = Socks6Builder::new().handshake(&mut mock, &target).unwrap();
+        assert_eq!(proxy_addr, TargetAddr::Ip("10.0.0.2:1080".parse().unwrap()));
+        assert_eq!(options, [Socks6Option::new(0x1000, &[1, 2, 3, 4])]);
+        assert_eq!(mock.output, [6, 1, 0, 0, 1, 187, 0, 1, 10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn rejected() {
+        let mut mock = Mock::new(vec![6, 1, 0, 0]);
+
+        let target = TargetAddr::Ip("10.0.0.1:443".parse().unwrap());
+        let err = Socks6Builder::new().handshake(&mut mock, &target).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
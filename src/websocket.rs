@@ -0,0 +1,308 @@
+//! WebSocket connections through a SOCKS5 proxy.
+//!
+//! Requires the `tungstenite` Cargo feature. `wss` URIs are supported by
+//! `connect_tls`, which additionally requires either the
+//! `tungstenite-native-tls` or the `tungstenite-rustls` feature.
+//!
+//! The module also supports the reverse arrangement, where the connection to
+//! the SOCKS proxy is itself tunneled inside a WebSocket; see
+//! `connect_over_websocket`.
+// tungstenite's handshake errors are large, but they're what callers expect.
+#![allow(clippy::result_large_err)]
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Response;
+use tungstenite::http::Uri;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{ClientHandshake, HandshakeError, Message, WebSocket};
+
+use {Command, SchemePorts, Socks5Builder, Socks5Stream, TargetAddr, ToTargetAddr};
+
+#[cfg(any(feature = "tungstenite-native-tls", feature = "tungstenite-rustls"))]
+type TlsHandshakeError = HandshakeError<ClientHandshake<MaybeTlsStream<Socks5Stream>>>;
+
+/// Returns the target address of a `ws` or `wss` URI.
+///
+/// If the URI does not specify a port, the default port for its scheme is
+/// used, as given by `SchemePorts`. Host names are left unresolved so that
+/// DNS lookup happens on the proxy server.
+pub fn target_addr(uri: &Uri) -> io::Result<TargetAddr> {
+    match uri.scheme_str() {
+        Some("ws") | Some("wss") => {}
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "unsupported WebSocket URI scheme"))
+        }
+    }
+    SchemePorts::new().authority_target_addr(uri.scheme_str(), uri.host(), uri.port_u16())
+}
+
+/// Connects to the host of a `ws` or `wss` URI through a SOCKS5 proxy.
+///
+/// The returned stream can be passed to `tungstenite::client`. Secure
+/// connections must additionally be wrapped in TLS, using the URI's host for
+/// SNI; `connect_tls` takes care of that.
+pub fn connect_stream<T>(proxy: T, uri: &Uri) -> io::Result<Socks5Stream>
+    where T: ToSocketAddrs
+{
+    Socks5Stream::connect(proxy, target_addr(uri)?)
+}
+
+/// Performs a WebSocket handshake through a SOCKS5 proxy.
+///
+/// No TLS is performed, so this should only be used with `ws` URIs.
+pub fn connect<T, R>
+    (proxy: T,
+     request: R)
+     -> Result<(WebSocket<Socks5Stream>, Response), HandshakeError<ClientHandshake<Socks5Stream>>>
+    where T: ToSocketAddrs,
+          R: IntoClientRequest
+{
+    let request = request.into_client_request()?;
+    let stream = connect_stream(proxy, request.uri())
+        .map_err(|e| HandshakeError::Failure(e.into()))?;
+    tungstenite::client(request, stream)
+}
+
+/// Performs a WebSocket handshake through a SOCKS5 proxy, wrapping the
+/// stream in TLS if the request's URI has the `wss` scheme.
+///
+/// Requires the `tungstenite-native-tls` or `tungstenite-rustls` Cargo
+/// feature.
+#[cfg(any(feature = "tungstenite-native-tls", feature = "tungstenite-rustls"))]
+pub fn connect_tls<T, R>
+    (proxy: T,
+     request: R)
+     -> Result<(WebSocket<MaybeTlsStream<Socks5Stream>>, Response), TlsHandshakeError>
+    where T: ToSocketAddrs,
+          R: IntoClientRequest
+{
+    let request = request.into_client_request()?;
+    let stream = connect_stream(proxy, request.uri())
+        .map_err(|e| HandshakeError::Failure(e.into()))?;
+    tungstenite::client_tls(request, stream)
+}
+
+/// A byte stream carried in the binary messages of a WebSocket.
+///
+/// Each write is sent as a single binary message. Text messages are treated
+/// as binary data, control messages are handled by tungstenite, and a close
+/// from the peer reads as end of stream.
+#[derive(Debug)]
+pub struct WebSocketTransport<S> {
+    socket: WebSocket<S>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+fn into_io_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+impl<S: Read + Write> WebSocketTransport<S> {
+    /// Wraps an established WebSocket.
+    pub fn new(socket: WebSocket<S>) -> WebSocketTransport<S> {
+        WebSocketTransport {
+            socket,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Returns a shared reference to the inner WebSocket.
+    pub fn get_ref(&self) -> &WebSocket<S> {
+        &self.socket
+    }
+
+    /// Returns a mutable reference to the inner WebSocket.
+    pub fn get_mut(&mut self) -> &mut WebSocket<S> {
+        &mut self.socket
+    }
+
+    /// Consumes the `WebSocketTransport`, returning the inner WebSocket.
+    ///
+    /// Any data received but not yet read is lost.
+    pub fn into_inner(self) -> WebSocket<S> {
+        self.socket
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketTransport<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let data = match self.socket.read() {
+                Ok(Message::Binary(data)) => data.to_vec(),
+                Ok(Message::Text(data)) => data.as_bytes().to_vec(),
+                Ok(Message::Close(_)) |
+                Err(tungstenite::Error::ConnectionClosed) |
+                Err(tungstenite::Error::AlreadyClosed) => return Ok(0),
+                Ok(_) => continue,
+                Err(err) => return Err(into_io_error(err)),
+            };
+            self.buf = data;
+            self.pos = 0;
+        }
+
+        let len = cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for WebSocketTransport<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(Message::binary(buf.to_vec())).map_err(into_io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush().map_err(into_io_error)
+    }
+}
+
+/// A connection to a target through a SOCKS5 proxy reached over a WebSocket.
+#[derive(Debug)]
+pub struct SocksOverWebSocket {
+    transport: WebSocketTransport<MaybeTlsStream<TcpStream>>,
+    proxy_addr: TargetAddr,
+    target: TargetAddr,
+}
+
+impl SocksOverWebSocket {
+    /// Returns the proxy-side address of the connection between the proxy and
+    /// target server.
+    pub fn proxy_addr(&self) -> &TargetAddr {
+        &self.proxy_addr
+    }
+
+    /// Returns the target address passed to `connect_over_websocket`.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Returns a shared reference to the inner transport.
+    pub fn get_ref(&self) -> &WebSocketTransport<MaybeTlsStream<TcpStream>> {
+        &self.transport
+    }
+
+    /// Returns a mutable reference to the inner transport.
+    pub fn get_mut(&mut self) -> &mut WebSocketTransport<MaybeTlsStream<TcpStream>> {
+        &mut self.transport
+    }
+
+    /// Consumes the `SocksOverWebSocket`, returning the inner transport.
+    pub fn into_inner(self) -> WebSocketTransport<MaybeTlsStream<TcpStream>> {
+        self.transport
+    }
+}
+
+impl Read for SocksOverWebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transport.read(buf)
+    }
+}
+
+impl Write for SocksOverWebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
+/// Connects to a target server through a SOCKS5 proxy whose connection is
+/// tunneled inside a WebSocket.
+///
+/// `request` identifies the WebSocket endpoint bridging to the proxy. `wss`
+/// endpoints additionally require the `tungstenite-native-tls` or
+/// `tungstenite-rustls` Cargo feature. Authentication to the proxy is
+/// configured by `builder`.
+pub fn connect_over_websocket<R, U>(request: R,
+                                    builder: &Socks5Builder,
+                                    target: U)
+                                    -> io::Result<SocksOverWebSocket>
+    where R: IntoClientRequest,
+          U: ToTargetAddr
+{
+    let target = target.to_target_addr()?;
+    let (socket, _) = tungstenite::connect(request).map_err(into_io_error)?;
+    let mut transport = WebSocketTransport::new(socket);
+    // the bridge's address says nothing about the proxy's
+    let proxy_addr = builder.handshake(&mut transport, Command::Connect, &target, None)?;
+
+    Ok(SocksOverWebSocket {
+        transport,
+        proxy_addr,
+        target,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn scheme_ports() {
+        let uri = "ws://example.com/chat".parse::<Uri>().unwrap();
+        match target_addr(&uri).unwrap() {
+            TargetAddr::Domain(ref host, 80) if host == "example.com" => {}
+            addr => panic!("unexpected address {:?}", addr),
+        }
+
+        let uri = "wss://example.com/chat".parse::<Uri>().unwrap();
+        match target_addr(&uri).unwrap() {
+            TargetAddr::Domain(ref host, 443) if host == "example.com" => {}
+            addr => panic!("unexpected address {:?}", addr),
+        }
+
+        let uri = "wss://[::1]:8443/".parse::<Uri>().unwrap();
+        match target_addr(&uri).unwrap() {
+            TargetAddr::Ip(addr) => assert_eq!(addr, "[::1]:8443".parse().unwrap()),
+            addr => panic!("unexpected address {:?}", addr),
+        }
+
+        let uri = "http://example.com/".parse::<Uri>().unwrap();
+        assert!(target_addr(&uri).is_err());
+    }
+
+    #[test]
+    fn socks_over_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            let mut ws = tungstenite::accept(socket).unwrap();
+            assert_eq!(ws.read().unwrap(), Message::binary(vec![5, 1, 0]));
+            ws.send(Message::binary(vec![5, 0])).unwrap();
+            assert_eq!(ws.read().unwrap(),
+                       Message::binary(vec![5, 1, 0, 1, 10, 0, 0, 1, 0, 80]));
+            ws.send(Message::binary(vec![5, 0, 0, 1, 10, 0, 0, 2, 4, 56])).unwrap();
+            let msg = ws.read().unwrap();
+            ws.send(msg).unwrap();
+        });
+
+        let uri = format!("ws://{}/", addr);
+        let mut stream = connect_over_websocket(uri.as_str(), &Socks5Builder::new(), "10.0.0.1:80")
+            .unwrap();
+        assert_eq!(*stream.proxy_addr(), TargetAddr::Ip("10.0.0.2:1080".parse().unwrap()));
+
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        server.join().unwrap();
+    }
+}
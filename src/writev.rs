@@ -1,59 +1,118 @@
-use std::io;
-use std::net::UdpSocket;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::{TcpStream, UdpSocket};
 
+use {MaybeProxiedStream, Socks4Stream, Socks5Stream};
+
+/// Vectored reads and writes on sockets through shared references.
+///
+/// The standard library's vectored I/O methods take `&mut self` and aren't
+/// implemented for `UdpSocket` at all. This trait provides them for any
+/// socket, including `UdpSocket` and `TcpStream`, performing each operation
+/// in a single system call. It is used to send SOCKS5 UDP headers along with
+/// their payloads without copying them into a single buffer.
+///
+/// It is implemented for `TcpStream`, `UdpSocket`, and the proxied streams
+/// of this crate, whose operations go straight to the connection to the
+/// proxy.
 pub trait WritevExt {
-    fn writev(&self, bufs: [&[u8]; 2]) -> io::Result<usize>;
-    fn readv(&self, bufs: [&mut [u8]; 2]) -> io::Result<usize>;
+    /// Writes data from a sequence of buffers, returning the number of bytes
+    /// written.
+    ///
+    /// For a datagram socket, the buffers are sent as a single message.
+    fn writev(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize>;
+
+    /// Reads data into a sequence of buffers, filling each in turn, and
+    /// returns the number of bytes read.
+    fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize>;
+}
+
+impl WritevExt for TcpStream {
+    fn writev(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        imp::writev(self, bufs)
+    }
+
+    fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        imp::readv(self, bufs)
+    }
+}
+
+impl WritevExt for UdpSocket {
+    fn writev(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        imp::writev(self, bufs)
+    }
+
+    fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        imp::readv(self, bufs)
+    }
+}
+
+impl WritevExt for Socks4Stream {
+    fn writev(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        imp::writev(self.as_ref(), bufs)
+    }
+
+    fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        imp::readv(self.as_ref(), bufs)
+    }
+}
+
+impl WritevExt for Socks5Stream {
+    fn writev(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        imp::writev(self.as_ref(), bufs)
+    }
+
+    fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        imp::readv(self.as_ref(), bufs)
+    }
+}
+
+impl WritevExt for MaybeProxiedStream {
+    fn writev(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        imp::writev(self.as_ref(), bufs)
+    }
+
+    fn readv(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        imp::readv(self.as_ref(), bufs)
+    }
 }
 
 #[cfg(unix)]
 mod imp {
     use libc;
+    use std::cmp;
     use std::os::unix::io::AsRawFd;
 
     use super::*;
 
-    impl WritevExt for UdpSocket {
-        fn writev(&self, bufs: [&[u8]; 2]) -> io::Result<usize> {
-            unsafe {
-                let iovecs = [
-                    libc::iovec {
-                        iov_base: bufs[0].as_ptr() as *const _ as *mut _,
-                        iov_len: bufs[0].len(),
-                    },
-                    libc::iovec {
-                        iov_base: bufs[1].as_ptr() as *const _ as *mut _,
-                        iov_len: bufs[1].len(),
-                    },
-                ];
-                let r = libc::writev(self.as_raw_fd(), iovecs.as_ptr(), 2);
-                if r < 0 {
-                    Err(io::Error::last_os_error())
-                } else {
-                    Ok(r as usize)
-                }
-            }
+    // `IoSlice` and `IoSliceMut` are guaranteed to be ABI compatible with
+    // `iovec`.
+    fn count(len: usize) -> libc::c_int {
+        cmp::min(len, libc::c_int::MAX as usize) as libc::c_int
+    }
+
+    pub fn writev<T: AsRawFd>(socket: &T, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let r = unsafe {
+            libc::writev(socket.as_raw_fd(),
+                         bufs.as_ptr() as *const libc::iovec,
+                         count(bufs.len()))
+        };
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(r as usize)
         }
+    }
 
-        fn readv(&self, bufs: [&mut [u8]; 2]) -> io::Result<usize> {
-            unsafe {
-                let mut iovecs = [
-                    libc::iovec {
-                        iov_base: bufs[0].as_mut_ptr() as *mut _,
-                        iov_len: bufs[0].len(),
-                    },
-                    libc::iovec {
-                        iov_base: bufs[1].as_mut_ptr() as *mut _,
-                        iov_len: bufs[1].len(),
-                    },
-                ];
-                let r = libc::readv(self.as_raw_fd(), iovecs.as_mut_ptr(), 2);
-                if r < 0 {
-                    Err(io::Error::last_os_error())
-                } else {
-                    Ok(r as usize)
-                }
-            }
+    pub fn readv<T: AsRawFd>(socket: &T, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let r = unsafe {
+            libc::readv(socket.as_raw_fd(),
+                        bufs.as_mut_ptr() as *mut libc::iovec,
+                        count(bufs.len()))
+        };
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(r as usize)
         }
     }
 }
@@ -63,71 +122,95 @@ mod imp {
     use winapi::um::winsock2;
     use winapi::shared::ws2def;
     use winapi::shared::minwindef;
+    use std::cmp;
     use std::os::windows::io::AsRawSocket;
     use std::ptr;
 
     use super::*;
 
-    impl WritevExt for UdpSocket {
-        fn writev(&self, bufs: [&[u8]; 2]) -> io::Result<usize> {
-            unsafe {
-                let mut wsabufs = [
-                    ws2def::WSABUF {
-                        len: bufs[0].len() as winsock2::u_long,
-                        buf: bufs[0].as_ptr() as *const _ as *mut _,
-                    },
-                    ws2def::WSABUF {
-                        len: bufs[1].len() as winsock2::u_long,
-                        buf: bufs[1].as_ptr() as *const _ as *mut _,
-                    },
-                ];
-                let mut sent = 0;
-                let r = winsock2::WSASend(
-                    self.as_raw_socket() as usize,
-                    wsabufs.as_mut_ptr(),
-                    bufs.len() as minwindef::DWORD,
-                    &mut sent,
-                    0,
-                    ptr::null_mut(),
-                    None,
-                );
-                if r == 0 {
-                    Ok(sent as usize)
-                } else {
-                    Err(io::Error::last_os_error())
-                }
+    // `IoSlice` and `IoSliceMut` are guaranteed to be ABI compatible with
+    // `WSABUF`.
+    fn count(len: usize) -> minwindef::DWORD {
+        cmp::min(len, minwindef::DWORD::MAX as usize) as minwindef::DWORD
+    }
+
+    pub fn writev<T: AsRawSocket>(socket: &T, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        unsafe {
+            let mut sent = 0;
+            let r = winsock2::WSASend(
+                socket.as_raw_socket() as usize,
+                bufs.as_ptr() as *mut ws2def::WSABUF,
+                count(bufs.len()),
+                &mut sent,
+                0,
+                ptr::null_mut(),
+                None,
+            );
+            if r == 0 {
+                Ok(sent as usize)
+            } else {
+                Err(io::Error::last_os_error())
             }
         }
+    }
 
-        fn readv(&self, bufs: [&mut [u8]; 2]) -> io::Result<usize> {
-            unsafe {
-                let mut wsabufs = [
-                    ws2def::WSABUF {
-                        len: bufs[0].len() as winsock2::u_long,
-                        buf: bufs[0].as_mut_ptr() as *mut _,
-                    },
-                    ws2def::WSABUF {
-                        len: bufs[1].len() as winsock2::u_long,
-                        buf: bufs[1].as_mut_ptr() as *mut _,
-                    },
-                ];
-                let mut recved = 0;
-                let mut flags = 0;
-                let r = winsock2::WSARecv(
-                    self.as_raw_socket() as usize,
-                    wsabufs.as_mut_ptr(),
-                    bufs.len() as minwindef::DWORD,
-                    &mut recved,
-                    &mut flags,
-                    ptr::null_mut(),
-                    None,
-                );
-                if r == 0 {
-                    Ok(recved as usize)
-                } else {
-                    Err(io::Error::last_os_error())
-                }
+    pub fn readv<T: AsRawSocket>(socket: &T, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        unsafe {
+            let mut recved = 0;
+            let mut flags = 0;
+            let r = winsock2::WSARecv(
+                socket.as_raw_socket() as usize,
+                bufs.as_mut_ptr() as *mut ws2def::WSABUF,
+                count(bufs.len()),
+                &mut recved,
+                &mut flags,
+                ptr::null_mut(),
+                None,
+            );
+            if r == 0 {
+                Ok(recved as usize)
+            } else {
+                Err(io::Error::last_os_error())
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+
+    use super::*;
+
+    #[test]
+    fn udp() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+
+        let sent = a.writev(&[IoSlice::new(b"ab"), IoSlice::new(b""), IoSlice::new(b"cde")]);
+        assert_eq!(sent.unwrap(), 5);
+
+        let (mut first, mut second) = ([0; 3], [0; 3]);
+        let len = b.readv(&mut [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)])
+            .unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(&first, b"abc");
+        assert_eq!(&second[..2], b"de");
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut server = listener.accept().unwrap().0;
+
+        client.writev(&[IoSlice::new(b"hello "), IoSlice::new(b"world")]).unwrap();
+        drop(client);
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+}